@@ -0,0 +1,111 @@
+use {
+    crate::{
+        events::HealthEvent,
+        metrics::Metrics,
+        pool::{Pool, Probe},
+    },
+    actix_web::{web, App, HttpResponse, HttpServer},
+    log::info,
+    serde::Deserialize,
+    serde_json::{json, Value},
+    std::{net::SocketAddr, sync::Arc, time::Instant},
+};
+
+/// Number of events returned by `/events` unless the query asks for a different number.
+const DEFAULT_EVENT_LIMIT: usize = 50;
+
+#[derive(Deserialize)]
+struct EventQuery {
+    backend: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Serves the administrative endpoints, which are kept off the proxied address so they can't
+/// collide with backend paths.
+pub struct AdminHandler {
+    addr: SocketAddr,
+    metrics: Arc<Metrics>,
+    pool: Arc<Pool>,
+}
+
+impl AdminHandler {
+    pub fn new(addr: SocketAddr, metrics: Arc<Metrics>, pool: Arc<Pool>) -> Self {
+        Self {
+            addr,
+            metrics,
+            pool,
+        }
+    }
+
+    async fn metrics(metrics: web::Data<Arc<Metrics>>) -> HttpResponse {
+        HttpResponse::Ok()
+            .content_type("text/plain")
+            .body(metrics.render())
+    }
+
+    /// The state of every backend, including its recent health checks.
+    async fn backends(pool: web::Data<Arc<Pool>>) -> HttpResponse {
+        HttpResponse::Ok().json(Self::describe(&pool))
+    }
+
+    /// Recent backend health changes, newest first, e.g. `/events?backend=10.0.0.1:80&limit=10`.
+    async fn events(pool: web::Data<Arc<Pool>>, query: web::Query<EventQuery>) -> HttpResponse {
+        let events = pool
+            .events()
+            .query(
+                query.backend.as_deref(),
+                query.limit.unwrap_or(DEFAULT_EVENT_LIMIT),
+            )
+            .iter()
+            .map(HealthEvent::to_json)
+            .collect::<Vec<_>>();
+        HttpResponse::Ok().json(json!({ "events": events }))
+    }
+
+    fn describe(pool: &Pool) -> Value {
+        let now = Instant::now();
+        let mut backends = pool.backends();
+        backends.sort_by_key(|backend| backend.config.authority());
+        let backends = backends
+            .iter()
+            .map(|backend| {
+                let history = backend
+                    .history
+                    .iter()
+                    .map(Probe::to_json)
+                    .collect::<Vec<_>>();
+                json!({
+                    "backend": backend.config.authority(),
+                    "status": format!("{:?}", backend.status),
+                    "in_flight": backend.in_flight,
+                    "weight": backend.effective_weight(),
+                    "zone": backend.config.zone,
+                    "tags": backend.config.tags,
+                    "meta": backend.config.meta,
+                    "transitioning": backend.transitioning(),
+                    "held": backend.held(now),
+                    "history": history,
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({ "panic": pool.in_panic(), "backends": backends })
+    }
+
+    pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let (metrics, pool) = (self.metrics.clone(), self.pool.clone());
+        info!("Serving admin endpoints on '{}'.", self.addr);
+        HttpServer::new(move || {
+            App::new()
+                .data(metrics.clone())
+                .data(pool.clone())
+                .route("/metrics", web::get().to(Self::metrics))
+                .route("/backends", web::get().to(Self::backends))
+                .route("/events", web::get().to(Self::events))
+        })
+        .bind(self.addr)?
+        .run()
+        .await?;
+
+        Ok(())
+    }
+}
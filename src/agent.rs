@@ -0,0 +1,132 @@
+use {
+    crate::{
+        config::{BackendConfig, Config, ServerStatus},
+        pool::Pool,
+        with_read_lock, Threadable,
+    },
+    log::warn,
+    std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    },
+    tokio::{
+        io::AsyncReadExt,
+        net::TcpStream,
+        spawn,
+        time::{delay_for, timeout},
+    },
+};
+
+/// Longest agent answer that is read.
+const MAX_REPLY_SIZE: u64 = 512;
+
+/// What a backend's agent asked for.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AgentReport {
+    /// Percentage of the backend's configured weight.
+    pub weight: Option<u32>,
+    pub status: Option<ServerStatus>,
+}
+
+impl AgentReport {
+    /// Parses an answer such as `75%`, `drain`, `maint`, `up` or a combination like `up 50%`.
+    /// Unknown words are ignored.
+    pub fn parse(reply: &str) -> Self {
+        let line = reply.lines().next().unwrap_or_default();
+        line.split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|word| !word.is_empty())
+            .fold(Self::default(), |mut report, word| {
+                match word.to_lowercase().as_str() {
+                    "drain" => report.status = Some(ServerStatus::Draining),
+                    "maint" => report.status = Some(ServerStatus::Maintenance),
+                    "up" | "ready" => report.status = Some(ServerStatus::Alive),
+                    word => {
+                        if let Some(Ok(weight)) = word.strip_suffix('%').map(str::parse::<u32>) {
+                            report.weight = Some(weight);
+                        }
+                    }
+                }
+                report
+            })
+    }
+}
+
+async fn query(addr: &str, limit: Duration) -> Result<String, Box<dyn std::error::Error>> {
+    let reply = timeout(limit, async {
+        let stream = TcpStream::connect(addr).await?;
+        let mut reply = String::new();
+        stream
+            .take(MAX_REPLY_SIZE)
+            .read_to_string(&mut reply)
+            .await?;
+        Ok::<_, std::io::Error>(reply)
+    })
+    .await??;
+    Ok(reply)
+}
+
+/// Polls the agent of `server` for as long as the backend stays in the pool and has one.
+pub fn start(pool: Arc<Pool>, server: BackendConfig) {
+    spawn(async move {
+        let since = pool.joined(&server);
+        loop {
+            let (server, agent) = match pool.current(&server) {
+                Some(server) if pool.joined(&server) == since => match server.agent.clone() {
+                    Some(agent) => (server, agent),
+                    None => return,
+                },
+                _ => return,
+            };
+            let port = agent.port.unwrap_or(server.port);
+            let addr = format!("{}:{}", server.ip, port);
+            let (interval, limit) = (agent.interval, agent.timeout);
+            let start = Instant::now();
+            match query(&addr, limit).await {
+                Ok(reply) => pool.report_agent(&server, &AgentReport::parse(&reply)),
+                Err(e) => warn!("Error querying agent at {}: {}.", addr, e),
+            }
+            let elapsed = start.elapsed();
+            if elapsed < interval {
+                delay_for(interval - elapsed).await;
+            }
+        }
+    });
+}
+
+/// Polls the agent of every backend which has one, folding the answers into the pool.
+pub async fn run(
+    config: Threadable<Config>,
+    pool: Arc<Pool>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let servers = with_read_lock(config, |config| config.backends.clone());
+    for (_, server) in servers.into_iter() {
+        if server.agent.is_some() {
+            start(pool.clone(), server);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reply() {
+        let report = |weight, status| AgentReport { weight, status };
+        assert_eq!(AgentReport::parse("75%\n"), report(Some(75), None));
+        assert_eq!(
+            AgentReport::parse("up, 50%"),
+            report(Some(50), Some(ServerStatus::Alive))
+        );
+        assert_eq!(
+            AgentReport::parse("DRAIN"),
+            report(None, Some(ServerStatus::Draining))
+        );
+        assert_eq!(
+            AgentReport::parse("maint\n10%"),
+            report(None, Some(ServerStatus::Maintenance))
+        );
+        assert_eq!(AgentReport::parse("hello"), AgentReport::default());
+    }
+}
@@ -2,14 +2,32 @@ use {
     crate::{
         algorithm::{
             ip_hash::IPHash, random::Random, round_robin::RoundRobin, url_hash::UriPathHash, least_latency::LeastLatency,
+            least_connections::{LeastConnections, WeightedLeastConnections},
+            weighted_round_robin::WeightedRoundRobin,
         },
         config::{BackendConfig, Config},
     },
     actix_web::{dev::ConnectionInfo, http::Uri},
     async_trait::async_trait,
-    serde::Deserialize,
-    std::fmt,
-    strum_macros::EnumString,
+    schemars::{
+        gen::SchemaGenerator,
+        schema::{
+            InstanceType, Metadata, ObjectValidation, Schema, SchemaObject, SubschemaValidation,
+        },
+        JsonSchema,
+    },
+    serde::{
+        de::{self, DeserializeOwned, MapAccess, Visitor},
+        Deserialize, Deserializer,
+    },
+    std::{
+        collections::{HashMap, HashSet},
+        fmt,
+        marker::PhantomData,
+        str::FromStr,
+    },
+    strum::VariantNames,
+    strum_macros::{Display, EnumString, EnumVariantNames},
     actix::prelude::*,
 };
 
@@ -25,9 +43,20 @@ impl fmt::Display for ServerSelectionError {
     }
 }
 
+/// How loaded a backend currently is, as seen by the pool.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BackendLoad {
+    /// The backend's configured weight, adjusted by the load it reports.
+    pub weight: f64,
+    pub in_flight: usize,
+}
+
+#[derive(Clone)]
 pub struct RequestInfo {
     uri: Uri,
     connection_info: ConnectionInfo,
+    excluded: HashSet<String>,
+    loads: HashMap<String, BackendLoad>,
 }
 
 impl RequestInfo {
@@ -35,9 +64,40 @@ impl RequestInfo {
         Self {
             uri,
             connection_info,
+            excluded: HashSet::new(),
+            loads: HashMap::new(),
         }
     }
 
+    /// Gives strategies the current load of every backend, keyed by authority.
+    pub fn set_loads(&mut self, loads: HashMap<String, BackendLoad>) {
+        self.loads = loads;
+    }
+
+    /// The load of `server`. Backends the pool doesn't know about are assumed idle.
+    pub fn load(&self, server: &BackendConfig) -> BackendLoad {
+        self.loads
+            .get(&server.authority())
+            .copied()
+            .unwrap_or(BackendLoad {
+                weight: server.weight as f64,
+                in_flight: 0,
+            })
+    }
+
+    /// Prevents strategies from choosing `server` for this request, e.g. after a failed attempt.
+    pub fn exclude(&mut self, server: &BackendConfig) {
+        self.excluded.insert(server.authority());
+    }
+
+    pub fn clear_exclusions(&mut self) {
+        self.excluded.clear();
+    }
+
+    pub fn is_excluded(&self, server: &BackendConfig) -> bool {
+        self.excluded.contains(&server.authority())
+    }
+
     pub fn uri(&self) -> &Uri {
         &self.uri
     }
@@ -49,19 +109,155 @@ impl RequestInfo {
 
 /// A user specified dynamic strategy for forwarding requests to a given server.
 /// Requests will be fed to a strategy and a server with an (ip, port, path) triplet will be output.
-#[derive(EnumString, Deserialize, Debug, Clone)]
+/// Configs name a strategy, e.g. `strategy = "RoundRobin"`, or give its parameters in a table
+/// named after it, e.g. `[strategy.LeastLatency]`.
+#[derive(EnumString, EnumVariantNames, Display, Debug, Clone, PartialEq)]
 pub enum Strategy {
     RoundRobin(RoundRobin),
-    WeightedRoundRobin(RoundRobin),
+    WeightedRoundRobin(WeightedRoundRobin),
     Random(Random),
-    LeastConnections(RoundRobin),
-    WeightedLeastConnections(RoundRobin),
+    LeastConnections(LeastConnections),
+    WeightedLeastConnections(WeightedLeastConnections),
     UriPathHash(UriPathHash),
     SourceIPHash(IPHash),
     LeastTraffic(RoundRobin),
     LeastLatency(LeastLatency),
 }
 
+impl Strategy {
+    /// Hands the parameters type of the strategy called `name` to `with`, along with the variant
+    /// wrapping it. This is the one list of strategies' parameters, which both deserializing and
+    /// the schema go through.
+    fn with_parameters<W: WithParameters>(name: &str, with: W) -> Option<W::Output> {
+        Some(match name {
+            "RoundRobin" => with.apply(Strategy::RoundRobin),
+            "WeightedRoundRobin" => with.apply(Strategy::WeightedRoundRobin),
+            "Random" => with.apply(Strategy::Random),
+            "LeastConnections" => with.apply(Strategy::LeastConnections),
+            "WeightedLeastConnections" => with.apply(Strategy::WeightedLeastConnections),
+            "UriPathHash" => with.apply(Strategy::UriPathHash),
+            "SourceIPHash" => with.apply(Strategy::SourceIPHash),
+            "LeastTraffic" => with.apply(Strategy::LeastTraffic),
+            "LeastLatency" => with.apply(Strategy::LeastLatency),
+            _ => return None,
+        })
+    }
+}
+
+/// Something done with a strategy's parameters type, see `Strategy::with_parameters`.
+trait WithParameters {
+    type Output;
+
+    fn apply<T: DeserializeOwned + JsonSchema>(self, variant: fn(T) -> Strategy) -> Self::Output;
+}
+
+/// Deserializes the parameters of a strategy from the value of a table's entry.
+struct NextValue<'a, 'de, A>(&'a mut A, PhantomData<&'de ()>);
+
+impl<'de, A: MapAccess<'de>> WithParameters for NextValue<'_, 'de, A> {
+    type Output = Result<Strategy, A::Error>;
+
+    fn apply<T: DeserializeOwned + JsonSchema>(self, variant: fn(T) -> Strategy) -> Self::Output {
+        self.0.next_value().map(variant)
+    }
+}
+
+/// The schema of a strategy's parameters.
+struct ParametersSchema<'a>(&'a mut SchemaGenerator);
+
+impl WithParameters for ParametersSchema<'_> {
+    type Output = Schema;
+
+    fn apply<T: DeserializeOwned + JsonSchema>(self, _: fn(T) -> Strategy) -> Self::Output {
+        self.0.subschema_for::<T>()
+    }
+}
+
+struct StrategyVisitor;
+
+impl<'de> Visitor<'de> for StrategyVisitor {
+    type Value = Strategy;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a strategy name, or a table of parameters named after the strategy")
+    }
+
+    fn visit_str<E: de::Error>(self, name: &str) -> Result<Strategy, E> {
+        Strategy::from_str(name).map_err(|_| E::unknown_variant(name, Strategy::VARIANTS))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Strategy, A::Error> {
+        let name: String = map
+            .next_key()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let strategy = Strategy::with_parameters(&name, NextValue(&mut map, PhantomData))
+            .ok_or_else(|| de::Error::unknown_variant(&name, Strategy::VARIANTS))??;
+        if map.next_key::<String>()?.is_some() {
+            return Err(de::Error::custom("only one strategy may be given"));
+        }
+        Ok(strategy)
+    }
+}
+
+impl<'de> Deserialize<'de> for Strategy {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(StrategyVisitor)
+    }
+}
+
+impl JsonSchema for Strategy {
+    fn schema_name() -> String {
+        String::from("Strategy")
+    }
+
+    /// Either a strategy's name, or a table with a single key naming the strategy and its
+    /// parameters as the value, matching what `StrategyVisitor` accepts.
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let parameters = Strategy::VARIANTS
+            .iter()
+            .filter_map(|name| {
+                Strategy::with_parameters(name, ParametersSchema(gen))
+                    .map(|schema| (name.to_string(), schema))
+            })
+            .collect();
+        let name = SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            enum_values: Some(
+                Strategy::VARIANTS
+                    .iter()
+                    .map(|name| (*name).into())
+                    .collect(),
+            ),
+            ..SchemaObject::default()
+        };
+        let table = SchemaObject {
+            instance_type: Some(InstanceType::Object.into()),
+            object: Some(Box::new(ObjectValidation {
+                min_properties: Some(1),
+                max_properties: Some(1),
+                properties: parameters,
+                ..ObjectValidation::default()
+            })),
+            ..SchemaObject::default()
+        };
+        SchemaObject {
+            metadata: Some(Box::new(Metadata {
+                description: Some(String::from(
+                    "A strategy's name, e.g. \"RoundRobin\", or a table of its parameters named \
+                     after it.",
+                )),
+                ..Metadata::default()
+            })),
+            subschemas: Some(Box::new(SubschemaValidation {
+                one_of: Some(vec![name.into(), table.into()]),
+                ..SubschemaValidation::default()
+            })),
+            ..SchemaObject::default()
+        }
+        .into()
+    }
+}
+
 impl Actor for Strategy {
     type Context = Context<Self>;
 }
@@ -69,10 +265,17 @@ impl Actor for Strategy {
 #[async_trait]
 pub trait Algorithm {
     /// Configuration for strategies such as initializing servers.
+    /// Called again with the new config when it is reloaded.
     fn configure(&mut self, config: &Config);
 
     /// Determines the server to which the given request should be forwarded.
     async fn server(&mut self, req: &RequestInfo) -> Option<BackendConfig>;
+
+    /// Whether some server could serve the request once it is available, which isn't the case
+    /// when the strategy maps requests to servers and none matches.
+    fn can_serve(&self, _req: &RequestInfo) -> bool {
+        true
+    }
 }
 
 #[async_trait]
@@ -106,4 +309,12 @@ impl Algorithm for Strategy {
             Strategy::WeightedLeastConnections(ref mut strategy) => strategy.server(req).await,
         }
     }
+
+    fn can_serve(&self, req: &RequestInfo) -> bool {
+        match *self {
+            Strategy::SourceIPHash(ref strategy) => strategy.can_serve(req),
+            Strategy::UriPathHash(ref strategy) => strategy.can_serve(req),
+            _ => true,
+        }
+    }
 }
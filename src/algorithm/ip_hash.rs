@@ -4,19 +4,22 @@ use {
         config::*,
     },
     async_trait::async_trait,
+    schemars::JsonSchema,
     serde::Deserialize,
     std::collections::HashMap,
 };
 
 /// Maps the given request to a server using the URL's path as a directive.
-#[derive(Default, Debug, Deserialize, Clone)]
+#[derive(Default, Debug, Deserialize, JsonSchema, Clone, PartialEq)]
 pub struct IPHash {
+    #[serde(skip)]
     ip_mappings: HashMap<String, BackendConfig>,
 }
 
 #[async_trait]
 impl Algorithm for IPHash {
     fn configure(&mut self, config: &Config) {
+        self.ip_mappings.clear();
         for (name, mapping) in config.mappings.iter() {
             let path = mapping.path.clone();
             if let Some(backend) = config.backends.get(name) {
@@ -24,11 +27,7 @@ impl Algorithm for IPHash {
                     path,
                     BackendConfig {
                         status: ServerStatus::Alive,
-                        ip: backend.ip().clone(),
-                        port: backend.port().clone(),
-                        scheme: backend.scheme().clone(),
-                        path: backend.path().clone(),
-                        num_connections: backend.num_connections().clone(),
+                        ..backend.clone()
                     },
                 );
             }
@@ -37,6 +36,15 @@ impl Algorithm for IPHash {
 
     async fn server(&mut self, req: &RequestInfo) -> Option<BackendConfig> {
         let host = req.uri().host().unwrap();
-        self.ip_mappings.get(host).map(ToOwned::to_owned)
+        self.ip_mappings
+            .get(host)
+            .filter(|server| !req.is_excluded(server))
+            .map(ToOwned::to_owned)
+    }
+
+    fn can_serve(&self, req: &RequestInfo) -> bool {
+        req.uri()
+            .host()
+            .is_some_and(|host| self.ip_mappings.contains_key(host))
     }
 }
@@ -0,0 +1,99 @@
+use {
+    crate::{
+        algorithm::algorithm::{Algorithm, BackendLoad, RequestInfo},
+        config::{BackendConfig, Config, ServerStatus},
+    },
+    async_trait::async_trait,
+    schemars::JsonSchema,
+    serde::Deserialize,
+};
+
+/// Chooses the candidate with the lowest cost, preferring earlier servers on ties.
+fn least<F>(servers: &[BackendConfig], req: &RequestInfo, cost: F) -> Option<BackendConfig>
+where
+    F: Fn(BackendLoad) -> f64,
+{
+    servers
+        .iter()
+        .filter(|server| !req.is_excluded(server))
+        .map(|server| (server, cost(req.load(server))))
+        .fold(
+            None,
+            |best: Option<(&BackendConfig, f64)>, (server, cost)| match best {
+                Some((_, lowest)) if lowest <= cost => best,
+                _ => Some((server, cost)),
+            },
+        )
+        .map(|(server, _)| server.clone())
+}
+
+/// Cost of sending another request to a backend with the given load, relative to its weight.
+fn weighted_cost(load: BackendLoad) -> f64 {
+    if load.weight > 0.0 {
+        (load.in_flight + 1) as f64 / load.weight
+    } else {
+        f64::INFINITY
+    }
+}
+
+/// Sends requests to the backend with the fewest requests in flight.
+#[derive(Default, Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct LeastConnections {
+    #[serde(skip)]
+    pub servers: Vec<BackendConfig>,
+}
+
+#[async_trait]
+impl Algorithm for LeastConnections {
+    fn configure(&mut self, config: &Config) {
+        self.servers.clear();
+        for backend in config.backends.values() {
+            self.servers.push(BackendConfig {
+                status: ServerStatus::Alive,
+                ..backend.clone()
+            })
+        }
+    }
+
+    async fn server(&mut self, req: &RequestInfo) -> Option<BackendConfig> {
+        least(&self.servers, req, |load| load.in_flight as f64)
+    }
+}
+
+/// Sends requests to the backend with the fewest requests in flight relative to its weight,
+/// which includes the load the backend reports about itself.
+#[derive(Default, Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct WeightedLeastConnections {
+    #[serde(skip)]
+    pub servers: Vec<BackendConfig>,
+}
+
+#[async_trait]
+impl Algorithm for WeightedLeastConnections {
+    fn configure(&mut self, config: &Config) {
+        self.servers.clear();
+        for backend in config.backends.values() {
+            self.servers.push(BackendConfig {
+                status: ServerStatus::Alive,
+                ..backend.clone()
+            })
+        }
+    }
+
+    async fn server(&mut self, req: &RequestInfo) -> Option<BackendConfig> {
+        least(&self.servers, req, weighted_cost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_weighted_cost() {
+        let load = |weight, in_flight| BackendLoad { weight, in_flight };
+        assert!(weighted_cost(load(4.0, 3)) < weighted_cost(load(1.0, 1)));
+        assert!(weighted_cost(load(1.0, 0)) < weighted_cost(load(0.5, 0)));
+        assert_eq!(weighted_cost(load(0.0, 0)), f64::INFINITY);
+    }
+}
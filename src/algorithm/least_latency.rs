@@ -5,35 +5,42 @@ use {
     },
     async_trait::async_trait,
     futures::{stream, StreamExt},
+    schemars::JsonSchema,
     serde::Deserialize,
     tokio::net::TcpStream,
     actix::clock::Instant,
     futures::future::ready
 };
 
-#[derive(Default, Debug, Deserialize, Clone)]
+#[derive(Default, Debug, Deserialize, JsonSchema, Clone, PartialEq)]
 pub struct LeastLatency {
+    #[serde(skip)]
     pub current_server: usize,
+    #[serde(skip)]
     pub servers: Vec<BackendConfig>,
 }
 
 #[async_trait]
 impl Algorithm for LeastLatency {
     fn configure(&mut self, config: &Config) {
+        self.servers.clear();
         for (_, backend) in config.backends.iter() {
             self.servers.push(BackendConfig {
                 status: ServerStatus::Alive,
-                ip: backend.ip().clone(),
-                port: backend.port().clone(),
-                scheme: backend.scheme().clone(),
-                path: backend.path().clone(),
-                num_connections: backend.num_connections().clone(),
+                ..backend.clone()
             })
         }
     }
 
-    async fn server(&mut self, _: &RequestInfo) -> Option<BackendConfig> {
-        let mut times = stream::iter(self.servers.clone())
+    async fn server(&mut self, req: &RequestInfo) -> Option<BackendConfig> {
+        let candidates = self
+            .servers
+            .iter()
+            .filter(|server| !req.is_excluded(server))
+            .cloned()
+            .collect::<Vec<_>>();
+        let len = candidates.len().max(1);
+        let mut times = stream::iter(candidates)
             .map(|server| async move {
                 let now = Instant::now();
                 TcpStream::connect(format!("{}:{}", server.ip(), server.port()))
@@ -41,7 +48,7 @@ impl Algorithm for LeastLatency {
                     .is_ok()
                     .then_some((server, now.elapsed()))
             })
-            .buffer_unordered(self.servers.len())
+            .buffer_unordered(len)
             .filter(|res| ready(res.is_some()))
             .map(|res| res.unwrap())
             .collect::<Vec<_>>().await;
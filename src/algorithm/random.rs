@@ -5,31 +5,38 @@ use {
     },
     async_trait::async_trait,
     rand::Rng,
+    schemars::JsonSchema,
     serde::Deserialize,
 };
 
-#[derive(Default, Debug, Deserialize, Clone)]
+#[derive(Default, Debug, Deserialize, JsonSchema, Clone, PartialEq)]
 pub struct Random {
+    #[serde(skip)]
     pub servers: Vec<BackendConfig>,
 }
 
 #[async_trait]
 impl Algorithm for Random {
     fn configure(&mut self, config: &Config) {
+        self.servers.clear();
         for (_, backend) in config.backends.iter() {
             self.servers.push(BackendConfig {
                 status: ServerStatus::Alive,
-                ip: backend.ip().clone(),
-                port: backend.port().clone(),
-                scheme: backend.scheme().clone(),
-                path: backend.path().clone(),
-                num_connections: backend.num_connections().clone(),
+                ..backend.clone()
             })
         }
     }
 
-    async fn server(&mut self, _: &RequestInfo) -> Option<BackendConfig> {
-        let i = rand::thread_rng().gen_range(0, self.servers.len());
-        self.servers.get(i).map(ToOwned::to_owned)
+    async fn server(&mut self, req: &RequestInfo) -> Option<BackendConfig> {
+        let candidates = self
+            .servers
+            .iter()
+            .filter(|server| !req.is_excluded(server))
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return None;
+        }
+        let i = rand::thread_rng().gen_range(0, candidates.len());
+        candidates.get(i).map(|server| (*server).to_owned())
     }
 }
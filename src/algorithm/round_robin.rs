@@ -4,33 +4,42 @@ use {
         config::{BackendConfig, Config, ServerStatus},
     },
     async_trait::async_trait,
+    schemars::JsonSchema,
     serde::Deserialize,
 };
 
-#[derive(Default, Debug, Deserialize, Clone)]
+#[derive(Default, Debug, Deserialize, JsonSchema, Clone, PartialEq)]
 pub struct RoundRobin {
+    #[serde(skip)]
     pub current_server: usize,
+    #[serde(skip)]
     pub servers: Vec<BackendConfig>,
 }
 
 #[async_trait]
 impl Algorithm for RoundRobin {
     fn configure(&mut self, config: &Config) {
+        self.servers.clear();
         for (_, backend) in config.backends.iter() {
             self.servers.push(BackendConfig {
                 status: ServerStatus::Alive,
-                ip: backend.ip().clone(),
-                port: backend.port().clone(),
-                scheme: backend.scheme().clone(),
-                path: backend.path().clone(),
-                num_connections: backend.num_connections().clone(),
+                ..backend.clone()
             })
         }
     }
 
-    async fn server(&mut self, _: &RequestInfo) -> Option<BackendConfig> {
-        let (i, len) = (self.current_server, self.servers.len());
-        self.current_server = (self.current_server + 1) % len;
-        self.servers.get(i).map(ToOwned::to_owned)
+    async fn server(&mut self, req: &RequestInfo) -> Option<BackendConfig> {
+        let len = self.servers.len();
+        if self.current_server >= len {
+            self.current_server = 0;
+        }
+        for _ in 0..len {
+            let i = self.current_server;
+            self.current_server = (self.current_server + 1) % len;
+            if !req.is_excluded(&self.servers[i]) {
+                return self.servers.get(i).map(ToOwned::to_owned);
+            }
+        }
+        None
     }
 }
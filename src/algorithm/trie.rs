@@ -78,6 +78,29 @@ impl Trie {
             Some(matching_word)
         }
     }
+
+    #[inline]
+    /// Get the longest inserted word which is a prefix of the given word.
+    /// If no inserted word is a prefix of it, return None.
+    pub fn longest_match(&self, word: &str) -> Option<String> {
+        let mut matching_word = String::new();
+        let mut longest = None;
+        let mut cur_node = self.root();
+        for c in word.chars() {
+            match cur_node.get_child(c) {
+                Some(child) => {
+                    matching_word.push(c);
+                    cur_node = child;
+                    if cur_node.is_end {
+                        longest = Some(matching_word.clone());
+                    }
+                }
+                None => break,
+            }
+        }
+
+        longest
+    }
 }
 
 #[derive(Default, Debug, Clone)]
@@ -166,4 +189,20 @@ mod tests {
         assert_eq!(trie.longest_prefix("trie"), Some("trie".to_string()));
         assert_eq!(trie.longest_prefix("hello"), Some("hello".to_string()));
     }
+
+    #[test]
+    fn test_longest_match() {
+        let mut trie = Trie::new();
+        trie.insert("/");
+        trie.insert("/api");
+        trie.insert("/api/users");
+
+        assert_eq!(
+            trie.longest_match("/api/users/1"),
+            Some("/api/users".to_string())
+        );
+        assert_eq!(trie.longest_match("/api/orders"), Some("/api".to_string()));
+        assert_eq!(trie.longest_match("/static"), Some("/".to_string()));
+        assert_eq!(Trie::new().longest_match("/static"), None);
+    }
 }
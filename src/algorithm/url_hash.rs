@@ -4,19 +4,22 @@ use {
         config::*,
     },
     async_trait::async_trait,
+    schemars::JsonSchema,
     serde::Deserialize,
     std::collections::HashMap,
 };
 
 /// Maps the given request to a server using the URL's path as a directive.
-#[derive(Default, Debug, Deserialize, Clone)]
+#[derive(Default, Debug, Deserialize, JsonSchema, Clone, PartialEq)]
 pub struct UriPathHash {
+    #[serde(skip)]
     url_mappings: HashMap<String, BackendConfig>,
 }
 
 #[async_trait]
 impl Algorithm for UriPathHash {
     fn configure(&mut self, config: &Config) {
+        self.url_mappings.clear();
         for (name, mapping) in config.mappings.iter() {
             let path = mapping.path.clone();
             if let Some(backend) = config.backends.get(name) {
@@ -24,11 +27,7 @@ impl Algorithm for UriPathHash {
                     path,
                     BackendConfig {
                         status: ServerStatus::Alive,
-                        ip: backend.ip().clone(),
-                        port: backend.port().clone(),
-                        scheme: backend.scheme().clone(),
-                        path: backend.path().clone(),
-                        num_connections: backend.num_connections().clone(),
+                        ..backend.clone()
                     },
                 );
             }
@@ -37,6 +36,13 @@ impl Algorithm for UriPathHash {
 
     async fn server(&mut self, req: &RequestInfo) -> Option<BackendConfig> {
         let req_path = req.uri().path();
-        self.url_mappings.get(req_path).map(ToOwned::to_owned)
+        self.url_mappings
+            .get(req_path)
+            .filter(|server| !req.is_excluded(server))
+            .map(ToOwned::to_owned)
+    }
+
+    fn can_serve(&self, req: &RequestInfo) -> bool {
+        self.url_mappings.contains_key(req.uri().path())
     }
 }
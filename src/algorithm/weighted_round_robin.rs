@@ -0,0 +1,126 @@
+use {
+    crate::{
+        algorithm::algorithm::{Algorithm, RequestInfo},
+        config::{BackendConfig, Config, ServerStatus},
+    },
+    async_trait::async_trait,
+    schemars::JsonSchema,
+    serde::Deserialize,
+    std::collections::HashMap,
+};
+
+/// Smooth weighted round robin, as used by nginx.
+/// Every backend gains its weight on each pick and the backend with the most is chosen and pays
+/// back the total, which spreads heavier backends out instead of sending them bursts.
+#[derive(Default, Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct WeightedRoundRobin {
+    #[serde(skip)]
+    pub servers: Vec<BackendConfig>,
+    #[serde(skip)]
+    current: HashMap<String, f64>,
+}
+
+impl WeightedRoundRobin {
+    fn pick(&mut self, candidates: Vec<(BackendConfig, f64)>) -> Option<BackendConfig> {
+        let weighted = candidates.iter().any(|(_, weight)| *weight > 0.0);
+        let mut total = 0.0;
+        let mut best: Option<(BackendConfig, f64)> = None;
+        for (server, weight) in candidates {
+            let weight = if weighted { weight.max(0.0) } else { 1.0 };
+            total += weight;
+            let current = self.current.entry(server.authority()).or_insert(0.0);
+            *current += weight;
+            if best.as_ref().is_none_or(|(_, max)| *current > *max) {
+                best = Some((server, *current));
+            }
+        }
+        let (server, _) = best?;
+        if let Some(current) = self.current.get_mut(&server.authority()) {
+            *current -= total;
+        }
+        Some(server)
+    }
+}
+
+#[async_trait]
+impl Algorithm for WeightedRoundRobin {
+    fn configure(&mut self, config: &Config) {
+        self.servers.clear();
+        for backend in config.backends.values() {
+            self.servers.push(BackendConfig {
+                status: ServerStatus::Alive,
+                ..backend.clone()
+            })
+        }
+        let servers = &self.servers;
+        self.current.retain(|authority, _| {
+            servers
+                .iter()
+                .any(|server| server.authority() == *authority)
+        });
+    }
+
+    async fn server(&mut self, req: &RequestInfo) -> Option<BackendConfig> {
+        let candidates = self
+            .servers
+            .iter()
+            .filter(|server| !req.is_excluded(server))
+            .map(|server| (server.clone(), req.load(server).weight))
+            .collect::<Vec<_>>();
+        self.pick(candidates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(port: u16) -> BackendConfig {
+        BackendConfig {
+            port,
+            ..BackendConfig::default()
+        }
+    }
+
+    fn picks(strategy: &mut WeightedRoundRobin, weights: &[(u16, f64)], n: usize) -> Vec<u16> {
+        (0..n)
+            .filter_map(|_| {
+                let candidates = weights
+                    .iter()
+                    .map(|(port, weight)| (server(*port), *weight))
+                    .collect();
+                strategy.pick(candidates)
+            })
+            .map(|server| server.port)
+            .collect()
+    }
+
+    #[test]
+    fn test_smooth_weights() {
+        let mut strategy = WeightedRoundRobin::default();
+        let picks = picks(&mut strategy, &[(1, 5.0), (2, 1.0), (3, 1.0)], 7);
+        assert_eq!(picks, vec![1, 1, 2, 1, 3, 1, 1]);
+    }
+
+    #[test]
+    fn test_zero_weights() {
+        let mut strategy = WeightedRoundRobin::default();
+        assert_eq!(
+            picks(&mut strategy, &[(1, 0.0), (2, 1.0)], 3),
+            vec![2, 2, 2]
+        );
+        let mut strategy = WeightedRoundRobin::default();
+        assert_eq!(picks(&mut strategy, &[(1, 0.0), (2, 0.0)], 2).len(), 2);
+    }
+
+    #[test]
+    fn test_configure_forgets_removed_backends() {
+        let mut strategy = WeightedRoundRobin::default();
+        picks(&mut strategy, &[(1, 1.0), (2, 1.0)], 3);
+        let mut config = Config::default();
+        config.backends.insert("one".to_string(), server(1));
+        strategy.configure(&config);
+        assert_eq!(strategy.current.len(), 1);
+        assert!(strategy.current.contains_key(&server(1).authority()));
+    }
+}
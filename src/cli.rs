@@ -0,0 +1,126 @@
+use {
+    crate::format::Format,
+    log::LevelFilter,
+    std::{net::SocketAddr, path::PathBuf},
+    structopt::StructOpt,
+    strum::VariantNames,
+};
+
+/// An L7 load balancer.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "loblaw")]
+pub struct Cli {
+    /// Most verbose level logged: off, error, warn, info, debug or trace.
+    #[structopt(long, default_value = "info", global = true)]
+    pub log_level: LevelFilter,
+    /// What to do. Defaults to running with `config.toml`.
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Runs the load balancer.
+    Run {
+        /// Path of the config file.
+        #[structopt(long, short, default_value = "config.toml")]
+        config: PathBuf,
+        /// Language the config is written in: toml, yaml or json. Defaults to going by its
+        /// extension.
+        #[structopt(long, possible_values = Format::VARIANTS)]
+        format: Option<Format>,
+        /// Address to accept requests on, overriding the config's `ip` and `port`.
+        #[structopt(long)]
+        listen: Option<SocketAddr>,
+        /// Reloads the config whenever the file changes, as well as on SIGHUP.
+        #[structopt(long)]
+        watch: bool,
+    },
+    /// Validates a config file and prints the resolved settings.
+    Check {
+        /// Path of the config file.
+        path: PathBuf,
+        /// Language the config is written in: toml, yaml or json. Defaults to going by its
+        /// extension.
+        #[structopt(long, possible_values = Format::VARIANTS)]
+        format: Option<Format>,
+    },
+    /// Prints a JSON Schema of the config, for editors and CI to validate configs with.
+    Schema,
+    /// Prints the version.
+    Version,
+}
+
+impl Cli {
+    pub fn command(&self) -> Command {
+        self.command.clone().unwrap_or(Command::Run {
+            config: PathBuf::from("config.toml"),
+            format: None,
+            listen: None,
+            watch: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Cli {
+        Cli::from_iter_safe(std::iter::once("loblaw").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn test_run() {
+        let cli = parse(&[
+            "run",
+            "--config",
+            "a.toml",
+            "--listen",
+            "0.0.0.0:80",
+            "--watch",
+            "--format",
+            "yaml",
+        ]);
+        assert_eq!(
+            cli.command(),
+            Command::Run {
+                config: PathBuf::from("a.toml"),
+                format: Some(Format::Yaml),
+                listen: Some("0.0.0.0:80".parse().unwrap()),
+                watch: true,
+            }
+        );
+        assert_eq!(cli.log_level, LevelFilter::Info);
+    }
+
+    #[test]
+    fn test_defaults_to_run() {
+        let cli = parse(&["--log-level", "debug"]);
+        assert_eq!(cli.log_level, LevelFilter::Debug);
+        assert_eq!(
+            cli.command(),
+            Command::Run {
+                config: PathBuf::from("config.toml"),
+                format: None,
+                listen: None,
+                watch: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_check_and_version() {
+        assert_eq!(
+            parse(&["check", "b.toml", "--log-level", "warn"]).command(),
+            Command::Check {
+                path: PathBuf::from("b.toml"),
+                format: None,
+            }
+        );
+        assert_eq!(parse(&["version"]).command(), Command::Version);
+        assert_eq!(parse(&["schema"]).command(), Command::Schema);
+        assert!(Cli::from_iter_safe(&["loblaw", "run", "--listen", "nowhere"]).is_err());
+        assert!(Cli::from_iter_safe(&["loblaw", "check", "c", "--format", "ini"]).is_err());
+    }
+}
@@ -0,0 +1,97 @@
+use {
+    crate::config::{BackendConfig, CommandCheckConfig},
+    std::{process::Stdio, time::Duration},
+    tokio::{process::Command, time::timeout},
+};
+
+/// Longest output kept from a command, in characters.
+const MAX_OUTPUT: usize = 1024;
+
+/// The command's stdout and stderr, trimmed and truncated to `MAX_OUTPUT` characters.
+fn describe(stdout: &[u8], stderr: &[u8]) -> String {
+    let output = [stdout, stderr]
+        .iter()
+        .map(|output| String::from_utf8_lossy(output).trim().to_string())
+        .filter(|output| !output.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if output.chars().count() > MAX_OUTPUT {
+        format!("{}...", output.chars().take(MAX_OUTPUT).collect::<String>())
+    } else {
+        output
+    }
+}
+
+/// Runs the configured command against `server`, succeeding if it exits with status 0.
+pub async fn check(
+    config: &CommandCheckConfig,
+    server: &BackendConfig,
+    limit: Duration,
+) -> Result<String, String> {
+    let limit = config.timeout.unwrap_or(limit);
+    let child = Command::new(&config.path)
+        .args(&config.args)
+        .env("LOBLAW_BACKEND_IP", &server.ip)
+        .env("LOBLAW_BACKEND_PORT", server.port.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("could not run '{}': {}", config.path, e))?;
+
+    let output = timeout(limit, child.wait_with_output())
+        .await
+        .map_err(|_| format!("'{}' timed out after {}ms", config.path, limit.as_millis()))?
+        .map_err(|e| format!("could not run '{}': {}", config.path, e))?;
+    let detail = describe(&output.stdout, &output.stderr);
+    match output.status.code() {
+        Some(0) => Ok(detail),
+        Some(code) => Err(format!("exit status {}: {}", code, detail)),
+        None => Err(format!("killed by a signal: {}", detail)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sh(script: &str) -> CommandCheckConfig {
+        CommandCheckConfig {
+            path: String::from("sh"),
+            args: vec![String::from("-c"), script.to_string()],
+            timeout: None,
+        }
+    }
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(describe(b"ok\n", b""), "ok");
+        assert_eq!(describe(b" out ", b"err\n"), "out\nerr");
+        assert_eq!(describe(&[b'a'; MAX_OUTPUT + 1], b"").len(), MAX_OUTPUT + 3);
+    }
+
+    #[tokio::test]
+    async fn test_exit_status() {
+        let server = BackendConfig::default();
+        let limit = Duration::from_secs(5);
+        let healthy = check(
+            &sh("echo $LOBLAW_BACKEND_IP:$LOBLAW_BACKEND_PORT"),
+            &server,
+            limit,
+        )
+        .await;
+        assert_eq!(healthy, Ok(server.authority()));
+
+        let unhealthy = check(&sh("echo lagging >&2; exit 3"), &server, limit).await;
+        assert_eq!(unhealthy, Err(String::from("exit status 3: lagging")));
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let mut config = sh("sleep 5");
+        config.timeout = Some(Duration::from_millis(50));
+        let result = check(&config, &BackendConfig::default(), Duration::from_secs(5)).await;
+        assert!(result.unwrap_err().contains("timed out"));
+    }
+}
@@ -0,0 +1,292 @@
+use {
+    crate::{
+        config::{ConcurrencyAlgorithm, ConcurrencyConfig},
+        error::UpstreamError,
+    },
+    actix_web::http::StatusCode,
+    std::{
+        sync::{Arc, Mutex, MutexGuard},
+        time::{Duration, Instant},
+    },
+    tokio::{sync::Notify, time::timeout},
+};
+
+/// Number of samples after which the lowest observed latency is forgotten, so the gradient
+/// algorithm can follow backends whose baseline latency changes.
+const MIN_LATENCY_RESET_SAMPLES: usize = 1000;
+
+#[derive(Debug)]
+struct LimiterState {
+    limit: f64,
+    in_flight: usize,
+    min_latency: Option<Duration>,
+    samples: usize,
+}
+
+impl LimiterState {
+    /// Whether enough requests are in flight for the limit to matter, which is the only time
+    /// samples can tell whether the limit should grow.
+    fn busy(&self) -> bool {
+        self.in_flight as f64 * 2.0 >= self.limit
+    }
+}
+
+/// Whether the outcome of an attempt tells of an overloaded backend: a timeout, a connection
+/// which couldn't be established, or a `503` or `429` answer. Other errors are the
+/// application's, and say nothing about load.
+pub fn dropped(outcome: Result<StatusCode, &UpstreamError>) -> bool {
+    match outcome {
+        Ok(status) => {
+            status == StatusCode::SERVICE_UNAVAILABLE || status == StatusCode::TOO_MANY_REQUESTS
+        }
+        Err(e) => matches!(
+            e,
+            UpstreamError::ConnectTimeout
+                | UpstreamError::ResponseHeaderTimeout
+                | UpstreamError::PerTryTimeout
+                | UpstreamError::Connect(_)
+        ),
+    }
+}
+
+/// Limits the requests in flight, adjusting the limit according to the configured algorithm.
+#[derive(Debug)]
+pub struct ConcurrencyLimiter {
+    config: ConcurrencyConfig,
+    state: Mutex<LimiterState>,
+    released: Notify,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(config: ConcurrencyConfig) -> Self {
+        let limit = config
+            .initial_limit
+            .max(config.min_limit)
+            .min(config.max_limit) as f64;
+        Self {
+            config,
+            state: Mutex::new(LimiterState {
+                limit,
+                in_flight: 0,
+                min_latency: None,
+                samples: 0,
+            }),
+            released: Notify::new(),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.lock().expect("Could not lock mutex.").limit as usize
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().expect("Could not lock mutex.").in_flight
+    }
+
+    /// Takes a slot if the limit allows it.
+    pub fn try_acquire(self: &Arc<Self>) -> Option<ConcurrencyPermit> {
+        let mut state = self.state.lock().expect("Could not lock mutex.");
+        if state.in_flight < state.limit as usize {
+            state.in_flight += 1;
+            Some(ConcurrencyPermit {
+                limiter: self.clone(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Waits up to the configured queue timeout for a slot.
+    pub async fn acquire(self: &Arc<Self>) -> Option<ConcurrencyPermit> {
+        let deadline = Instant::now() + self.config.queue_timeout;
+        loop {
+            if let Some(permit) = self.try_acquire() {
+                return Some(permit);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            let _ = timeout(deadline - now, self.released.notified()).await;
+        }
+    }
+
+    /// Feeds the latency of an attempt, from sending it to its response headers, and whether it
+    /// was dropped to the limit algorithm.
+    pub fn record(&self, latency: Duration, dropped: bool) {
+        let mut state = self.state.lock().expect("Could not lock mutex.");
+        self.update(&mut state, latency, dropped);
+        self.wake(state);
+    }
+
+    /// The lowest latency recorded recently.
+    #[allow(dead_code)]
+    pub fn min_latency(&self) -> Option<Duration> {
+        self.state
+            .lock()
+            .expect("Could not lock mutex.")
+            .min_latency
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().expect("Could not lock mutex.");
+        state.in_flight -= 1;
+        self.wake(state);
+    }
+
+    /// Wakes as many waiters as there are free slots.
+    fn wake(&self, state: MutexGuard<LimiterState>) {
+        let free = (state.limit as usize).saturating_sub(state.in_flight);
+        drop(state);
+        for _ in 0..free.max(1) {
+            self.released.notify();
+        }
+    }
+
+    fn update(&self, state: &mut LimiterState, latency: Duration, dropped: bool) {
+        let (min, max) = (self.config.min_limit as f64, self.config.max_limit as f64);
+        state.samples += 1;
+        if state.samples >= MIN_LATENCY_RESET_SAMPLES {
+            state.samples = 0;
+            state.min_latency = None;
+        }
+        let min_latency = state.min_latency.map_or(latency, |min| min.min(latency));
+        state.min_latency = Some(min_latency);
+
+        let limit = match self.config.algorithm {
+            ConcurrencyAlgorithm::Aimd {
+                latency_threshold,
+                backoff,
+            } => {
+                if dropped || latency > latency_threshold {
+                    state.limit * backoff
+                } else if state.busy() {
+                    state.limit + 1.0
+                } else {
+                    state.limit
+                }
+            }
+            ConcurrencyAlgorithm::Gradient { smoothing } => {
+                let gradient = if dropped {
+                    0.5
+                } else {
+                    (min_latency.as_secs_f64() / latency.as_secs_f64().max(f64::EPSILON))
+                        .clamp(0.5, 1.0)
+                };
+                let headroom = if state.busy() {
+                    state.limit.sqrt()
+                } else {
+                    0.0
+                };
+                let target = state.limit * gradient + headroom;
+                state.limit * (1.0 - smoothing) + target * smoothing
+            }
+        };
+        state.limit = limit.clamp(min, max);
+    }
+}
+
+/// A slot in the limiter, released when dropped.
+pub struct ConcurrencyPermit {
+    limiter: Arc<ConcurrencyLimiter>,
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        self.limiter.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(algorithm: ConcurrencyAlgorithm, initial_limit: usize) -> Arc<ConcurrencyLimiter> {
+        Arc::new(ConcurrencyLimiter::new(ConcurrencyConfig {
+            algorithm,
+            initial_limit,
+            min_limit: 1,
+            max_limit: 100,
+            queue_timeout: Duration::from_millis(0),
+        }))
+    }
+
+    #[test]
+    fn test_limit_is_enforced() {
+        let limiter = limiter(ConcurrencyAlgorithm::default(), 2);
+        let first = limiter.try_acquire();
+        let _second = limiter.try_acquire();
+        assert!(first.is_some());
+        assert!(limiter.try_acquire().is_none());
+
+        drop(first);
+        assert!(limiter.try_acquire().is_some());
+    }
+
+    #[test]
+    fn test_aimd_backs_off_on_slow_requests() {
+        let limiter = limiter(
+            ConcurrencyAlgorithm::Aimd {
+                latency_threshold: Duration::from_millis(100),
+                backoff: 0.5,
+            },
+            10,
+        );
+        let mut state = limiter.state.lock().unwrap();
+        state.in_flight = 10;
+        limiter.update(&mut state, Duration::from_millis(10), false);
+        assert_eq!(state.limit as usize, 11);
+        limiter.update(&mut state, Duration::from_millis(200), false);
+        assert_eq!(state.limit as usize, 5);
+        limiter.update(&mut state, Duration::from_millis(10), true);
+        assert_eq!(state.limit as usize, 2);
+    }
+
+    #[test]
+    fn test_gradient_shrinks_when_latency_rises() {
+        let limiter = limiter(ConcurrencyAlgorithm::Gradient { smoothing: 1.0 }, 64);
+        let mut state = limiter.state.lock().unwrap();
+        state.in_flight = 64;
+        limiter.update(&mut state, Duration::from_millis(10), false);
+        assert_eq!(state.limit as usize, 72);
+        limiter.update(&mut state, Duration::from_millis(40), false);
+        assert!(state.limit < 72.0 / 2.0 + 10.0);
+    }
+
+    #[test]
+    fn test_limit_only_grows_when_busy() {
+        let limiter = limiter(ConcurrencyAlgorithm::Gradient { smoothing: 1.0 }, 16);
+        let mut state = limiter.state.lock().unwrap();
+        state.in_flight = 1;
+        for _ in 0..100 {
+            limiter.update(&mut state, Duration::from_millis(10), false);
+        }
+        assert_eq!(state.limit as usize, 16);
+        state.in_flight = 16;
+        limiter.update(&mut state, Duration::from_millis(10), false);
+        assert_eq!(state.limit as usize, 20);
+    }
+
+    #[test]
+    fn test_only_overload_is_dropped() {
+        assert!(!dropped(Ok(StatusCode::OK)));
+        assert!(!dropped(Ok(StatusCode::INTERNAL_SERVER_ERROR)));
+        assert!(!dropped(Ok(StatusCode::BAD_GATEWAY)));
+        assert!(dropped(Ok(StatusCode::SERVICE_UNAVAILABLE)));
+        assert!(dropped(Ok(StatusCode::TOO_MANY_REQUESTS)));
+        assert!(dropped(Err(&UpstreamError::ConnectTimeout)));
+        assert!(dropped(Err(&UpstreamError::PerTryTimeout)));
+        assert!(!dropped(Err(&UpstreamError::IdleBodyTimeout)));
+        assert!(!dropped(Err(&UpstreamError::Busy)));
+    }
+
+    #[test]
+    fn test_samples_keep_the_slot() {
+        let limiter = limiter(ConcurrencyAlgorithm::default(), 2);
+        let permit = limiter.try_acquire().unwrap();
+        limiter.record(Duration::from_millis(10), false);
+        assert_eq!(limiter.in_flight(), 1);
+        drop(permit);
+        assert_eq!(limiter.in_flight(), 0);
+    }
+}
@@ -1,57 +1,114 @@
 use {
+    crate::{
+        algorithm::{algorithm::Strategy, round_robin::RoundRobin},
+        format::Format,
+        include,
+        interpolate::interpolate,
+        schema,
+        validate::{self, ConfigError, ConfigErrors},
+    },
     actix_web::http::{Error, Uri},
-    serde::{Deserialize, Deserializer},
-    std::{collections::HashMap, fs::read_to_string, str::FromStr},
+    schemars::JsonSchema,
+    serde::{de, Deserialize, Deserializer},
+    serde_json::Value,
+    std::{
+        collections::{BTreeMap, HashMap},
+        convert::TryFrom,
+        env::var,
+        fs::read_to_string,
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        path::{Path, PathBuf},
+        str::FromStr,
+        time::Duration,
+    },
     strum_macros::{Display, EnumString},
 };
 use core::fmt;
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 #[serde(default)]
 pub struct Config {
-    pub ip: String,
-    pub port: String,
-    pub strategy: String,
+    #[serde(deserialize_with = "deserialize_ip")]
+    #[schemars(with = "IpAddr")]
+    pub ip: IpAddr,
+    #[serde(deserialize_with = "deserialize_port")]
+    #[schemars(with = "schema::Port")]
+    pub port: u16,
+    #[serde(deserialize_with = "deserialize_strategy")]
+    #[schemars(with = "Strategy")]
+    pub strategy: Strategy,
     #[serde(deserialize_with = "PersistenceType::deserialize_persistence_type")]
     pub persistence_type: PersistenceType,
     pub replicas: usize,
     pub backends: HashMap<String, BackendConfig>,
     pub mappings: HashMap<String, StrategyMapping>,
     pub health_check: HealthCheckConfig,
+    pub retry: RetryConfig,
+    pub routes: HashMap<String, RouteConfig>,
+    pub admin: Option<AdminConfig>,
+    pub timeouts: TimeoutConfig,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub pool: PoolConfig,
+    pub events: EventsConfig,
+    /// Files, or globs of files, whose backends, routes and mappings are merged into this config.
+    /// Relative paths are resolved against the config's directory.
+    pub include: Vec<String>,
+    /// The files which were merged in, in the order they were merged.
+    #[serde(skip)]
+    pub included: Vec<PathBuf>,
 }
 
 impl Config {
-    pub fn strategy(&self) -> &String {
+    pub fn strategy(&self) -> &Strategy {
         &self.strategy
     }
 
-    pub fn strategy_mut(&mut self) -> &mut String {
+    pub fn strategy_mut(&mut self) -> &mut Strategy {
         &mut self.strategy
     }
+
+    /// The address requests are accepted on.
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
+
+    pub fn set_addr(&mut self, addr: SocketAddr) {
+        self.ip = addr.ip();
+        self.port = addr.port();
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
-            ip: String::from("127.0.0.1"),
-            port: String::from("8080"),
-            strategy: String::from("RoundRobin"),
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 8080,
+            strategy: Strategy::RoundRobin(RoundRobin::default()),
             persistence_type: PersistenceType::default(),
             replicas: 0,
             backends: HashMap::new(),
             mappings: HashMap::new(),
             health_check: HealthCheckConfig::default(),
+            retry: RetryConfig::default(),
+            routes: HashMap::new(),
+            admin: None,
+            timeouts: TimeoutConfig::default(),
+            rate_limit: None,
+            pool: PoolConfig::default(),
+            events: EventsConfig::default(),
+            include: Vec::new(),
+            included: Vec::new(),
         }
     }
 }
 
 /// Represents a persistent connection between the client and a specific server.
-/// ****************************************************************************
+///
 /// Applications developed without load-balancing in mind may break when deployed in a load-balanced
 /// architecture because they depend on session data that is stored only on the original server on which the session was initiated.
-/// ****************************************************************************
+///
 /// By default, if no persistence type is specified, requests will be routed based on cookies.
-#[derive(EnumString, Deserialize, Debug, Copy, Clone, Display)]
+#[derive(EnumString, Deserialize, JsonSchema, Debug, Copy, Clone, Display, PartialEq)]
 pub enum PersistenceType {
     /// Requests with the same cookie will be routed to the same server.
     Cookie,
@@ -72,23 +129,28 @@ impl PersistenceType {
     where
         D: Deserializer<'de>,
     {
-        let s: &str = Deserialize::deserialize(field)?;
-        Ok(Self::from_str(s).unwrap())
+        let s: String = Deserialize::deserialize(field)?;
+        Self::from_str(s.as_str())
+            .map_err(|_| de::Error::unknown_variant(s.as_str(), &["Cookie", "IP", "None"]))
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
 pub struct StrategyMapping {
     pub path: String,
 }
 
-#[derive(Debug, Copy, Clone, Deserialize)]
+#[derive(Debug, Copy, Clone, Deserialize, JsonSchema, PartialEq)]
 #[allow(dead_code)]
 pub enum ServerStatus {
     Alive,
     Busy,
     Dead,
     Throttled,
+    /// Takes no new sessions, but keeps serving existing ones.
+    Draining,
+    /// Takes no requests at all.
+    Maintenance,
 }
 
 impl Default for ServerStatus {
@@ -97,15 +159,35 @@ impl Default for ServerStatus {
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
 #[serde(default)]
 pub struct BackendConfig {
+    /// Host name or IP address of the backend.
     pub ip: String,
-    pub port: String,
+    #[serde(deserialize_with = "deserialize_port")]
+    #[schemars(with = "schema::Port")]
+    pub port: u16,
     pub path: String,
-    pub scheme: String,
+    pub scheme: Scheme,
     pub status: ServerStatus,
     pub num_connections: u64,
+    pub max_connections: Option<usize>,
+    pub timeouts: TimeoutConfig,
+    /// Share of the traffic sent to this backend by the weighted strategies, relative to the others.
+    pub weight: u32,
+    pub agent: Option<AgentConfig>,
+    /// How the backend's addresses are looked up in DNS. Only backends with this are looked up,
+    /// e.g. with `dns = {}` for the addresses of the host name in `ip`.
+    pub dns: Option<DnsConfig>,
+    /// File listing the backend's members, which replace it.
+    pub file_sd: Option<FileSdConfig>,
+    /// Service registry listing the backend's instances, which replace it.
+    pub registry: Option<RegistryConfig>,
+    /// Zone, e.g. availability zone, the backend runs in.
+    pub zone: Option<String>,
+    pub tags: Vec<String>,
+    /// Metadata about the backend, such as the labels or metadata it was discovered with.
+    pub meta: BTreeMap<String, String>,
 }
 
 impl BackendConfig {
@@ -114,12 +196,33 @@ impl BackendConfig {
     pub fn uri(&self) -> Result<Uri, Error> {
         Uri::builder()
             .scheme(self.scheme().as_str())
-            .authority(format!("{}:{}", self.ip(), self.port()).as_str())
+            .authority(self.authority().as_str())
             .path_and_query(self.path().as_str())
             .build()
             .map_err(Error::from)
     }
 
+    /// Whether the backend stands for the addresses its name resolves to in DNS, rather than
+    /// being an address itself.
+    pub fn resolves(&self) -> bool {
+        self.dns.is_some() && self.file_sd.is_none() && self.registry.is_none()
+    }
+
+    /// Whether the backend stands for backends which are discovered, through DNS, a file or a
+    /// service registry.
+    pub fn is_discovered(&self) -> bool {
+        self.resolves() || self.file_sd.is_some() || self.registry.is_some()
+    }
+
+    /// The `ip:port` pair which uniquely identifies this backend. IPv6 addresses are bracketed.
+    #[inline]
+    pub fn authority(&self) -> String {
+        match self.ip.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, self.port()),
+            _ => format!("{}:{}", self.ip(), self.port()),
+        }
+    }
+
     #[inline]
     #[allow(dead_code)]
     pub fn status(&self) -> &ServerStatus {
@@ -146,25 +249,25 @@ impl BackendConfig {
 
     #[inline]
     #[allow(dead_code)]
-    pub fn port(&self) -> &String {
+    pub fn port(&self) -> &u16 {
         &self.port
     }
 
     #[inline]
     #[allow(dead_code)]
-    pub fn port_mut(&mut self) -> &mut String {
+    pub fn port_mut(&mut self) -> &mut u16 {
         &mut self.port
     }
 
     #[inline]
     #[allow(dead_code)]
-    pub fn scheme(&self) -> &String {
+    pub fn scheme(&self) -> &Scheme {
         &self.scheme
     }
 
     #[inline]
     #[allow(dead_code)]
-    pub fn scheme_mut(&mut self) -> &mut String {
+    pub fn scheme_mut(&mut self) -> &mut Scheme {
         &mut self.scheme
     }
 
@@ -197,21 +300,200 @@ impl Default for BackendConfig {
     fn default() -> Self {
         Self {
             ip: String::from("127.0.0.1"),
-            port: String::from("8080"),
+            port: 8080,
             path: String::from("/backend"),
-            scheme: String::from("http"),
+            scheme: Scheme::default(),
             status: ServerStatus::default(),
             num_connections: 0,
+            max_connections: None,
+            timeouts: TimeoutConfig::default(),
+            weight: 1,
+            agent: None,
+            dns: None,
+            file_sd: None,
+            registry: None,
+            zone: None,
+            tags: Vec::new(),
+            meta: BTreeMap::new(),
+        }
+    }
+}
+
+/// How requests are sent to a backend.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    #[default]
+    Http,
+    Https,
+}
+
+impl Scheme {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+        }
+    }
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Looks up a backend's addresses in DNS, making a backend of each address found. They're looked
+/// up again as the answers expire, and backends are added and removed as the addresses change.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct DnsConfig {
+    /// SRV name such as `_http._tcp.api.internal`, whose targets and ports are used instead of
+    /// `ip` and `port`. Only the targets with the lowest priority are used, weighted by their
+    /// weights.
+    pub srv: Option<String>,
+    /// Time between lookups. Defaults to the answers' TTL.
+    #[serde(deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<schema::Duration>")]
+    pub interval: Option<Duration>,
+    /// Least time between lookups, however short the TTL. Failed lookups are retried after this.
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[schemars(with = "schema::Duration")]
+    pub min_interval: Duration,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            srv: None,
+            interval: None,
+            min_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Reads a backend's members from a file in the style of Prometheus' `file_sd`, a list of groups
+/// of `ip:port` targets with labels. The file is watched, and members are added and removed as it
+/// changes.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct FileSdConfig {
+    /// Path of the file, relative to the config's directory. Its format goes by its extension.
+    pub path: String,
+    /// How long removed members keep serving their sessions, taking no new ones, before they're
+    /// dropped.
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[schemars(with = "schema::Duration")]
+    pub drain: Duration,
+}
+
+impl Default for FileSdConfig {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            drain: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Polls a service registry with a Consul-style `/v1/health/service/<name>` endpoint for a
+/// backend's instances. Instances are added and removed as the registry's answer changes.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RegistryConfig {
+    /// Address of the registry, e.g. `http://127.0.0.1:8500`.
+    pub url: String,
+    /// Name of the service. Defaults to the backend's name.
+    pub service: Option<String>,
+    /// Only instances with this tag are used.
+    pub tag: Option<String>,
+    pub datacenter: Option<String>,
+    /// Sent as the `X-Consul-Token` header.
+    pub token: Option<String>,
+    /// Key of the service's, or else the node's, metadata which holds an instance's zone.
+    pub zone_key: String,
+    /// Time between polls, and before retrying one which failed.
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[schemars(with = "schema::Duration")]
+    pub interval: Duration,
+    /// How long the registry may hold a blocking query until the service changes. Without it,
+    /// the registry is polled every `interval`.
+    #[serde(deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<schema::Duration>")]
+    pub wait: Option<Duration>,
+    /// How long a query may take, on top of `wait`.
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[schemars(with = "schema::Duration")]
+    pub timeout: Duration,
+}
+
+impl Default for RegistryConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            service: None,
+            tag: None,
+            datacenter: None,
+            token: None,
+            zone_key: String::from("zone"),
+            interval: Duration::from_secs(10),
+            wait: None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Periodically asks an agent on the backend for its weight or state, like HAProxy's agent-check.
+/// The agent answers with a line such as `75%`, `drain`, `maint` or `up`.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct AgentConfig {
+    /// Port of the agent. Defaults to the backend's own port.
+    #[serde(deserialize_with = "deserialize_optional_port")]
+    #[schemars(with = "Option<schema::Port>")]
+    pub port: Option<u16>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[schemars(with = "schema::Duration")]
+    pub interval: Duration,
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[schemars(with = "schema::Duration")]
+    pub timeout: Duration,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            port: None,
+            interval: Duration::from_secs(2),
+            timeout: Duration::from_secs(1),
         }
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default)]
 pub struct HealthCheckConfig {
-    pub timeout: u64,
-    pub interval: u64,
+    /// Time a single probe may take, e.g. `"2s"`. Whole numbers are seconds.
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[schemars(with = "schema::Duration")]
+    pub timeout: Duration,
+    /// Time between probes, e.g. `"500ms"`. Whole numbers are seconds.
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[schemars(with = "schema::Duration")]
+    pub interval: Duration,
     pub healthy_threshold: usize,
     pub unhealthy_threshold: usize,
+    /// Probes are scheduled up to this percentage of the interval earlier or later, so that
+    /// backends aren't all probed at the same instant.
+    pub jitter_percent: f64,
+    /// Interval used while a backend is between healthy and unhealthy.
+    #[serde(deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<schema::Duration>")]
+    pub transition_interval: Option<Duration>,
+    /// Number of recent probe results kept for every backend.
+    pub history_size: usize,
+    pub flap: Option<FlapConfig>,
+    pub probe: ProbeConfig,
 }
 
 impl fmt::Display for HealthCheckConfig {
@@ -223,30 +505,695 @@ impl fmt::Display for HealthCheckConfig {
 impl Default for HealthCheckConfig {
     fn default() -> Self {
         Self {
-            timeout: 10,
-            interval: 5,
+            timeout: Duration::from_secs(10),
+            interval: Duration::from_secs(5),
             healthy_threshold: 5,
             unhealthy_threshold: 5,
+            jitter_percent: 10.0,
+            transition_interval: None,
+            history_size: 20,
+            flap: None,
+            probe: ProbeConfig::default(),
+        }
+    }
+}
+
+/// How a backend's health is checked.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeConfig {
+    /// The backend is healthy if a TCP connection can be established.
+    #[default]
+    Connect,
+    /// The backend is healthy if a scripted conversation over TCP succeeds.
+    Tcp(TcpCheckConfig),
+    /// The backend is healthy if `grpc.health.v1.Health/Check` reports it as `SERVING`.
+    Grpc(GrpcCheckConfig),
+    /// The backend is healthy if a local command exits with status 0.
+    Command(CommandCheckConfig),
+}
+
+/// Asks the backend for its health using the gRPC health checking protocol, over HTTP/2 without TLS.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct GrpcCheckConfig {
+    /// The service to ask about. The empty name asks about the server as a whole.
+    pub service: String,
+}
+
+/// Runs a local executable with `LOBLAW_BACKEND_IP` and `LOBLAW_BACKEND_PORT` set to the backend's
+/// address. Its output is kept in the backend's probe history.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct CommandCheckConfig {
+    pub path: String,
+    pub args: Vec<String>,
+    /// Time after which the command is killed. Defaults to the health check timeout.
+    #[serde(deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<schema::Duration>")]
+    pub timeout: Option<Duration>,
+}
+
+/// A sequence of steps run against the backend, like HAProxy's `tcp-check`.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct TcpCheckConfig {
+    pub steps: Vec<TcpCheckStep>,
+    /// Time an `expect` step waits for its pattern.
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[schemars(with = "schema::Duration")]
+    pub expect_timeout: Duration,
+}
+
+impl Default for TcpCheckConfig {
+    fn default() -> Self {
+        Self {
+            steps: Vec::new(),
+            expect_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum TcpCheckStep {
+    /// Opens a new connection to the backend, optionally over TLS.
+    /// Scripts which don't start with a `connect` step connect without TLS first.
+    Connect {
+        #[serde(default)]
+        tls: bool,
+        /// Skips verifying the backend's certificate.
+        #[serde(default)]
+        insecure: bool,
+        /// Name used to verify the certificate. Defaults to the backend's ip.
+        server_name: Option<String>,
+    },
+    Send(TcpCheckPayload),
+    Expect(TcpCheckPattern),
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TcpCheckPayload {
+    Text(String),
+    /// Hex encoded bytes, e.g. `"2a310d0a"`. Whitespace is ignored.
+    Hex(String),
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TcpCheckPattern {
+    String(String),
+    Regex(String),
+    /// Hex encoded bytes.
+    Binary(String),
+}
+
+/// Where backend health changes are reported.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct EventsConfig {
+    /// Whether events are written to the log.
+    pub log: bool,
+    /// Number of recent events kept for the admin `/events` endpoint.
+    pub history_size: usize,
+    pub webhook: Option<WebhookConfig>,
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self {
+            log: true,
+            history_size: 100,
+            webhook: None,
+        }
+    }
+}
+
+/// POSTs every event as JSON to `url`.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Additional attempts after a failed delivery.
+    pub retries: usize,
+    /// Delay before the first retry, doubling with every further retry.
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[schemars(with = "schema::Duration")]
+    pub backoff: Duration,
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[schemars(with = "schema::Duration")]
+    pub timeout: Duration,
+    /// Events waiting for delivery beyond this number are dropped.
+    pub queue_size: usize,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            retries: 3,
+            backoff: Duration::from_millis(500),
+            timeout: Duration::from_secs(2),
+            queue_size: 1000,
+        }
+    }
+}
+
+/// Holds a backend out of rotation when its health changes more than `max_changes` times within
+/// `window`.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct FlapConfig {
+    pub max_changes: usize,
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[schemars(with = "schema::Duration")]
+    pub window: Duration,
+    /// Time a flapping backend is held out of rotation.
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[schemars(with = "schema::Duration")]
+    pub hold: Duration,
+}
+
+impl Default for FlapConfig {
+    fn default() -> Self {
+        Self {
+            max_changes: 4,
+            window: Duration::from_secs(60),
+            hold: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Controls how failed upstream attempts are retried against a different backend.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Maximum number of additional attempts after the first one fails.
+    pub attempts: usize,
+    /// Upstream status codes that count as a failed attempt.
+    pub on_status: Vec<u16>,
+    /// Time a single attempt may take before it is abandoned.
+    #[serde(deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<schema::Duration>")]
+    pub per_try_timeout: Option<Duration>,
+    /// Whether non-idempotent requests (e.g. POST) may be retried.
+    pub allow_non_idempotent: bool,
+    /// Retries in flight may not exceed this percentage of active requests.
+    pub budget_percent: f64,
+    /// Retries in flight that are always allowed, regardless of the budget.
+    pub min_concurrent_retries: usize,
+    /// Whether a request answered with a 429 or 503 and a `Retry-After` header is retried on
+    /// another backend, even if the status isn't listed in `on_status`.
+    pub on_throttled: bool,
+}
+
+impl fmt::Display for RetryConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:#?}", self)
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 0,
+            on_status: vec![502, 503, 504],
+            per_try_timeout: None,
+            allow_non_idempotent: false,
+            budget_percent: 20.0,
+            min_concurrent_retries: 3,
+            on_throttled: true,
         }
     }
 }
 
+/// Settings which apply to requests whose path starts with `path`.
+/// When several routes match, the one with the longest path wins.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct RouteConfig {
+    pub path: String,
+    pub hedge: Option<HedgeConfig>,
+    pub timeouts: TimeoutConfig,
+    pub rate_limit: Option<RateLimitConfig>,
+}
+
+/// Sends a second attempt to another backend when the first one is slow to respond.
+/// Only idempotent requests are hedged.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct HedgeConfig {
+    /// Time to wait for the first attempt before hedging.
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[schemars(with = "schema::Duration")]
+    pub delay: Duration,
+    /// When set, hedge once the first attempt exceeds this percentile of the route's observed
+    /// latency instead. `delay` is used until enough latencies have been observed.
+    pub percentile: Option<f64>,
+    /// Hedges in flight may not exceed this percentage of active requests on the route.
+    pub budget_percent: f64,
+    /// Hedges in flight that are always allowed, regardless of the budget.
+    pub min_concurrent_hedges: usize,
+}
+
+impl Default for HedgeConfig {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_millis(100),
+            percentile: None,
+            budget_percent: 10.0,
+            min_concurrent_hedges: 1,
+        }
+    }
+}
+
+/// Limits on how long each phase of an upstream request may take.
+/// Timeouts set on a route take precedence over those set on a backend, which in turn take
+/// precedence over the global ones.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    /// Time allowed for establishing a connection to the backend.
+    #[serde(deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<schema::Duration>")]
+    pub connect: Option<Duration>,
+    /// Time allowed between sending the request and receiving the response headers.
+    #[serde(deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<schema::Duration>")]
+    pub response_header: Option<Duration>,
+    /// Time allowed between two chunks of the response body.
+    #[serde(deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<schema::Duration>")]
+    pub idle_body: Option<Duration>,
+    /// Time allowed for the whole request, including retries and hedges.
+    #[serde(deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<schema::Duration>")]
+    pub total: Option<Duration>,
+}
+
+impl TimeoutConfig {
+    /// Fills the timeouts which aren't set with those of `fallback`.
+    pub fn or(&self, fallback: &TimeoutConfig) -> TimeoutConfig {
+        TimeoutConfig {
+            connect: self.connect.or(fallback.connect),
+            response_header: self.response_header.or(fallback.response_header),
+            idle_body: self.idle_body.or(fallback.idle_body),
+            total: self.total.or(fallback.total),
+        }
+    }
+}
+
+/// Limits how many requests each client may make, using a token bucket per client.
+/// Each request takes a token, and tokens are refilled at `rate` per second up to `burst`.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Tokens added to each bucket per second.
+    pub rate: f64,
+    /// Maximum number of tokens a bucket holds.
+    pub burst: u64,
+    /// What identifies a client.
+    pub key: RateLimitKey,
+    /// Time after which a client's unused bucket is forgotten.
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[schemars(with = "schema::Duration")]
+    pub idle_timeout: Duration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            rate: 10.0,
+            burst: 20,
+            key: RateLimitKey::default(),
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Identifies the client a request is counted against.
+/// Requests lacking the configured header or cookie are counted against their IP address.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitKey {
+    /// The IP address of the client.
+    #[default]
+    Ip,
+    /// The value of the given request header, e.g. an API key.
+    Header(String),
+    /// The value of the given cookie.
+    Cookie(String),
+}
+
+/// Settings for the pool of backends as a whole.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PoolConfig {
+    pub concurrency: Option<ConcurrencyConfig>,
+    pub queue: QueueConfig,
+    /// Longest time a backend is avoided because of its `Retry-After` header.
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[schemars(with = "schema::Duration")]
+    pub max_throttle: Duration,
+    /// Response header in which backends report their load, between 0 and 1.
+    pub load_header: String,
+    /// Percentage of healthy backends below which health is ignored and requests are balanced
+    /// across every backend, so that the few survivors aren't overwhelmed.
+    pub panic_threshold: Option<f64>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: None,
+            queue: QueueConfig::default(),
+            max_throttle: Duration::from_secs(300),
+            load_header: String::from("X-Backend-Load"),
+            panic_threshold: None,
+        }
+    }
+}
+
+/// Holds requests while every backend is at its `max_connections`.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct QueueConfig {
+    /// Maximum number of waiting requests. Requests beyond it are rejected right away.
+    pub max_size: usize,
+    /// Time a request may wait for a backend before being rejected.
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[schemars(with = "schema::Duration")]
+    pub max_wait: Duration,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 100,
+            max_wait: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Limits the number of requests in flight to the pool, adapting the limit to the observed
+/// latency so that backends aren't pushed into overload.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ConcurrencyConfig {
+    pub algorithm: ConcurrencyAlgorithm,
+    pub initial_limit: usize,
+    pub min_limit: usize,
+    pub max_limit: usize,
+    /// Time a request may wait for the limit to allow it before being rejected.
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[schemars(with = "schema::Duration")]
+    pub queue_timeout: Duration,
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: ConcurrencyAlgorithm::default(),
+            initial_limit: 20,
+            min_limit: 1,
+            max_limit: 1000,
+            queue_timeout: Duration::from_millis(50),
+        }
+    }
+}
+
+/// How the concurrency limit reacts to observed latency.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConcurrencyAlgorithm {
+    /// Additive increase, multiplicative decrease: the limit grows by one while latency stays
+    /// below `latency_threshold` and is multiplied by `backoff` when it doesn't or a request fails.
+    Aimd {
+        #[serde(deserialize_with = "deserialize_duration")]
+        #[schemars(with = "schema::Duration")]
+        latency_threshold: Duration,
+        backoff: f64,
+    },
+    /// Scales the limit by the ratio between the lowest latency seen and the current latency,
+    /// leaving `sqrt(limit)` requests of headroom.
+    Gradient {
+        /// Weight given to each new limit, between 0 and 1.
+        smoothing: f64,
+    },
+}
+
+impl Default for ConcurrencyAlgorithm {
+    fn default() -> Self {
+        ConcurrencyAlgorithm::Gradient { smoothing: 0.2 }
+    }
+}
+
+/// Address of the administrative endpoint that exposes metrics.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct AdminConfig {
+    #[serde(deserialize_with = "deserialize_ip")]
+    #[schemars(with = "IpAddr")]
+    pub ip: IpAddr,
+    #[serde(deserialize_with = "deserialize_port")]
+    #[schemars(with = "schema::Port")]
+    pub port: u16,
+}
+
+impl AdminConfig {
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
+}
+
+struct PortVisitor;
+
+impl<'de> de::Visitor<'de> for PortVisitor {
+    type Value = u16;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a port number")
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<u16, E> {
+        u16::try_from(v).map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &self))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<u16, E> {
+        u16::try_from(v).map_err(|_| E::invalid_value(de::Unexpected::Unsigned(v), &self))
+    }
+
+    /// Older configs quote their ports.
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<u16, E> {
+        v.parse()
+            .map_err(|_| E::invalid_value(de::Unexpected::Str(v), &self))
+    }
+}
+
+/// Deserializes a value which `validate::check_values` checks before the config is deserialized.
+/// An invalid value becomes `fallback` instead of failing, so that the problem is reported along
+/// with every other one.
+fn checked<'de, D, T, F>(deserializer: D, parse: F, fallback: T) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    F: FnOnce(Value) -> Result<T, serde_json::Error>,
+{
+    let value = Value::deserialize(deserializer)?;
+    Ok(parse(value).unwrap_or(fallback))
+}
+
+fn deserialize_ip<'de, D: Deserializer<'de>>(deserializer: D) -> Result<IpAddr, D::Error> {
+    checked(
+        deserializer,
+        IpAddr::deserialize,
+        IpAddr::V4(Ipv4Addr::LOCALHOST),
+    )
+}
+
+fn deserialize_port<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    checked(deserializer, |value| value.deserialize_any(PortVisitor), 0)
+}
+
+fn deserialize_strategy<'de, D>(deserializer: D) -> Result<Strategy, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    checked(
+        deserializer,
+        Strategy::deserialize,
+        Strategy::RoundRobin(RoundRobin::default()),
+    )
+}
+
+fn deserialize_optional_port<'de, D>(deserializer: D) -> Result<Option<u16>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_port(deserializer).map(Some)
+}
+
+struct DurationVisitor;
+
+impl<'de> de::Visitor<'de> for DurationVisitor {
+    type Value = Duration;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a duration such as \"250ms\" or \"5s\", or a whole number of seconds")
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Duration, E> {
+        u64::try_from(v)
+            .map(Duration::from_secs)
+            .map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &self))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Duration, E> {
+        Ok(Duration::from_secs(v))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Duration, E> {
+        humantime::parse_duration(v)
+            .map_err(|e| E::custom(format!("invalid duration '{}': {}", v, e)))
+    }
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    deserializer.deserialize_any(DurationVisitor)
+}
+
+fn deserialize_optional_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_duration(deserializer).map(Some)
+}
+
 impl Config {
-    pub fn parse() -> Result<Self, Box<dyn std::error::Error>> {
-        let config: Config = {
-            let contents = read_to_string("config.toml")?;
-            toml::from_str(contents.as_str())?
-        };
+    /// Parses the config at `path`, written in `format`.
+    pub fn parse(path: &Path, format: Format) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = read_to_string(path)
+            .map_err(|e| format!("Could not read '{}': {}", path.display(), e))?;
+        Self::from_file(&contents, path, format).map_err(|errors| {
+            ConfigErrors {
+                path: path.to_path_buf(),
+                errors,
+            }
+            .into()
+        })
+    }
+
+    /// Interpolates, deserializes and validates the `contents` of the config at `path`, merging in
+    /// the files it includes.
+    fn from_file(contents: &str, path: &Path, format: Format) -> Result<Self, Vec<ConfigError>> {
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let source = interpolate(contents, format, dir, &|name| var(name).ok())?;
+        let mut errors = Self::check_values(&source, format);
+        let (config, deserialize_errors) = format.deserialize::<Self>(&source);
+        errors.extend(deserialize_errors);
+        if let Some(mut config) = config {
+            let (included, origins) = include::merge(&mut config, path);
+            errors.extend(included);
+            for mut error in validate::validate(&config) {
+                origins.locate(&mut error);
+                errors.push(error);
+            }
+            if errors.is_empty() {
+                return Ok(config);
+            }
+        }
+        format.locate(&mut errors, &source);
+        validate::sort(&mut errors);
+        Err(errors)
+    }
 
+    /// Deserializes and validates `source`, reporting every problem found rather than just the
+    /// first one. Files it includes aren't merged in.
+    pub fn from_source(source: &str, format: Format) -> Result<Self, Vec<ConfigError>> {
+        let mut errors = Self::check_values(source, format);
+        let (config, deserialize_errors) = format.deserialize::<Self>(source);
+        errors.extend(deserialize_errors);
+        if let Some(config) = config {
+            errors.extend(validate::validate(&config));
+            if errors.is_empty() {
+                return Ok(config);
+            }
+        }
+        format.locate(&mut errors, source);
+        validate::sort(&mut errors);
+        Err(errors)
+    }
+
+    /// Checks the values which don't fail to deserialize when they're invalid. A source which
+    /// can't be parsed at all is reported by deserializing it.
+    fn check_values(source: &str, format: Format) -> Vec<ConfigError> {
+        match format.deserialize::<Value>(source) {
+            (Some(value), _) => validate::check_values(&value),
+            (None, _) => Vec::new(),
+        }
+    }
+
+    pub fn from_toml(source: &str) -> Result<Self, Vec<ConfigError>> {
+        Self::from_source(source, Format::Toml)
+    }
+
+    /// Prints the resolved settings.
+    pub fn print(&self) {
+        let config = self;
         println!("The following settings were provided:");
         println!("- ip: {}.", config.ip);
         println!("- port: {}.", config.port);
         println!("- strategy: {}.", config.strategy);
         println!("- sticky_session: {}.", config.persistence_type);
         println!("- # replicas: {}.", config.replicas);
+        println!("- include: {:?}.", config.include);
+        println!("- included files: {:#?}.", config.included);
         println!("- backends: {:#?}.", config.backends);
         println!("- mappings: {:#?}.", config.mappings);
         println!("- health check: {}.", config.health_check);
-        Ok(config)
+        println!("- retry: {}.", config.retry);
+        println!("- routes: {:#?}.", config.routes);
+        println!("- admin: {:#?}.", config.admin);
+        println!("- timeouts: {:#?}.", config.timeouts);
+        println!("- rate limit: {:#?}.", config.rate_limit);
+        println!("- pool: {:#?}.", config.pool);
+        println!("- events: {:#?}.", config.events);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_timeout_precedence() {
+        let global = TimeoutConfig {
+            connect: Some(ms(1)),
+            response_header: Some(ms(1)),
+            idle_body: Some(ms(1)),
+            total: None,
+        };
+        let backend = TimeoutConfig {
+            connect: Some(ms(2)),
+            response_header: Some(ms(2)),
+            ..TimeoutConfig::default()
+        };
+        let route = TimeoutConfig {
+            connect: Some(ms(3)),
+            ..TimeoutConfig::default()
+        };
+        let timeouts = route.or(&backend.or(&global));
+        assert_eq!(
+            timeouts,
+            TimeoutConfig {
+                connect: Some(ms(3)),
+                response_header: Some(ms(2)),
+                idle_body: Some(ms(1)),
+                total: None,
+            }
+        );
     }
 }
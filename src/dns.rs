@@ -0,0 +1,531 @@
+use {
+    crate::{config::BackendConfig, dynamic::Reloader, metrics::Metrics},
+    async_trait::async_trait,
+    log::{error, warn},
+    std::{
+        collections::HashMap,
+        net::IpAddr,
+        sync::Arc,
+        time::{Duration, Instant},
+    },
+    tokio::time::delay_for,
+    trust_dns_resolver::{lookup::SrvLookup, lookup_ip::LookupIp, TokioAsyncResolver},
+};
+
+/// How often backends are checked for lookups which are due.
+const TICK: Duration = Duration::from_secs(1);
+
+/// The records found by a lookup, and how long they may be cached.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Answer<T> {
+    pub records: Vec<T>,
+    pub ttl: Duration,
+}
+
+/// An SRV record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Srv {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// Answers DNS queries.
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    /// The IPv4 and IPv6 addresses of `host`.
+    async fn lookup_ip(&self, host: &str) -> Result<Answer<IpAddr>, String>;
+    /// The SRV records of `name`.
+    async fn lookup_srv(&self, name: &str) -> Result<Answer<Srv>, String>;
+}
+
+/// Resolves with the system's DNS configuration, i.e. `/etc/resolv.conf`.
+pub struct SystemResolver(TokioAsyncResolver);
+
+impl SystemResolver {
+    pub async fn new() -> Result<Self, String> {
+        TokioAsyncResolver::tokio_from_system_conf()
+            .await
+            .map(SystemResolver)
+            .map_err(|e| e.to_string())
+    }
+}
+
+fn ttl(valid_until: Instant) -> Duration {
+    valid_until.saturating_duration_since(Instant::now())
+}
+
+/// The addresses found by a lookup of a host name.
+fn ip_answer(lookup: &LookupIp) -> Answer<IpAddr> {
+    Answer {
+        records: lookup.iter().collect(),
+        ttl: ttl(lookup.valid_until()),
+    }
+}
+
+/// The SRV records found by a lookup.
+fn srv_answer(lookup: &SrvLookup) -> Answer<Srv> {
+    let records = lookup
+        .iter()
+        .map(|srv| Srv {
+            priority: srv.priority(),
+            weight: srv.weight(),
+            port: srv.port(),
+            target: srv.target().to_string(),
+        })
+        .collect();
+    Answer {
+        records,
+        ttl: ttl(lookup.as_lookup().valid_until()),
+    }
+}
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn lookup_ip(&self, host: &str) -> Result<Answer<IpAddr>, String> {
+        let lookup = self.0.lookup_ip(host).await.map_err(|e| e.to_string())?;
+        Ok(ip_answer(&lookup))
+    }
+
+    async fn lookup_srv(&self, name: &str) -> Result<Answer<Srv>, String> {
+        let lookup = self.0.srv_lookup(name).await.map_err(|e| e.to_string())?;
+        Ok(srv_answer(&lookup))
+    }
+}
+
+/// Looks up the addresses of `backend`, returning a backend for each and how long they may be
+/// cached. Each gets the settings of `backend` but its own address, and SRV records' weights.
+/// SRV targets which can't be looked up are left out, unless none can.
+pub async fn resolve(
+    resolver: &dyn Resolver,
+    backend: &BackendConfig,
+) -> Result<(Vec<BackendConfig>, Duration), String> {
+    let address = |ip: IpAddr, port: u16, weight: u32| BackendConfig {
+        ip: ip.to_string(),
+        port,
+        weight,
+        dns: None,
+        ..backend.clone()
+    };
+    let mut backends = Vec::new();
+    let mut ttl;
+    match backend.dns.as_ref().and_then(|dns| dns.srv.as_ref()) {
+        Some(name) => {
+            let answer = resolver.lookup_srv(name).await?;
+            ttl = answer.ttl;
+            let priority = answer.records.iter().map(|srv| srv.priority).min();
+            // A target of "." means the service isn't available.
+            let targets = answer
+                .records
+                .into_iter()
+                .filter(|srv| Some(srv.priority) == priority && srv.target != ".");
+            let mut failure = None;
+            for srv in targets {
+                let ips = match resolver.lookup_ip(&srv.target).await {
+                    Ok(ips) => ips,
+                    Err(e) => {
+                        warn!("Could not look up SRV target '{}': {}", srv.target, e);
+                        failure = Some(e);
+                        continue;
+                    }
+                };
+                ttl = ttl.min(ips.ttl);
+                let weight = u32::from(srv.weight.max(1));
+                backends.extend(
+                    ips.records
+                        .into_iter()
+                        .map(|ip| address(ip, srv.port, weight)),
+                );
+            }
+            if let (true, Some(e)) = (backends.is_empty(), failure) {
+                return Err(e);
+            }
+        }
+        None => {
+            let answer = resolver.lookup_ip(&backend.ip).await?;
+            ttl = answer.ttl;
+            backends.extend(
+                answer
+                    .records
+                    .into_iter()
+                    .map(|ip| address(ip, backend.port, backend.weight)),
+            );
+        }
+    }
+    backends.sort_by_key(BackendConfig::authority);
+    backends.dedup_by_key(|backend| backend.authority());
+    Ok((backends, ttl))
+}
+
+/// When a backend is next looked up.
+#[derive(Debug)]
+struct Lookup {
+    /// The backend's config when it was last looked up. Once it changes, it's looked up again.
+    backend: BackendConfig,
+    due: Instant,
+}
+
+/// Keeps the addresses of the backends which are discovered through DNS up to date.
+#[derive(Debug, Default)]
+pub struct Discovery {
+    lookups: HashMap<String, Lookup>,
+}
+
+impl Discovery {
+    /// Looks up the backends which are due, and hands what's found to `reloader`. A backend whose
+    /// lookup fails keeps the addresses it had.
+    pub async fn poll(&mut self, resolver: &dyn Resolver, reloader: &Reloader, metrics: &Metrics) {
+        let declared = reloader.declared();
+        self.lookups.retain(|name, _| {
+            declared
+                .backends
+                .get(name)
+                .is_some_and(BackendConfig::resolves)
+        });
+        let mut backends = declared
+            .backends
+            .iter()
+            .filter(|(_, backend)| backend.resolves())
+            .collect::<Vec<_>>();
+        backends.sort_by_key(|(name, _)| name.as_str());
+        for (name, backend) in backends {
+            let now = Instant::now();
+            let due = self
+                .lookups
+                .get(name)
+                .is_none_or(|lookup| lookup.backend != *backend || lookup.due <= now);
+            if !due {
+                continue;
+            }
+            let dns = backend.dns.clone().unwrap_or_default();
+            let next = match resolve(resolver, backend).await {
+                Ok((addresses, ttl)) => {
+                    metrics.incr("dns.resolutions");
+                    reloader.discover(name, addresses);
+                    dns.interval.unwrap_or(ttl).max(dns.min_interval)
+                }
+                Err(e) => {
+                    metrics.incr("dns.failures");
+                    warn!(
+                        "Could not look up backend '{}', keeping its addresses: {}",
+                        name, e
+                    );
+                    dns.min_interval
+                }
+            };
+            self.lookups.insert(
+                name.clone(),
+                Lookup {
+                    backend: backend.clone(),
+                    due: now + next,
+                },
+            );
+        }
+    }
+}
+
+/// Keeps discovering backends for as long as the load balancer runs. The system resolver is only
+/// set up once a backend needs it.
+pub async fn run(
+    reloader: &Reloader,
+    metrics: Arc<Metrics>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut discovery = Discovery::default();
+    let mut resolver = None;
+    loop {
+        let needed = reloader
+            .declared()
+            .backends
+            .values()
+            .any(BackendConfig::resolves);
+        if needed && resolver.is_none() {
+            match SystemResolver::new().await {
+                Ok(system) => resolver = Some(system),
+                Err(e) => error!("Could not set up the DNS resolver: {}", e),
+            }
+        }
+        if let (true, Some(resolver)) = (needed, resolver.as_ref()) {
+            discovery.poll(resolver, reloader, &metrics).await;
+        }
+        delay_for(TICK).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            algorithm::algorithm::Algorithm, config::Config, dynamic::expand, events::Events,
+            format::Format, pool::Pool,
+        },
+        std::sync::{Mutex, RwLock},
+        trust_dns_resolver::{
+            lookup::Lookup,
+            proto::{
+                op::Query,
+                rr::{rdata::SRV, RData, Record, RecordType},
+            },
+            Name,
+        },
+    };
+
+    /// Answers from a table of names, which tests change between lookups.
+    #[derive(Default)]
+    struct StandIn {
+        ips: Mutex<HashMap<String, Vec<IpAddr>>>,
+        srvs: Mutex<HashMap<String, Vec<Srv>>>,
+    }
+
+    impl StandIn {
+        fn set(&self, host: &str, ips: &[&str]) {
+            let ips = ips.iter().map(|ip| ip.parse().unwrap()).collect();
+            self.ips.lock().unwrap().insert(host.to_string(), ips);
+        }
+    }
+
+    #[async_trait]
+    impl Resolver for StandIn {
+        async fn lookup_ip(&self, host: &str) -> Result<Answer<IpAddr>, String> {
+            match self.ips.lock().unwrap().get(host) {
+                Some(ips) => Ok(Answer {
+                    records: ips.clone(),
+                    ttl: Duration::from_secs(30),
+                }),
+                None => Err(format!("no record found for {}", host)),
+            }
+        }
+
+        async fn lookup_srv(&self, name: &str) -> Result<Answer<Srv>, String> {
+            match self.srvs.lock().unwrap().get(name) {
+                Some(srvs) => Ok(Answer {
+                    records: srvs.clone(),
+                    ttl: Duration::from_secs(10),
+                }),
+                None => Err(format!("no record found for {}", name)),
+            }
+        }
+    }
+
+    fn srv(priority: u16, weight: u16, port: u16, target: &str) -> Srv {
+        Srv {
+            priority,
+            weight,
+            port,
+            target: target.to_string(),
+        }
+    }
+
+    fn authorities(backends: &[BackendConfig]) -> Vec<(String, u32)> {
+        backends
+            .iter()
+            .map(|backend| (backend.authority(), backend.weight))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let resolver = StandIn::default();
+        resolver.set("api.internal", &["10.0.0.2", "10.0.0.1", "::1", "10.0.0.1"]);
+        resolver.set("a.internal.", &["10.0.1.1"]);
+        resolver.set("b.internal.", &["10.0.1.2"]);
+        resolver.srvs.lock().unwrap().insert(
+            String::from("_http._tcp.api.internal"),
+            vec![
+                srv(10, 0, 8081, "a.internal."),
+                srv(10, 3, 8082, "b.internal."),
+                srv(20, 1, 8083, "c.internal."),
+            ],
+        );
+
+        let config = Config::from_toml(
+            r#"
+[backends.host]
+ip = "api.internal"
+port = 80
+weight = 2
+dns = {}
+[backends.srv]
+dns = { srv = "_http._tcp.api.internal" }
+"#,
+        )
+        .unwrap();
+        let (backends, ttl) = resolve(&resolver, &config.backends["host"]).await.unwrap();
+        assert_eq!(
+            authorities(&backends),
+            vec![
+                (String::from("10.0.0.1:80"), 2),
+                (String::from("10.0.0.2:80"), 2),
+                (String::from("[::1]:80"), 2),
+            ]
+        );
+        assert_eq!(ttl, Duration::from_secs(30));
+        assert!(backends.iter().all(|backend| !backend.resolves()));
+
+        let (backends, ttl) = resolve(&resolver, &config.backends["srv"]).await.unwrap();
+        assert_eq!(
+            authorities(&backends),
+            vec![
+                (String::from("10.0.1.1:8081"), 1),
+                (String::from("10.0.1.2:8082"), 3),
+            ]
+        );
+        assert_eq!(ttl, Duration::from_secs(10));
+
+        resolver.ips.lock().unwrap().remove("a.internal.");
+        let (backends, _) = resolve(&resolver, &config.backends["srv"]).await.unwrap();
+        assert_eq!(
+            authorities(&backends),
+            vec![(String::from("10.0.1.2:8082"), 3)]
+        );
+        resolver.ips.lock().unwrap().remove("b.internal.");
+        assert!(resolve(&resolver, &config.backends["srv"]).await.is_err());
+    }
+
+    #[test]
+    fn test_only_backends_with_dns_resolve() {
+        let config = Config::from_toml(
+            r#"
+[backends.named]
+ip = "localhost"
+[backends.looked_up]
+ip = "localhost"
+dns = {}
+"#,
+        )
+        .unwrap();
+        assert!(!config.backends["named"].resolves());
+        assert!(!config.backends["named"].is_discovered());
+        assert!(config.backends["looked_up"].resolves());
+    }
+
+    fn lookup(rdata: Vec<RData>) -> Lookup {
+        let name = Name::from_ascii("api.internal.").unwrap();
+        let records = rdata
+            .into_iter()
+            .map(|rdata| Record::from_rdata(name.clone(), 60, rdata))
+            .collect();
+        Lookup::new_with_deadline(
+            Query::query(name, RecordType::A),
+            Arc::new(records),
+            Instant::now() + Duration::from_secs(60),
+        )
+    }
+
+    #[test]
+    fn test_answers() {
+        let answer = ip_answer(&LookupIp::from(lookup(vec![
+            RData::A("10.0.0.1".parse().unwrap()),
+            RData::CNAME(Name::from_ascii("other.internal.").unwrap()),
+            RData::AAAA("::1".parse().unwrap()),
+        ])));
+        assert_eq!(
+            answer.records,
+            vec![
+                "10.0.0.1".parse::<IpAddr>().unwrap(),
+                "::1".parse::<IpAddr>().unwrap()
+            ]
+        );
+        assert!(answer.ttl <= Duration::from_secs(60) && answer.ttl > Duration::from_secs(50));
+
+        let target = Name::from_ascii("a.internal.").unwrap();
+        let answer = srv_answer(&SrvLookup::from(lookup(vec![RData::SRV(SRV::new(
+            10, 3, 8081, target,
+        ))])));
+        assert_eq!(answer.records, vec![srv(10, 3, 8081, "a.internal.")]);
+    }
+
+    #[tokio::test]
+    async fn test_discovery_follows_dns() {
+        let source = r#"
+strategy = "RoundRobin"
+[backends.static]
+port = 1
+[backends.api]
+ip = "api.internal"
+port = 80
+dns = { interval = "0s", min_interval = "0s" }
+"#;
+        let declared = Config::from_toml(source).unwrap();
+        let config = expand(&declared, &HashMap::new());
+        let metrics = Arc::new(Metrics::default());
+        let (events, _) = Events::new(&config.events, metrics.clone());
+        let pool = Arc::new(Pool::new(&config, Arc::new(events), metrics.clone()));
+        let mut strategy = config.strategy.clone();
+        strategy.configure(&config);
+        let config = Arc::new(RwLock::new(config));
+        let reloader = Reloader::new(
+            std::env::temp_dir().join("loblaw-dns.toml"),
+            Format::Toml,
+            None,
+            declared,
+            config.clone(),
+            Arc::new(RwLock::new(strategy)),
+            pool.clone(),
+            metrics.clone(),
+        );
+        let names = || {
+            let mut names = config
+                .read()
+                .unwrap()
+                .backends
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        let authorities = || {
+            let mut authorities = pool
+                .backends()
+                .iter()
+                .map(|backend| backend.config.authority())
+                .collect::<Vec<_>>();
+            authorities.sort();
+            authorities
+        };
+        assert_eq!(names(), vec!["static"]);
+
+        let resolver = StandIn::default();
+        let mut discovery = Discovery::default();
+        resolver.set("api.internal", &["10.0.0.1", "10.0.0.2"]);
+        discovery.poll(&resolver, &reloader, &metrics).await;
+        assert_eq!(
+            names(),
+            vec!["api/10.0.0.1:80", "api/10.0.0.2:80", "static"]
+        );
+        assert_eq!(
+            authorities(),
+            vec!["10.0.0.1:80", "10.0.0.2:80", "127.0.0.1:1"]
+        );
+
+        resolver.set("api.internal", &["10.0.0.2", "10.0.0.3"]);
+        discovery.poll(&resolver, &reloader, &metrics).await;
+        assert_eq!(
+            authorities(),
+            vec!["10.0.0.2:80", "10.0.0.3:80", "127.0.0.1:1"]
+        );
+
+        resolver.ips.lock().unwrap().clear();
+        discovery.poll(&resolver, &reloader, &metrics).await;
+        assert_eq!(
+            authorities(),
+            vec!["10.0.0.2:80", "10.0.0.3:80", "127.0.0.1:1"]
+        );
+        assert_eq!(metrics.get("dns.resolutions"), 2);
+        assert_eq!(metrics.get("dns.failures"), 1);
+    }
+
+    #[test]
+    fn test_mappings_cannot_refer_to_discovered_backends() {
+        let errors = Config::from_toml(
+            "[backends.api]\nip = \"api.internal\"\ndns = {}\n[mappings.api]\npath = \"/api\"\n",
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{:?}: {}", errors[0].line, errors[0]),
+            "Some(4): mappings.api: refers to backend 'api', whose addresses are discovered"
+        );
+    }
+}
@@ -1 +1,412 @@
+use {
+    crate::{
+        agent,
+        algorithm::algorithm::{Algorithm, Strategy},
+        config::{BackendConfig, Config},
+        format::Format,
+        health_check,
+        metrics::Metrics,
+        pool::Pool,
+        with_read_lock, with_write_lock, Threadable,
+    },
+    log::{error, info, warn},
+    std::{
+        collections::HashMap,
+        fs::metadata,
+        net::SocketAddr,
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    },
+    tokio::{
+        select,
+        signal::unix::{signal, SignalKind},
+        time::delay_for,
+    },
+};
 
+/// How often the config file is checked for changes when watching it.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What changed between the running config and a reloaded one.
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigDiff {
+    /// Backends at an address which wasn't configured before.
+    pub added: Vec<BackendConfig>,
+    /// Backends at an address which is no longer configured.
+    pub removed: Vec<BackendConfig>,
+    /// The new config of backends whose address stayed but whose settings changed.
+    pub updated: Vec<BackendConfig>,
+    /// Whether a different strategy, or different parameters for it, were chosen.
+    pub strategy: bool,
+    pub health_check: bool,
+    /// Settings which changed, but only take effect after a restart.
+    pub restart: Vec<&'static str>,
+}
+
+impl ConfigDiff {
+    pub fn new(old: &Config, new: &Config) -> Self {
+        let by_authority = |config: &Config| {
+            config
+                .backends
+                .values()
+                .map(|backend| (backend.authority(), backend.clone()))
+                .collect::<HashMap<_, _>>()
+        };
+        let (old_backends, new_backends) = (by_authority(old), by_authority(new));
+        let mut diff = Self {
+            strategy: old.strategy != new.strategy,
+            health_check: old.health_check != new.health_check,
+            ..Self::default()
+        };
+        for (authority, backend) in new_backends.iter() {
+            match old_backends.get(authority) {
+                None => diff.added.push(backend.clone()),
+                Some(old) if old != backend => diff.updated.push(backend.clone()),
+                Some(_) => {}
+            }
+        }
+        for (authority, backend) in old_backends.iter() {
+            if !new_backends.contains_key(authority) {
+                diff.removed.push(backend.clone());
+            }
+        }
+        for backends in [&mut diff.added, &mut diff.removed, &mut diff.updated].iter_mut() {
+            backends.sort_by_key(BackendConfig::authority);
+        }
+
+        let restart = [
+            ("ip", old.ip != new.ip),
+            ("port", old.port != new.port),
+            (
+                "persistence_type",
+                old.persistence_type != new.persistence_type,
+            ),
+            ("admin", old.admin != new.admin),
+            ("routes", old.routes != new.routes),
+            ("retry", old.retry != new.retry),
+            ("timeouts", old.timeouts != new.timeouts),
+            ("rate_limit", old.rate_limit != new.rate_limit),
+            ("pool", old.pool != new.pool),
+            ("events", old.events != new.events),
+        ];
+        diff.restart = restart
+            .iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(setting, _)| *setting)
+            .collect();
+        diff
+    }
+}
+
+/// The running config, with each backend whose addresses are discovered replaced by a backend
+/// per address, or per member of its file. Those are named after the backend they were
+/// discovered for and their address, e.g. `api/10.0.0.1:8080`. Until addresses are discovered
+/// for a backend, it has none.
+pub fn expand(declared: &Config, discovered: &HashMap<String, Vec<BackendConfig>>) -> Config {
+    let mut config = declared.clone();
+    for (name, backend) in declared.backends.iter() {
+        if backend.is_discovered() {
+            config.backends.remove(name);
+            for backend in discovered.get(name).into_iter().flatten() {
+                config
+                    .backends
+                    .insert(format!("{}/{}", name, backend.authority()), backend.clone());
+            }
+        }
+    }
+    config
+}
+
+/// The config as loaded, and the backends discovered for it since.
+#[derive(Debug)]
+struct Sources {
+    declared: Config,
+    /// Keyed by the name of the backend they were discovered for.
+    discovered: HashMap<String, Vec<BackendConfig>>,
+}
+
+/// Applies changes to the config file, and to discovered backends, to the running load balancer.
+pub struct Reloader {
+    path: PathBuf,
+    format: Format,
+    /// Address given on the command line, which takes precedence over the file's.
+    listen: Option<SocketAddr>,
+    /// Held while changes are applied, so that they're applied one at a time.
+    sources: Mutex<Sources>,
+    config: Threadable<Config>,
+    strategy: Threadable<Strategy>,
+    pool: Arc<Pool>,
+    metrics: Arc<Metrics>,
+}
+
+impl Reloader {
+    /// `config` is the running config, expanded from the `declared` one.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        path: PathBuf,
+        format: Format,
+        listen: Option<SocketAddr>,
+        declared: Config,
+        config: Threadable<Config>,
+        strategy: Threadable<Strategy>,
+        pool: Arc<Pool>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            path,
+            format,
+            listen,
+            sources: Mutex::new(Sources {
+                declared,
+                discovered: HashMap::new(),
+            }),
+            config,
+            strategy,
+            pool,
+            metrics,
+        }
+    }
+
+    /// Path of the config file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Stops sending new sessions to a discovered backend, or starts again.
+    pub fn set_draining(&self, server: &BackendConfig, draining: bool) {
+        self.pool.set_draining(server, draining);
+    }
+
+    /// The config as loaded, before discovered backends are added.
+    pub fn declared(&self) -> Config {
+        let sources = self.sources.lock().expect("Could not lock mutex.");
+        sources.declared.clone()
+    }
+
+    /// Loads the config file and applies what changed. Backends which stay keep their sessions,
+    /// health and requests in flight. An invalid config is rejected and the running one kept.
+    pub fn reload(&self) -> Result<ConfigDiff, Box<dyn std::error::Error>> {
+        let mut declared = Config::parse(&self.path, self.format).inspect_err(|_| {
+            self.metrics.incr("config.reload_failed");
+        })?;
+        if let Some(addr) = self.listen {
+            declared.set_addr(addr);
+        }
+        let mut sources = self.sources.lock().expect("Could not lock mutex.");
+        // Backends which are no longer discovered drop their addresses. Those which changed keep
+        // theirs until they're looked up again.
+        sources.discovered.retain(|name, _| {
+            declared
+                .backends
+                .get(name)
+                .is_some_and(BackendConfig::is_discovered)
+        });
+        let diff = self.apply(expand(&declared, &sources.discovered));
+        sources.declared = declared;
+        for setting in diff.restart.iter() {
+            warn!("Changes to '{}' take effect after a restart.", setting);
+        }
+
+        self.metrics.incr("config.reloads");
+        info!(
+            "Reloaded '{}': {} backends added, {} removed and {} updated.",
+            self.path.display(),
+            diff.added.len(),
+            diff.removed.len(),
+            diff.updated.len()
+        );
+        Ok(diff)
+    }
+
+    /// Replaces the backends discovered for the backend `name`, and applies what changed.
+    /// Returns `None` if nothing did.
+    pub fn discover(&self, name: &str, backends: Vec<BackendConfig>) -> Option<ConfigDiff> {
+        let mut sources = self.sources.lock().expect("Could not lock mutex.");
+        if sources.discovered.get(name) == Some(&backends) {
+            return None;
+        }
+        sources.discovered.insert(name.to_string(), backends);
+        let diff = self.apply(expand(&sources.declared, &sources.discovered));
+        info!(
+            "Discovered backends of '{}' changed: {} added, {} removed and {} updated.",
+            name,
+            diff.added.len(),
+            diff.removed.len(),
+            diff.updated.len()
+        );
+        Some(diff)
+    }
+
+    /// Makes `new` the running config, changing only what differs from the current one.
+    fn apply(&self, new: Config) -> ConfigDiff {
+        let old = with_read_lock(self.config.clone(), |config| config.clone());
+        let diff = ConfigDiff::new(&old, &new);
+
+        self.pool.reload(&diff, &new.health_check);
+        with_write_lock(self.strategy.clone(), |strategy| {
+            if diff.strategy {
+                *strategy = new.strategy.clone();
+            }
+            strategy.configure(&new);
+        });
+        for server in diff.added.iter() {
+            health_check::start(self.pool.clone(), server.clone());
+        }
+        let had_agent = |server: &BackendConfig| {
+            old.backends
+                .values()
+                .any(|old| old.authority() == server.authority() && old.agent.is_some())
+        };
+        for server in diff.added.iter().chain(diff.updated.iter()) {
+            if server.agent.is_some() && !had_agent(server) {
+                agent::start(self.pool.clone(), server.clone());
+            }
+        }
+        with_write_lock(self.config.clone(), |config| *config = new);
+        diff
+    }
+
+    /// When the config file and the files it included were last modified.
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        let included = with_read_lock(self.config.clone(), |config| config.included.clone());
+        std::iter::once(&self.path)
+            .chain(included.iter())
+            .map(|path| metadata(path).and_then(|file| file.modified()).ok())
+            .collect()
+    }
+
+    /// Reloads on SIGHUP and, if `watch` is set, whenever the config file or a file it included
+    /// changes.
+    pub async fn run(&self, watch: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let mut modified = self.modified();
+        loop {
+            let reason = select! {
+                _ = hangup.recv() => Some("SIGHUP"),
+                _ = delay_for(WATCH_INTERVAL), if watch => {
+                    let now = self.modified();
+                    if now != modified {
+                        modified = now;
+                        Some("a change to the file")
+                    } else {
+                        None
+                    }
+                }
+            };
+            if let Some(reason) = reason {
+                info!("Reloading '{}' after {}.", self.path.display(), reason);
+                if let Err(e) = self.reload() {
+                    error!("Keeping the running config: {}", e);
+                }
+                modified = self.modified();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{config::ServerStatus, events::Events, pool::Probe},
+        std::{fs::write, sync::RwLock},
+    };
+
+    fn backend(port: u16) -> BackendConfig {
+        BackendConfig {
+            port,
+            ..BackendConfig::default()
+        }
+    }
+
+    fn config(ports: &[u16]) -> Config {
+        let mut config = Config::default();
+        for port in ports.iter() {
+            config.backends.insert(port.to_string(), backend(*port));
+        }
+        config
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = config(&[1, 2, 3]);
+        let mut new = config(&[2, 3, 4]);
+        new.backends.get_mut("3").unwrap().weight = 5;
+        new.strategy = "Random".parse().unwrap();
+        new.retry.attempts = 2;
+        assert_eq!(
+            ConfigDiff::new(&old, &new),
+            ConfigDiff {
+                added: vec![backend(4)],
+                removed: vec![backend(1)],
+                updated: vec![new.backends["3"].clone()],
+                strategy: true,
+                health_check: false,
+                restart: vec!["retry"],
+            }
+        );
+        assert_eq!(ConfigDiff::new(&old, &old.clone()), ConfigDiff::default());
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let path = std::env::temp_dir().join(format!("loblaw-reload-{}.toml", std::process::id()));
+        let file = |backends: &str| format!("strategy = \"RoundRobin\"\n{}", backends);
+        write(
+            &path,
+            file("[backends.a]\nport = 1\n[backends.b]\nport = 2\n"),
+        )
+        .unwrap();
+
+        let config = Config::parse(&path, Format::Toml).unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let (events, _) = Events::new(&config.events, metrics.clone());
+        let pool = Arc::new(Pool::new(&config, Arc::new(events), metrics.clone()));
+        let mut strategy = config.strategy.clone();
+        strategy.configure(&config);
+        let reloader = Reloader::new(
+            path.clone(),
+            Format::Toml,
+            None,
+            config.clone(),
+            Arc::new(RwLock::new(config)),
+            Arc::new(RwLock::new(strategy)),
+            pool.clone(),
+            metrics,
+        );
+        let failed = Probe {
+            at: SystemTime::now(),
+            healthy: false,
+            latency: Duration::default(),
+            detail: String::new(),
+        };
+        for _ in 0..pool.health_check().unhealthy_threshold {
+            pool.report_health(&backend(2), failed.clone());
+        }
+        let _in_flight = pool.reserve(&backend(2));
+
+        write(
+            &path,
+            file("[backends.b]\nport = 2\n[backends.c]\nport = 3\n"),
+        )
+        .unwrap();
+        let diff = reloader.reload().unwrap();
+        assert_eq!((diff.added.len(), diff.removed.len()), (1, 1));
+        let mut ports = pool
+            .backends()
+            .iter()
+            .map(|backend| (backend.config.port, backend.status, backend.in_flight))
+            .collect::<Vec<_>>();
+        ports.sort_by_key(|(port, _, _)| *port);
+        assert_eq!(
+            ports,
+            vec![(2, ServerStatus::Dead, 1), (3, ServerStatus::Alive, 0)]
+        );
+
+        write(&path, file("[backends.b]\nport = \"two\"\n")).unwrap();
+        assert!(reloader.reload().is_err());
+        assert_eq!(pool.backends().len(), 2);
+        let _ = std::fs::remove_file(&path);
+    }
+}
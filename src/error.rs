@@ -1,4 +1,12 @@
-use {crate::algorithm::algorithm::ServerSelectionError, std::fmt};
+use {
+    crate::algorithm::algorithm::ServerSelectionError,
+    actix_web::{
+        client::{ConnectError, PayloadError, SendRequestError},
+        http::StatusCode,
+        HttpResponse, ResponseError,
+    },
+    std::fmt,
+};
 
 #[derive(Debug, Clone)]
 pub struct CookieError;
@@ -35,3 +43,175 @@ impl From<ServerSelectionError> for ServerMappingError {
         ServerMappingError::ServerSelection(e)
     }
 }
+
+/// Why a request couldn't be completed by any backend.
+#[derive(Debug)]
+pub enum UpstreamError {
+    /// A connection to the backend couldn't be established in time.
+    ConnectTimeout,
+    /// The backend didn't send the response headers in time.
+    ResponseHeaderTimeout,
+    /// A single attempt took longer than the retry policy allows.
+    PerTryTimeout,
+    /// The backend stopped sending the response body.
+    IdleBodyTimeout,
+    /// The request as a whole took longer than its deadline.
+    DeadlineExceeded,
+    /// A connection to the backend couldn't be established.
+    Connect(ConnectError),
+    /// The backend couldn't be reached or sent an invalid response.
+    Send(SendRequestError),
+    /// The response body couldn't be read.
+    Payload(PayloadError),
+    /// The pool is at its concurrency limit.
+    Overloaded,
+    /// The chosen backend is at its connection limit.
+    Busy,
+    /// No backend could ever serve the request, e.g. because no mapping matches it.
+    Unroutable,
+    /// Every backend is busy and the queue is full.
+    QueueFull,
+    /// Every backend stayed busy for as long as the request could wait.
+    QueueTimeout,
+}
+
+impl UpstreamError {
+    /// Name of the metric counting occurrences of this error.
+    pub fn metric(&self) -> &'static str {
+        match *self {
+            UpstreamError::ConnectTimeout => "upstream.connect_timeout",
+            UpstreamError::ResponseHeaderTimeout => "upstream.response_header_timeout",
+            UpstreamError::PerTryTimeout => "upstream.per_try_timeout",
+            UpstreamError::IdleBodyTimeout => "upstream.idle_body_timeout",
+            UpstreamError::DeadlineExceeded => "upstream.deadline_exceeded",
+            UpstreamError::Connect(_) => "upstream.connect_error",
+            UpstreamError::Send(_) => "upstream.send_error",
+            UpstreamError::Payload(_) => "upstream.payload_error",
+            UpstreamError::Overloaded => "pool.concurrency_rejected",
+            UpstreamError::Busy => "pool.backend_busy",
+            UpstreamError::Unroutable => "pool.unroutable",
+            UpstreamError::QueueFull => "pool.queue_overflow",
+            UpstreamError::QueueTimeout => "pool.queue_expired",
+        }
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UpstreamError::ConnectTimeout => write!(f, "Timed out connecting to the backend."),
+            UpstreamError::ResponseHeaderTimeout => {
+                write!(f, "Timed out waiting for the backend's response headers.")
+            }
+            UpstreamError::PerTryTimeout => write!(f, "Timed out waiting for an attempt."),
+            UpstreamError::IdleBodyTimeout => {
+                write!(f, "Timed out waiting for the backend's response body.")
+            }
+            UpstreamError::DeadlineExceeded => write!(f, "The request exceeded its deadline."),
+            UpstreamError::Connect(ref e) => write!(f, "Couldn't connect to the backend: {}.", e),
+            UpstreamError::Send(ref e) => write!(f, "Couldn't forward the request: {}.", e),
+            UpstreamError::Payload(ref e) => write!(f, "Couldn't read the response body: {}.", e),
+            UpstreamError::Overloaded => write!(f, "The backends are overloaded."),
+            UpstreamError::Busy => write!(f, "The backend is at its connection limit."),
+            UpstreamError::Unroutable => write!(f, "No backend serves this request."),
+            UpstreamError::QueueFull => write!(f, "Every backend is busy."),
+            UpstreamError::QueueTimeout => write!(f, "Timed out waiting for a backend."),
+        }
+    }
+}
+
+impl ResponseError for UpstreamError {
+    fn status_code(&self) -> StatusCode {
+        match *self {
+            UpstreamError::ConnectTimeout
+            | UpstreamError::ResponseHeaderTimeout
+            | UpstreamError::PerTryTimeout
+            | UpstreamError::IdleBodyTimeout
+            | UpstreamError::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+            UpstreamError::Connect(_) | UpstreamError::Send(_) | UpstreamError::Payload(_) => {
+                StatusCode::BAD_GATEWAY
+            }
+            UpstreamError::Overloaded
+            | UpstreamError::Busy
+            | UpstreamError::Unroutable
+            | UpstreamError::QueueFull
+            | UpstreamError::QueueTimeout => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).body(self.to_string())
+    }
+}
+
+impl From<SendRequestError> for UpstreamError {
+    fn from(e: SendRequestError) -> Self {
+        match e {
+            SendRequestError::Timeout => UpstreamError::ResponseHeaderTimeout,
+            SendRequestError::Connect(ConnectError::Timeout) => UpstreamError::ConnectTimeout,
+            SendRequestError::Connect(e) => UpstreamError::Connect(e),
+            e => UpstreamError::Send(e),
+        }
+    }
+}
+
+impl From<PayloadError> for UpstreamError {
+    fn from(e: PayloadError) -> Self {
+        UpstreamError::Payload(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upstream_status_codes() {
+        let cases = vec![
+            (UpstreamError::ConnectTimeout, StatusCode::GATEWAY_TIMEOUT),
+            (
+                UpstreamError::ResponseHeaderTimeout,
+                StatusCode::GATEWAY_TIMEOUT,
+            ),
+            (UpstreamError::PerTryTimeout, StatusCode::GATEWAY_TIMEOUT),
+            (UpstreamError::IdleBodyTimeout, StatusCode::GATEWAY_TIMEOUT),
+            (UpstreamError::DeadlineExceeded, StatusCode::GATEWAY_TIMEOUT),
+            (
+                UpstreamError::Connect(ConnectError::NoRecords),
+                StatusCode::BAD_GATEWAY,
+            ),
+            (
+                UpstreamError::Payload(PayloadError::Overflow),
+                StatusCode::BAD_GATEWAY,
+            ),
+            (UpstreamError::Overloaded, StatusCode::SERVICE_UNAVAILABLE),
+            (UpstreamError::Busy, StatusCode::SERVICE_UNAVAILABLE),
+            (UpstreamError::Unroutable, StatusCode::SERVICE_UNAVAILABLE),
+            (UpstreamError::QueueFull, StatusCode::SERVICE_UNAVAILABLE),
+            (UpstreamError::QueueTimeout, StatusCode::SERVICE_UNAVAILABLE),
+        ];
+        for (error, status) in cases {
+            assert_eq!(error.status_code(), status, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn test_send_errors() {
+        assert!(matches!(
+            UpstreamError::from(SendRequestError::Timeout),
+            UpstreamError::ResponseHeaderTimeout
+        ));
+        assert!(matches!(
+            UpstreamError::from(SendRequestError::Connect(ConnectError::Timeout)),
+            UpstreamError::ConnectTimeout
+        ));
+        assert!(matches!(
+            UpstreamError::from(SendRequestError::Connect(ConnectError::NoRecords)),
+            UpstreamError::Connect(_)
+        ));
+        assert!(matches!(
+            UpstreamError::from(SendRequestError::TunnelNotSupported),
+            UpstreamError::Send(_)
+        ));
+    }
+}
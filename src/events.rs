@@ -0,0 +1,249 @@
+use {
+    crate::{
+        config::{EventsConfig, ServerStatus, WebhookConfig},
+        metrics::Metrics,
+        pool::Probe,
+    },
+    actix_web::client::Client,
+    log::{error, warn},
+    serde_json::{json, Value},
+    std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+    tokio::{sync::mpsc, time::delay_for},
+};
+
+/// A change in a backend's health.
+#[derive(Debug, Clone)]
+pub struct HealthEvent {
+    pub at: SystemTime,
+    pub backend: String,
+    pub old: ServerStatus,
+    pub new: ServerStatus,
+    pub reason: String,
+    /// The probe which caused the change, if any.
+    pub probe: Option<Probe>,
+}
+
+impl HealthEvent {
+    pub fn to_json(&self) -> Value {
+        let at = self.at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        json!({
+            "at_ms": at.as_millis() as u64,
+            "backend": self.backend,
+            "old": format!("{:?}", self.old),
+            "new": format!("{:?}", self.new),
+            "reason": self.reason,
+            "probe": self.probe.as_ref().map(Probe::to_json),
+        })
+    }
+}
+
+/// Sends health events to the log, the in-memory history and the webhook.
+#[derive(Debug)]
+pub struct Events {
+    config: EventsConfig,
+    history: Mutex<VecDeque<HealthEvent>>,
+    webhook: Option<Mutex<mpsc::Sender<HealthEvent>>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Events {
+    /// Also returns the webhook which delivers the events, if one is configured.
+    pub fn new(config: &EventsConfig, metrics: Arc<Metrics>) -> (Self, Option<Webhook>) {
+        let (sender, webhook) = match config.webhook {
+            Some(ref webhook) => {
+                let (sender, receiver) = mpsc::channel(webhook.queue_size.max(1));
+                let webhook = Webhook {
+                    config: webhook.clone(),
+                    events: receiver,
+                    metrics: metrics.clone(),
+                };
+                (Some(Mutex::new(sender)), Some(webhook))
+            }
+            None => (None, None),
+        };
+        let events = Self {
+            config: config.clone(),
+            history: Mutex::new(VecDeque::new()),
+            webhook: sender,
+            metrics,
+        };
+        (events, webhook)
+    }
+
+    pub fn emit(&self, event: HealthEvent) {
+        self.metrics.incr("events.emitted");
+        if self.config.log {
+            warn!(
+                "Backend {} changed from {:?} to {:?}: {}.",
+                event.backend, event.old, event.new, event.reason
+            );
+        }
+        if let Some(ref webhook) = self.webhook {
+            let mut webhook = webhook.lock().expect("Could not lock mutex.");
+            if webhook.try_send(event.clone()).is_err() {
+                self.metrics.incr("events.webhook_dropped");
+            }
+        }
+        let mut history = self.history.lock().expect("Could not lock mutex.");
+        history.push_back(event);
+        while history.len() > self.config.history_size {
+            history.pop_front();
+        }
+    }
+
+    /// The most recent events, newest first, optionally only those about `backend`.
+    pub fn query(&self, backend: Option<&str>, limit: usize) -> Vec<HealthEvent> {
+        let history = self.history.lock().expect("Could not lock mutex.");
+        history
+            .iter()
+            .rev()
+            .filter(|event| backend.is_none_or(|backend| event.backend == backend))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Delivers events to the configured webhook in the order they happened.
+pub struct Webhook {
+    config: WebhookConfig,
+    events: mpsc::Receiver<HealthEvent>,
+    metrics: Arc<Metrics>,
+}
+
+impl Webhook {
+    /// POSTs `event`, retrying with exponential backoff.
+    async fn deliver(&self, client: &Client, event: &HealthEvent) -> Result<(), String> {
+        let body = event.to_json();
+        let mut backoff = self.config.backoff;
+        let mut attempt = 0;
+        loop {
+            let result = match client.post(self.config.url.as_str()).send_json(&body).await {
+                Ok(res) if res.status().is_success() => return Ok(()),
+                Ok(res) => format!("status {}", res.status()),
+                Err(e) => e.to_string(),
+            };
+            if attempt >= self.config.retries {
+                return Err(result);
+            }
+            attempt += 1;
+            self.metrics.incr("events.webhook_retries");
+            delay_for(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    pub async fn run(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let client = Client::build().timeout(self.config.timeout).finish();
+        while let Some(event) = self.events.recv().await {
+            if let Err(e) = self.deliver(&client, &event).await {
+                self.metrics.incr("events.webhook_failed");
+                error!(
+                    "Could not deliver event about {} to '{}': {}.",
+                    event.backend, self.config.url, e
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs the webhook, if there is one.
+pub async fn run(webhook: Option<Webhook>) -> Result<(), Box<dyn std::error::Error>> {
+    match webhook {
+        Some(webhook) => webhook.run().await,
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::{
+            sync::atomic::{AtomicUsize, Ordering},
+            time::Duration,
+        },
+        tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpListener,
+        },
+    };
+
+    fn event(backend: &str) -> HealthEvent {
+        HealthEvent {
+            at: SystemTime::now(),
+            backend: backend.to_string(),
+            old: ServerStatus::Alive,
+            new: ServerStatus::Dead,
+            reason: String::from("5 consecutive failed checks"),
+            probe: None,
+        }
+    }
+
+    #[test]
+    fn test_history_is_bounded_and_queryable() {
+        let config = EventsConfig {
+            log: false,
+            history_size: 3,
+            webhook: None,
+        };
+        let (events, _) = Events::new(&config, Arc::new(Metrics::default()));
+        for backend in ["a", "b", "a", "b", "a"].iter() {
+            events.emit(event(backend));
+        }
+        assert_eq!(events.query(None, 10).len(), 3);
+        assert_eq!(events.query(Some("a"), 10).len(), 2);
+        assert_eq!(events.query(Some("b"), 10).len(), 1);
+        assert_eq!(events.query(None, 1).len(), 1);
+    }
+
+    /// A stand-in webhook receiver, which fails the first `failures` requests.
+    async fn receiver(failures: usize) -> (String, Arc<AtomicUsize>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let count = requests.clone();
+        actix_rt::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf).await;
+                let status = if count.fetch_add(1, Ordering::SeqCst) < failures {
+                    "500 Internal Server Error"
+                } else {
+                    "204 No Content"
+                };
+                let response = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\n\r\n", status);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, requests)
+    }
+
+    #[actix_rt::test]
+    async fn test_webhook_retries() {
+        let (url, requests) = receiver(2).await;
+        let config = EventsConfig {
+            log: false,
+            history_size: 10,
+            webhook: Some(WebhookConfig {
+                url,
+                retries: 2,
+                backoff: Duration::from_millis(1),
+                ..WebhookConfig::default()
+            }),
+        };
+        let (_, webhook) = Events::new(&config, Arc::new(Metrics::default()));
+        let webhook = webhook.unwrap();
+        let client = Client::default();
+        assert!(webhook.deliver(&client, &event("a")).await.is_ok());
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        assert!(webhook.deliver(&client, &event("a")).await.is_ok());
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+    }
+}
@@ -0,0 +1,460 @@
+use {
+    crate::{
+        config::{BackendConfig, Scheme},
+        dynamic::Reloader,
+        format::Format,
+        metrics::Metrics,
+    },
+    log::warn,
+    serde::Deserialize,
+    std::{
+        collections::HashMap,
+        fs::{metadata, read_to_string},
+        net::{IpAddr, SocketAddr},
+        path::{Path, PathBuf},
+        sync::Arc,
+        time::{Duration, Instant, SystemTime},
+    },
+    tokio::time::delay_for,
+};
+
+/// How often the files are checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Targets which share labels.
+#[derive(Deserialize, Debug)]
+struct TargetGroup {
+    /// Addresses such as `10.0.0.1:8080`, or IP addresses which take the backend's port.
+    targets: Vec<String>,
+    /// `weight`, `scheme`, `path` and `zone` override the backend's. Other labels become its
+    /// metadata.
+    #[serde(default)]
+    labels: HashMap<String, String>,
+}
+
+/// TOML has no top level lists, so there the groups are listed under `groups`.
+#[derive(Deserialize, Debug)]
+struct Groups {
+    groups: Vec<TargetGroup>,
+}
+
+/// The members listed in `source`, each with the settings of `backend` but for its address and
+/// labels. JSON and YAML files are a list of groups, as Prometheus writes them.
+pub fn parse(
+    source: &str,
+    format: Format,
+    backend: &BackendConfig,
+) -> Result<Vec<BackendConfig>, String> {
+    let (groups, errors) = match format {
+        Format::Toml => {
+            let (groups, errors) = format.deserialize::<Groups>(source);
+            (groups.map(|groups| groups.groups), errors)
+        }
+        Format::Yaml | Format::Json => format.deserialize::<Vec<TargetGroup>>(source),
+    };
+    if let Some(error) = errors.first() {
+        let line = error.line.or_else(|| format.line_of(source, &error.key));
+        return Err(match line {
+            Some(line) => format!("line {}: {}", line, error),
+            None => error.to_string(),
+        });
+    }
+    let mut members = Vec::new();
+    for group in groups.unwrap_or_default() {
+        let mut template = BackendConfig {
+            file_sd: None,
+            ..backend.clone()
+        };
+        for (label, value) in group.labels.iter() {
+            let invalid = || format!("label '{}' has an invalid value '{}'", label, value);
+            match label.as_str() {
+                "weight" => template.weight = value.parse().map_err(|_| invalid())?,
+                "scheme" => {
+                    template.scheme = match value.as_str() {
+                        "http" => Scheme::Http,
+                        "https" => Scheme::Https,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "path" => template.path = value.clone(),
+                "zone" => template.zone = Some(value.clone()),
+                _ => {
+                    template.meta.insert(label.clone(), value.clone());
+                }
+            }
+        }
+        for target in group.targets {
+            let (ip, port) = match (target.parse::<SocketAddr>(), target.parse::<IpAddr>()) {
+                (Ok(addr), _) => (addr.ip(), addr.port()),
+                (_, Ok(ip)) => (ip, backend.port),
+                _ => {
+                    return Err(format!(
+                        "'{}' is not an address such as 10.0.0.1:8080",
+                        target
+                    ))
+                }
+            };
+            members.push(BackendConfig {
+                ip: ip.to_string(),
+                port,
+                ..template.clone()
+            });
+        }
+    }
+    members.sort_by_key(BackendConfig::authority);
+    members.dedup_by_key(|member| member.authority());
+    Ok(members)
+}
+
+/// A backend's file, and the members read from it.
+#[derive(Debug)]
+struct Watched {
+    /// The backend's config when the file was last read. Once it changes, the file is read again.
+    backend: BackendConfig,
+    /// When the file that was last read was modified and its length, the inner `None` if it
+    /// didn't exist. The outer one is `None` until it's read.
+    version: Option<Option<(SystemTime, u64)>>,
+    members: Vec<BackendConfig>,
+    /// Members which were removed from the file, and when they're dropped.
+    draining: Vec<(BackendConfig, Instant)>,
+}
+
+/// Keeps the members of the backends which are discovered through files up to date.
+#[derive(Debug)]
+pub struct Discovery {
+    /// Directory which relative paths are resolved against.
+    dir: PathBuf,
+    watched: HashMap<String, Watched>,
+}
+
+impl Discovery {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            watched: HashMap::new(),
+        }
+    }
+
+    /// Reads the files which changed and hands their members to `reloader`. Removed members are
+    /// drained until their time is up. A file which can't be read or parsed keeps the members it
+    /// had.
+    pub fn poll(&mut self, reloader: &Reloader, metrics: &Metrics) {
+        let declared = reloader.declared();
+        self.watched.retain(|name, _| {
+            declared
+                .backends
+                .get(name)
+                .is_some_and(|backend| backend.file_sd.is_some())
+        });
+        let mut backends = declared
+            .backends
+            .iter()
+            .filter_map(|(name, backend)| Some((name, backend, backend.file_sd.as_ref()?)))
+            .collect::<Vec<_>>();
+        backends.sort_by_key(|(name, _, _)| name.as_str());
+        for (name, backend, file_sd) in backends {
+            let watched = self.watched.entry(name.clone()).or_insert_with(|| Watched {
+                backend: backend.clone(),
+                version: None,
+                members: Vec::new(),
+                draining: Vec::new(),
+            });
+            if watched.backend != *backend {
+                watched.backend = backend.clone();
+                watched.version = None;
+            }
+
+            let now = Instant::now();
+            let path = self.dir.join(&file_sd.path);
+            let version = metadata(&path)
+                .and_then(|metadata| Ok((metadata.modified()?, metadata.len())))
+                .ok();
+            let (mut drained, mut restored) = (Vec::new(), Vec::new());
+            let mut changed = false;
+            if watched.version != Some(version) {
+                watched.version = Some(version);
+                let members = read_to_string(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|source| parse(&source, Format::of(&path), backend));
+                match members {
+                    Ok(members) => {
+                        metrics.incr("file_sd.reloads");
+                        let listed = |member: &BackendConfig, members: &[BackendConfig]| {
+                            members
+                                .iter()
+                                .any(|other| other.authority() == member.authority())
+                        };
+                        watched.draining.retain(|(member, _)| {
+                            let back = listed(member, &members);
+                            if back {
+                                restored.push(member.clone());
+                            }
+                            !back
+                        });
+                        for member in watched.members.iter() {
+                            if !listed(member, &members) {
+                                watched.draining.push((member.clone(), now + file_sd.drain));
+                                drained.push(member.clone());
+                            }
+                        }
+                        watched.members = members;
+                        changed = true;
+                    }
+                    Err(e) => {
+                        metrics.incr("file_sd.failures");
+                        warn!(
+                            "Could not read the members of backend '{}' from '{}', keeping them: {}",
+                            name,
+                            path.display(),
+                            e
+                        );
+                    }
+                }
+            }
+            let draining = watched.draining.len();
+            watched.draining.retain(|(_, until)| *until > now);
+            changed |= watched.draining.len() != draining;
+
+            if changed {
+                let discovered = watched
+                    .members
+                    .iter()
+                    .chain(watched.draining.iter().map(|(member, _)| member))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                reloader.discover(name, discovered);
+                for member in restored.iter() {
+                    reloader.set_draining(member, false);
+                }
+                for member in drained.iter() {
+                    reloader.set_draining(member, true);
+                }
+            }
+        }
+    }
+}
+
+/// Watches the files of backends which are discovered through them for as long as the load
+/// balancer runs.
+pub async fn run(
+    reloader: &Reloader,
+    metrics: Arc<Metrics>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = reloader.path().parent().unwrap_or_else(|| Path::new("."));
+    let mut discovery = Discovery::new(dir);
+    loop {
+        discovery.poll(reloader, &metrics);
+        delay_for(WATCH_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            algorithm::algorithm::Algorithm,
+            config::{Config, ServerStatus},
+            dynamic::expand,
+            events::Events,
+            pool::Pool,
+        },
+        std::{
+            env::temp_dir,
+            fs::{create_dir_all, remove_dir_all, write},
+            sync::RwLock,
+        },
+    };
+
+    fn backend() -> BackendConfig {
+        BackendConfig {
+            port: 80,
+            weight: 2,
+            ..BackendConfig::default()
+        }
+    }
+
+    fn members(source: &str, format: Format) -> Result<Vec<(String, u32, String)>, String> {
+        Ok(parse(source, format, &backend())?
+            .into_iter()
+            .map(|member| {
+                (
+                    member.authority(),
+                    member.weight,
+                    member.uri().unwrap().to_string(),
+                )
+            })
+            .collect())
+    }
+
+    #[test]
+    fn test_parse() {
+        let json = r#"[
+  { "targets": ["10.0.0.2:8080", "10.0.0.1"] },
+  { "targets": ["10.0.0.3:8080"], "labels": { "weight": "5", "scheme": "https", "env": "prod" } }
+]"#;
+        let expected = vec![
+            (
+                String::from("10.0.0.1:80"),
+                2,
+                String::from("http://10.0.0.1:80/backend"),
+            ),
+            (
+                String::from("10.0.0.2:8080"),
+                2,
+                String::from("http://10.0.0.2:8080/backend"),
+            ),
+            (
+                String::from("10.0.0.3:8080"),
+                5,
+                String::from("https://10.0.0.3:8080/backend"),
+            ),
+        ];
+        assert_eq!(members(json, Format::Json).unwrap(), expected);
+        let labelled = parse(
+            "[{ \"targets\": [\"10.0.0.1\"], \"labels\": { \"zone\": \"b\", \"env\": \"prod\" } }]",
+            Format::Json,
+            &backend(),
+        )
+        .unwrap();
+        assert_eq!(labelled[0].zone.as_deref(), Some("b"));
+        assert_eq!(
+            labelled[0].meta.iter().collect::<Vec<_>>(),
+            vec![(&String::from("env"), &String::from("prod"))]
+        );
+        let yaml = "- targets: [\"10.0.0.2:8080\", \"10.0.0.1\"]\n- targets: [\"10.0.0.3:8080\"]\n  labels:\n    weight: \"5\"\n    scheme: https\n";
+        assert_eq!(members(yaml, Format::Yaml).unwrap(), expected);
+        let toml = "[[groups]]\ntargets = [\"10.0.0.2:8080\", \"10.0.0.1\"]\n[[groups]]\ntargets = [\"10.0.0.3:8080\"]\nlabels = { weight = \"5\", scheme = \"https\" }\n";
+        assert_eq!(members(toml, Format::Toml).unwrap(), expected);
+        assert_eq!(members("[]", Format::Json).unwrap(), vec![]);
+
+        assert_eq!(
+            members("[{ \"targets\": [\"api:80\"] }]", Format::Json).unwrap_err(),
+            "'api:80' is not an address such as 10.0.0.1:8080"
+        );
+        assert_eq!(
+            members(
+                "[{ \"targets\": [], \"labels\": { \"weight\": \"x\" } }]",
+                Format::Json
+            )
+            .unwrap_err(),
+            "label 'weight' has an invalid value 'x'"
+        );
+        assert_eq!(
+            members("[\n  { \"target\": [] }\n]", Format::Json).unwrap_err(),
+            "line 2: 0.target: unknown key"
+        );
+    }
+
+    #[test]
+    fn test_validation() {
+        let errors = Config::from_toml(
+            "[backends.a]\nfile_sd = { path = \"\" }\ndns = {}\n[mappings.a]\npath = \"/a\"\n",
+        )
+        .unwrap_err()
+        .iter()
+        .map(|error| format!("{:?}: {}", error.line, error))
+        .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                "Some(2): backends.a.file_sd.path: is empty",
+                "Some(3): backends.a.dns: can't be set along with 'file_sd'",
+                "Some(4): mappings.a: refers to backend 'a', whose addresses are discovered",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_members_follow_the_file() {
+        let dir = temp_dir().join(format!("loblaw-file-sd-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let targets = |file: &str, targets: &[&str]| {
+            let targets = targets
+                .iter()
+                .map(|target| format!("\"{}\"", target))
+                .collect::<Vec<_>>();
+            let source = format!("[{{ \"targets\": [{}] }}]", targets.join(", "));
+            write(dir.join(file), source).unwrap();
+        };
+        targets("a.json", &["10.0.0.1:80", "10.0.0.2:80"]);
+        targets("b.json", &["10.0.1.1:80"]);
+
+        let declared = Config::from_toml(
+            r#"
+strategy = "RoundRobin"
+[backends.a]
+file_sd = { path = "a.json", drain = "1h" }
+[backends.b]
+file_sd = { path = "b.json", drain = "0s" }
+"#,
+        )
+        .unwrap();
+        let config = expand(&declared, &HashMap::new());
+        let metrics = Arc::new(Metrics::default());
+        let (events, _) = Events::new(&config.events, metrics.clone());
+        let pool = Arc::new(Pool::new(&config, Arc::new(events), metrics.clone()));
+        let mut strategy = config.strategy.clone();
+        strategy.configure(&config);
+        let reloader = Reloader::new(
+            dir.join("config.toml"),
+            Format::Toml,
+            None,
+            declared,
+            Arc::new(RwLock::new(config)),
+            Arc::new(RwLock::new(strategy)),
+            pool.clone(),
+            metrics.clone(),
+        );
+        let statuses = || {
+            let mut statuses = pool
+                .backends()
+                .iter()
+                .map(|backend| (backend.config.authority(), backend.status))
+                .collect::<Vec<_>>();
+            statuses.sort_by(|(a, _), (b, _)| a.cmp(b));
+            statuses
+        };
+        let status = |authority: &str, status| (String::from(authority), status);
+
+        let mut discovery = Discovery::new(&dir);
+        discovery.poll(&reloader, &metrics);
+        assert_eq!(
+            statuses(),
+            vec![
+                status("10.0.0.1:80", ServerStatus::Alive),
+                status("10.0.0.2:80", ServerStatus::Alive),
+                status("10.0.1.1:80", ServerStatus::Alive),
+            ]
+        );
+
+        targets("a.json", &["10.0.0.2:80", "10.0.0.3:80"]);
+        targets("b.json", &["10.0.1.2:80"]);
+        discovery.poll(&reloader, &metrics);
+        assert_eq!(
+            statuses(),
+            vec![
+                status("10.0.0.1:80", ServerStatus::Draining),
+                status("10.0.0.2:80", ServerStatus::Alive),
+                status("10.0.0.3:80", ServerStatus::Alive),
+                status("10.0.1.2:80", ServerStatus::Alive),
+            ]
+        );
+
+        targets("a.json", &["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"]);
+        write(dir.join("b.json"), "[{ \"targets\": [\"10.0.1.2:80\"] ").unwrap();
+        discovery.poll(&reloader, &metrics);
+        assert_eq!(
+            statuses(),
+            vec![
+                status("10.0.0.1:80", ServerStatus::Alive),
+                status("10.0.0.2:80", ServerStatus::Alive),
+                status("10.0.0.3:80", ServerStatus::Alive),
+                status("10.0.1.2:80", ServerStatus::Alive),
+            ]
+        );
+        assert_eq!(metrics.get("file_sd.reloads"), 5);
+        assert_eq!(metrics.get("file_sd.failures"), 1);
+        let _ = remove_dir_all(&dir);
+    }
+}
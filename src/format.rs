@@ -0,0 +1,231 @@
+use {
+    crate::validate::{self, ConfigError},
+    serde::de::DeserializeOwned,
+    std::path::Path,
+    strum_macros::{Display, EnumString, EnumVariantNames},
+};
+
+/// The languages a config may be written in. They only differ in syntax, so a config means the
+/// same and is validated the same in each.
+#[derive(Debug, Clone, Copy, PartialEq, EnumString, EnumVariantNames, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum Format {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl Format {
+    /// The format of the file at `path` going by its extension, which is TOML unless the
+    /// extension is `.yaml`, `.yml` or `.json`.
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml") | Some("yml") => Format::Yaml,
+            Some("json") => Format::Json,
+            _ => Format::Toml,
+        }
+    }
+
+    /// Deserializes `source`, reporting unknown keys as errors rather than ignoring them.
+    pub fn deserialize<T: DeserializeOwned>(self, source: &str) -> (Option<T>, Vec<ConfigError>) {
+        let mut errors = Vec::new();
+        let unknown = |path: serde_ignored::Path| errors.push(ConfigError::unknown(&path));
+        let value = match self {
+            Format::Toml => {
+                serde_ignored::deserialize(&mut toml::Deserializer::new(source), unknown).map_err(
+                    |e| {
+                        let line = e.line_col().map(|(line, _)| line + 1);
+                        (e.to_string(), line)
+                    },
+                )
+            }
+            Format::Yaml => {
+                serde_ignored::deserialize(serde_yaml::Deserializer::from_str(source), unknown)
+                    .map_err(|e| {
+                        let line = e.location().map(|location| location.line());
+                        (e.to_string(), line)
+                    })
+            }
+            Format::Json => {
+                let mut deserializer = serde_json::Deserializer::from_str(source);
+                serde_ignored::deserialize(&mut deserializer, unknown)
+                    .and_then(|value| deserializer.end().map(|_| value))
+                    .map_err(|e| (e.to_string(), Some(e.line()).filter(|line| *line > 0)))
+            }
+        };
+        match value {
+            Ok(value) => (Some(value), errors),
+            Err((message, line)) => {
+                let mut error = ConfigError::new(&[], message);
+                error.line = line;
+                errors.push(error);
+                (None, errors)
+            }
+        }
+    }
+
+    /// The 1-based line of `source` on which `key` is set, or else on which its closest
+    /// enclosing table or mapping is.
+    pub fn line_of(self, source: &str, key: &[String]) -> Option<usize> {
+        match self {
+            Format::Toml => validate::line_of(source, key),
+            Format::Yaml | Format::Json => nested_line_of(source, key),
+        }
+    }
+
+    /// Finds the lines in `source` of errors in the main config which don't have one yet.
+    pub fn locate(self, errors: &mut [ConfigError], source: &str) {
+        for error in errors.iter_mut() {
+            if error.line.is_none() && error.file.is_none() {
+                error.line = self.line_of(source, &error.key);
+            }
+        }
+    }
+}
+
+/// The key a YAML or JSON line sets, e.g. `port` for `port: 80` or `"port": 80,`.
+fn key_of(line: &str) -> Option<&str> {
+    let line = line.trim_start_matches(|c: char| c == '-' || c == '{' || c.is_whitespace());
+    let (key, rest) = match line.chars().next()? {
+        quote @ '"' | quote @ '\'' => {
+            let end = line[1..].find(quote)? + 1;
+            (&line[1..end], &line[end + 1..])
+        }
+        _ => {
+            let end = line.find(':')?;
+            (line[..end].trim_end(), &line[end..])
+        }
+    };
+    let rest = rest.trim_start().strip_prefix(':')?;
+    if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+        Some(key)
+    } else {
+        None
+    }
+}
+
+/// Finds `key` in an indented YAML or JSON document, looking for each segment only among the
+/// lines indented deeper than its parent's. Array indices are ignored, as they are for TOML.
+fn nested_line_of(source: &str, key: &[String]) -> Option<usize> {
+    let key = key
+        .iter()
+        .filter(|segment| segment.parse::<usize>().is_err())
+        .collect::<Vec<_>>();
+    let (mut found, mut parent, mut line_found) = (0, None, None);
+    for (i, line) in source.lines().enumerate() {
+        if found == key.len() {
+            break;
+        }
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let indent = line.len() - trimmed.len();
+        if parent.is_some_and(|parent| indent <= parent) {
+            break;
+        }
+        if key_of(line) == Some(key[found].as_str()) {
+            found += 1;
+            parent = Some(indent);
+            line_found = Some(i + 1);
+        }
+    }
+    line_found
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::config::Config};
+
+    const YAML: &str = r#"
+strategy: RoundRobin
+port: 8081
+health_check:
+  interval: 250ms
+backends:
+  a:
+    port: 80
+  "b":
+    ip: ""
+    port: 81
+mappings:
+  c:
+    path: /c
+"#;
+
+    const JSON: &str = r#"{
+  "strategy": { "LeastLatency": {} },
+  "port": "8081",
+  "backends": {
+    "a": { "port": 80, "scheme": "https" },
+    "b": {
+      "ip": "",
+      "weights": 1
+    }
+  }
+}"#;
+
+    fn errors(source: &str, format: Format) -> Vec<String> {
+        Config::from_source(source, format)
+            .unwrap_err()
+            .iter()
+            .map(|error| format!("{:?}: {}", error.line, error))
+            .collect()
+    }
+
+    #[test]
+    fn test_of() {
+        assert_eq!(Format::of(Path::new("a/config.yml")), Format::Yaml);
+        assert_eq!(Format::of(Path::new("config.yaml")), Format::Yaml);
+        assert_eq!(Format::of(Path::new("config.json")), Format::Json);
+        assert_eq!(Format::of(Path::new("config.toml")), Format::Toml);
+        assert_eq!(Format::of(Path::new("config")), Format::Toml);
+        assert_eq!("yaml".parse(), Ok(Format::Yaml));
+    }
+
+    #[test]
+    fn test_same_semantics() {
+        let yaml = YAML.replace("ip: \"\"", "ip: \"10.0.0.2\"");
+        let config = Config::from_source(&yaml.replace("  c:", "  a:"), Format::Yaml).unwrap();
+        assert_eq!(config.port, 8081);
+        assert_eq!(config.strategy.to_string(), "RoundRobin");
+        assert_eq!(config.health_check.interval.as_millis(), 250);
+        assert_eq!(config.backends["b"].authority(), "10.0.0.2:81");
+
+        let json = JSON
+            .replace("\"ip\": \"\",", "")
+            .replace("\"weights\"", "\"weight\"");
+        let config = Config::from_source(&json, Format::Json).unwrap();
+        assert_eq!(config.strategy.to_string(), "LeastLatency");
+        assert_eq!(config.addr().port(), 8081);
+        assert_eq!(
+            config.backends["a"].uri().unwrap(),
+            "https://127.0.0.1:80/backend"
+        );
+        assert_eq!(config.backends["b"].weight, 1);
+    }
+
+    #[test]
+    fn test_same_validation() {
+        assert_eq!(
+            errors(YAML, Format::Yaml),
+            vec![
+                "Some(10): backends.b.ip: is empty",
+                "Some(13): mappings.c: refers to backend 'c', which isn't configured",
+            ]
+        );
+        assert_eq!(
+            errors(JSON, Format::Json),
+            vec![
+                "Some(7): backends.b.ip: is empty",
+                "Some(8): backends.b.weights: unknown key",
+            ]
+        );
+        let yaml = errors("port: 80\nbackends: ]\nip: x\n", Format::Yaml);
+        assert!(yaml[0].starts_with("Some(2): "), "{}", yaml[0]);
+        let json = errors("{\n\"replicas\": \"many\" }", Format::Json);
+        assert!(json[0].starts_with("Some(2): invalid type"), "{}", json[0]);
+        let json = errors("{\n\"port\": 99999 }", Format::Json);
+        assert!(json[0].starts_with("Some(2): port: '99999'"), "{}", json[0]);
+    }
+}
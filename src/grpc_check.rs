@@ -0,0 +1,249 @@
+use {
+    crate::config::GrpcCheckConfig,
+    bytes::Bytes,
+    h2::client,
+    http::{Method, Request},
+    std::convert::TryFrom,
+    tokio::{net::TcpStream, spawn},
+};
+
+const CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
+
+/// `grpc.health.v1.HealthCheckResponse.ServingStatus`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ServingStatus {
+    Unknown,
+    Serving,
+    NotServing,
+    ServiceUnknown,
+}
+
+impl ServingStatus {
+    fn from_code(code: u64) -> Self {
+        match code {
+            1 => ServingStatus::Serving,
+            2 => ServingStatus::NotServing,
+            3 => ServingStatus::ServiceUnknown,
+            _ => ServingStatus::Unknown,
+        }
+    }
+}
+
+fn encode_varint(mut value: u64, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn decode_varint(data: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0;
+    for (i, byte) in data.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// A length-prefixed `HealthCheckRequest { string service = 1; }` message.
+pub fn encode_request(service: &str) -> Vec<u8> {
+    let mut message = Vec::new();
+    if !service.is_empty() {
+        message.push(0x0a);
+        encode_varint(service.len() as u64, &mut message);
+        message.extend_from_slice(service.as_bytes());
+    }
+    let mut frame = vec![0];
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&message);
+    frame
+}
+
+/// Reads the status out of a length-prefixed `HealthCheckResponse { ServingStatus status = 1; }`.
+pub fn decode_response(frame: &[u8]) -> Result<ServingStatus, String> {
+    if frame.len() < 5 {
+        return Err(String::from("truncated gRPC message"));
+    }
+    if frame[0] != 0 {
+        return Err(String::from("compressed gRPC messages are not supported"));
+    }
+    let len = u32::from_be_bytes([frame[1], frame[2], frame[3], frame[4]]) as usize;
+    let mut message = frame
+        .get(5..5 + len)
+        .ok_or_else(|| String::from("truncated gRPC message"))?;
+
+    let mut status = ServingStatus::Unknown;
+    while !message.is_empty() {
+        let (key, read) = decode_varint(message).ok_or("malformed message")?;
+        message = &message[read..];
+        let (field, wire_type) = (key >> 3, key & 0x7);
+        let skip = match wire_type {
+            0 => {
+                let (value, read) = decode_varint(message).ok_or("malformed message")?;
+                if field == 1 {
+                    status = ServingStatus::from_code(value);
+                }
+                read
+            }
+            1 => 8,
+            2 => {
+                let (len, read) = decode_varint(message).ok_or("malformed message")?;
+                // The length comes from the backend, and may be anything.
+                usize::try_from(len)
+                    .ok()
+                    .and_then(|len| read.checked_add(len))
+                    .ok_or("malformed message")?
+            }
+            5 => 4,
+            _ => return Err(format!("unsupported wire type {}", wire_type)),
+        };
+        message = message.get(skip..).ok_or("malformed message")?;
+    }
+    Ok(status)
+}
+
+/// Calls `grpc.health.v1.Health/Check` on `ip:port`, succeeding if the service is `SERVING`.
+pub async fn check(config: &GrpcCheckConfig, ip: &str, port: u16) -> Result<String, String> {
+    let addr = format!("{}:{}", ip, port);
+    let stream = TcpStream::connect(&addr)
+        .await
+        .map_err(|e| format!("connect to {} failed: {}", addr, e))?;
+    let (client, connection) = client::handshake(stream)
+        .await
+        .map_err(|e| format!("HTTP/2 handshake failed: {}", e))?;
+    spawn(async move {
+        let _ = connection.await;
+    });
+
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://{}{}", addr, CHECK_PATH))
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(())
+        .map_err(|e| e.to_string())?;
+    let mut client = client.ready().await.map_err(|e| e.to_string())?;
+    let (response, mut body) = client
+        .send_request(request, false)
+        .map_err(|e| e.to_string())?;
+    body.send_data(Bytes::from(encode_request(&config.service)), true)
+        .map_err(|e| e.to_string())?;
+
+    let response = response.await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("HTTP status {}", response.status()));
+    }
+    let mut body = response.into_body();
+    let mut frame = Vec::new();
+    while let Some(data) = body.data().await {
+        let data = data.map_err(|e| e.to_string())?;
+        let _ = body.flow_control().release_capacity(data.len());
+        frame.extend_from_slice(&data);
+    }
+    let trailers = body.trailers().await.map_err(|e| e.to_string())?;
+    let grpc_status = trailers
+        .as_ref()
+        .and_then(|trailers| trailers.get("grpc-status"))
+        .and_then(|status| status.to_str().ok())
+        .unwrap_or("0");
+    if grpc_status != "0" {
+        return Err(format!("grpc-status {}", grpc_status));
+    }
+
+    match decode_response(&frame)? {
+        ServingStatus::Serving => Ok(String::from("SERVING")),
+        status => Err(format!("{:?}", status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        h2::server,
+        http::{HeaderMap, Response},
+        tokio::net::TcpListener,
+    };
+
+    fn response(status: u64) -> Vec<u8> {
+        vec![0, 0, 0, 0, 2, 0x08, status as u8]
+    }
+
+    /// A stand-in gRPC server, which is serving `ready` and not serving anything else.
+    async fn server() -> u16 {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                spawn(async move {
+                    let mut connection = server::handshake(stream).await.unwrap();
+                    while let Some(Ok((request, mut respond))) = connection.accept().await {
+                        let mut body = request.into_body();
+                        let mut message = Vec::new();
+                        while let Some(Ok(data)) = body.data().await {
+                            message.extend_from_slice(&data);
+                        }
+                        let status = if message == encode_request("ready") {
+                            1
+                        } else {
+                            2
+                        };
+                        let mut stream = respond.send_response(Response::new(()), false).unwrap();
+                        stream.send_data(response(status).into(), false).unwrap();
+                        let mut trailers = HeaderMap::new();
+                        trailers.insert("grpc-status", "0".parse().unwrap());
+                        stream.send_trailers(trailers).unwrap();
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn test_encode_request() {
+        assert_eq!(encode_request(""), vec![0, 0, 0, 0, 0]);
+        assert_eq!(
+            encode_request("db"),
+            vec![0, 0, 0, 0, 4, 0x0a, 2, b'd', b'b']
+        );
+    }
+
+    #[test]
+    fn test_decode_response() {
+        assert_eq!(decode_response(&response(1)), Ok(ServingStatus::Serving));
+        assert_eq!(decode_response(&response(2)), Ok(ServingStatus::NotServing));
+        assert_eq!(
+            decode_response(&[0, 0, 0, 0, 0]),
+            Ok(ServingStatus::Unknown)
+        );
+        // A length-delimited field as long as a varint can say.
+        let mut huge = vec![0, 0, 0, 0, 12, 0x12];
+        huge.extend_from_slice(&[0xff; 9]);
+        huge.extend_from_slice(&[0x01, 0]);
+        assert_eq!(
+            decode_response(&huge),
+            Err(String::from("malformed message"))
+        );
+        assert!(decode_response(&[0, 0, 0, 0, 9, 0x08]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let port = server().await;
+        let check = |service: &str| GrpcCheckConfig {
+            service: service.to_string(),
+        };
+        assert_eq!(
+            super::check(&check("ready"), "127.0.0.1", port).await,
+            Ok(String::from("SERVING"))
+        );
+        assert_eq!(
+            super::check(&check("starting"), "127.0.0.1", port).await,
+            Err(String::from("NotServing"))
+        );
+    }
+}
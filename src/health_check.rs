@@ -1,49 +1,202 @@
 use {
-    crate::{config::Config, with_read_lock, Threadable},
+    crate::{
+        command_check,
+        config::{
+            BackendConfig, CommandCheckConfig, Config, GrpcCheckConfig, HealthCheckConfig,
+            ProbeConfig,
+        },
+        grpc_check,
+        pool::{Pool, Probe},
+        tcp_check::Script,
+        with_read_lock, Threadable,
+    },
+    log::{error, warn},
+    rand::Rng,
     std::{
         net::Shutdown,
-        time::{Duration, Instant},
+        sync::Arc,
+        time::{Duration, Instant, SystemTime},
     },
     tokio::{
-        io::ErrorKind,
         net::TcpStream,
         spawn,
         time::{delay_for, timeout},
     },
 };
 
-pub async fn run(config: Threadable<Config>) -> Result<(), Box<dyn std::error::Error>> {
-    let (limit, interval, servers) = with_read_lock(config, |config| {
-        (
-            Duration::from_secs(config.health_check.timeout),
-            config.health_check.interval,
-            config.backends.clone(),
-        )
-    });
+/// Time until the next probe, spread by up to `jitter_percent` of the interval either way.
+fn next_delay(config: &HealthCheckConfig, transitioning: bool, jitter: f64) -> Duration {
+    let interval = match config.transition_interval {
+        Some(interval) if transitioning => interval,
+        _ => config.interval,
+    };
+    let spread = config.jitter_percent.clamp(0.0, 100.0) / 100.0 * jitter.clamp(-1.0, 1.0);
+    interval.mul_f64(1.0 + spread)
+}
 
-    for (_, server) in servers.into_iter() {
-        spawn(async move {
-            loop {
-                let (start, interval, stream) = (
-                    Instant::now(),
-                    Duration::from_millis(interval * 1000),
-                    TcpStream::connect(format!("{}:{}", server.ip, server.port)),
-                );
-                match timeout(limit, stream).await.unwrap() {
-                    Ok(ref stream) => {
-                        if let Err(e) = stream.shutdown(Shutdown::Both) {
-                            eprintln!("Error shutting down stream: {}", e);
-                        }
+/// A probe prepared for a backend.
+enum Checker {
+    Connect,
+    Tcp(Script),
+    Grpc(GrpcCheckConfig),
+    Command(CommandCheckConfig),
+}
+
+impl Checker {
+    fn new(config: &ProbeConfig) -> Result<Self, String> {
+        Ok(match *config {
+            ProbeConfig::Connect => Checker::Connect,
+            ProbeConfig::Tcp(ref check) => Checker::Tcp(Script::new(check)?),
+            ProbeConfig::Grpc(ref check) => Checker::Grpc(check.clone()),
+            ProbeConfig::Command(ref check) => Checker::Command(check.clone()),
+        })
+    }
+
+    async fn check(&self, server: &BackendConfig, limit: Duration) -> Result<String, String> {
+        match *self {
+            Checker::Connect => {
+                let addr = format!("{}:{}", server.ip, server.port);
+                let stream = TcpStream::connect(&addr).await.map_err(|e| e.to_string())?;
+                if let Err(e) = stream.shutdown(Shutdown::Both) {
+                    warn!("Error shutting down stream: {}", e);
+                }
+                Ok(String::from("connected"))
+            }
+            Checker::Tcp(ref script) => script.run(&server.ip, server.port).await,
+            Checker::Grpc(ref check) => grpc_check::check(check, &server.ip, server.port).await,
+            Checker::Command(ref check) => command_check::check(check, server, limit).await,
+        }
+    }
+
+    /// How long a probe may take, given the health check's `timeout`. Command checks may set
+    /// their own.
+    fn limit(&self, timeout: Duration) -> Duration {
+        match *self {
+            Checker::Command(ref check) => check.timeout.unwrap_or(timeout),
+            _ => timeout,
+        }
+    }
+
+    async fn probe(&self, server: &BackendConfig, limit: Duration) -> Probe {
+        let (at, start) = (SystemTime::now(), Instant::now());
+        let limit = self.limit(limit);
+        let result = timeout(limit, self.check(server, limit))
+            .await
+            .unwrap_or_else(|_| Err(format!("timed out after {}ms", limit.as_millis())));
+        let (healthy, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(detail) => (false, detail),
+        };
+        Probe {
+            at,
+            healthy,
+            latency: start.elapsed(),
+            detail,
+        }
+    }
+}
+
+/// Probes `server` for as long as it stays in the pool. The health check settings are read
+/// before every probe, so that a reloaded config takes effect without restarting the task.
+pub fn start(pool: Arc<Pool>, server: BackendConfig) {
+    spawn(async move {
+        let since = pool.joined(&server);
+        let offset = rand::thread_rng().gen_range(0.0, 1.0);
+        delay_for(pool.health_check().interval.mul_f64(offset)).await;
+        let (mut probe_config, mut checker) = (None, Checker::Connect);
+        loop {
+            let server = match pool.current(&server) {
+                Some(server) if pool.joined(&server) == since => server,
+                _ => return,
+            };
+            let config = pool.health_check();
+            if probe_config.as_ref() != Some(&config.probe) {
+                checker = match Checker::new(&config.probe) {
+                    Ok(checker) => checker,
+                    Err(e) => {
+                        error!("Invalid health check for {}: {}.", server.authority(), e);
+                        delay_for(config.interval).await;
+                        continue;
                     }
-                    Err(ref e) if e.kind() == ErrorKind::TimedOut => {}
-                    Err(ref e) => eprintln!("Error sending health check: {}.", e),
                 };
-                let elapsed = start.elapsed();
-                if elapsed < interval {
-                    delay_for(interval - elapsed).await;
-                }
+                probe_config = Some(config.probe.clone());
             }
-        });
+
+            let start = Instant::now();
+            let probe = checker.probe(&server, config.timeout).await;
+            if !probe.healthy {
+                warn!(
+                    "Health check of {} failed: {}.",
+                    server.authority(),
+                    probe.detail
+                );
+            }
+            pool.report_health(&server, probe);
+
+            let jitter = rand::thread_rng().gen_range(-1.0, 1.0);
+            let interval = next_delay(&config, pool.transitioning(&server), jitter);
+            let elapsed = start.elapsed();
+            if elapsed < interval {
+                delay_for(interval - elapsed).await;
+            }
+        }
+    });
+}
+
+pub async fn run(
+    config: Threadable<Config>,
+    pool: Arc<Pool>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let servers = with_read_lock(config, |config| config.backends.clone());
+    for (_, server) in servers.into_iter() {
+        start(pool.clone(), server);
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_delay() {
+        let config = HealthCheckConfig {
+            interval: Duration::from_secs(10),
+            jitter_percent: 20.0,
+            transition_interval: Some(Duration::from_secs(1)),
+            ..HealthCheckConfig::default()
+        };
+        assert_eq!(next_delay(&config, false, 0.0), Duration::from_secs(10));
+        assert_eq!(next_delay(&config, false, -1.0), Duration::from_secs(8));
+        assert_eq!(next_delay(&config, true, 1.0), Duration::from_millis(1200));
+    }
+
+    #[tokio::test]
+    async fn test_command_timeout_overrides() {
+        let checker = Checker::Command(CommandCheckConfig {
+            path: String::from("sh"),
+            args: vec![String::from("-c"), String::from("sleep 0.2; echo ok")],
+            timeout: Some(Duration::from_secs(5)),
+        });
+        let probe = checker
+            .probe(&BackendConfig::default(), Duration::from_millis(50))
+            .await;
+        assert!(probe.healthy, "{}", probe.detail);
+        assert_eq!(probe.detail, "ok");
+
+        let checker = Checker::Command(CommandCheckConfig {
+            path: String::from("sh"),
+            args: vec![String::from("-c"), String::from("sleep 5")],
+            timeout: None,
+        });
+        let probe = checker
+            .probe(&BackendConfig::default(), Duration::from_millis(50))
+            .await;
+        assert!(!probe.healthy);
+        assert!(
+            probe.detail.contains("timed out after 50ms"),
+            "{}",
+            probe.detail
+        );
+    }
+}
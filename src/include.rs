@@ -0,0 +1,293 @@
+use {
+    crate::{
+        config::{BackendConfig, Config, RouteConfig, StrategyMapping},
+        format::Format,
+        interpolate::interpolate,
+        validate::{self, ConfigError},
+    },
+    serde::Deserialize,
+    serde_json::Value,
+    std::{
+        collections::{hash_map::Entry, HashMap, HashSet},
+        env::var,
+        fs::{canonicalize, read_to_string},
+        path::{Path, PathBuf},
+    },
+};
+
+/// What an included file may define.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct Fragment {
+    backends: HashMap<String, BackendConfig>,
+    routes: HashMap<String, RouteConfig>,
+    mappings: HashMap<String, StrategyMapping>,
+}
+
+/// Which included file each merged backend, route and mapping was defined in.
+#[derive(Debug, Default)]
+pub struct Origins {
+    sources: HashMap<PathBuf, (Format, String)>,
+    /// Keyed by section and name, e.g. `("backends", "api")`.
+    defined: HashMap<(String, String), PathBuf>,
+}
+
+impl Origins {
+    /// Points `error` at the included file which defined what it's about, if there is one.
+    pub fn locate(&self, error: &mut ConfigError) {
+        if let [section, name, ..] = error.key.as_slice() {
+            if let Some(file) = self.defined.get(&(section.clone(), name.clone())) {
+                let (format, ref source) = self.sources[file];
+                error.line = format.line_of(source, &error.key);
+                error.file = Some(file.clone());
+            }
+        }
+    }
+
+    /// Where the section's entry with the given name was first defined.
+    fn first(&self, section: &str, name: &str, main: &Path) -> String {
+        self.defined
+            .get(&(section.to_string(), name.to_string()))
+            .map_or(main, PathBuf::as_path)
+            .display()
+            .to_string()
+    }
+}
+
+/// The files matching `patterns`, in the order the patterns are listed and each pattern's matches
+/// in path order. Files which were already matched, and `main` itself, are left out.
+fn resolve(main: &Path, patterns: &[String]) -> (Vec<PathBuf>, Vec<ConfigError>) {
+    let dir = main.parent().unwrap_or_else(|| Path::new("."));
+    let canonical = |path: &Path| canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut seen = HashSet::new();
+    seen.insert(canonical(main));
+    let (mut files, mut errors) = (Vec::new(), Vec::new());
+    for (i, pattern) in patterns.iter().enumerate() {
+        let key = ["include", &i.to_string()];
+        let paths = match glob::glob(&dir.join(pattern).to_string_lossy()) {
+            Ok(paths) => paths,
+            Err(e) => {
+                errors.push(ConfigError::new(
+                    &key,
+                    format!("'{}' is not a valid pattern: {}", pattern, e),
+                ));
+                continue;
+            }
+        };
+        let mut matched = paths
+            .filter_map(Result::ok)
+            .filter(|path| path.is_file())
+            .collect::<Vec<_>>();
+        matched.sort();
+        // An empty directory is fine, but a missing file is likely a mistake.
+        if matched.is_empty() && !pattern.contains(&['*', '?', '['][..]) {
+            errors.push(ConfigError::new(
+                &key,
+                format!("'{}' does not exist", pattern),
+            ));
+        }
+        for path in matched {
+            if seen.insert(canonical(&path)) {
+                files.push(path);
+            }
+        }
+    }
+    (files, errors)
+}
+
+/// Moves the entries of a section of an included file into the config's. A name which is already
+/// defined is an error, and its first definition is kept.
+fn add<T>(
+    section: &str,
+    entries: HashMap<String, T>,
+    into: &mut HashMap<String, T>,
+    (file, format, source, main): (&Path, Format, &str, &Path),
+    origins: &mut Origins,
+    errors: &mut Vec<ConfigError>,
+) {
+    let mut entries = entries.into_iter().collect::<Vec<_>>();
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (name, entry) in entries {
+        match into.entry(name) {
+            Entry::Occupied(occupied) => {
+                let name = occupied.key();
+                let mut error = ConfigError::new(
+                    &[section, name],
+                    format!(
+                        "is already defined in '{}'",
+                        origins.first(section, name, main)
+                    ),
+                );
+                error.line = format.line_of(source, &error.key);
+                error.file = Some(file.to_path_buf());
+                errors.push(error);
+            }
+            Entry::Vacant(vacant) => {
+                origins.defined.insert(
+                    (section.to_string(), vacant.key().clone()),
+                    file.to_path_buf(),
+                );
+                vacant.insert(entry);
+            }
+        }
+    }
+}
+
+/// Merges the backends, routes and mappings of the files the config at `main` includes into it.
+/// Returns the problems found in the included files, and where what they define came from.
+pub fn merge(config: &mut Config, main: &Path) -> (Vec<ConfigError>, Origins) {
+    let (files, mut errors) = resolve(main, &config.include);
+    let mut origins = Origins::default();
+    for file in files {
+        let dir = file.parent().unwrap_or_else(|| Path::new("."));
+        let format = Format::of(&file);
+        let source = read_to_string(&file)
+            .map_err(|e| vec![ConfigError::new(&[], format!("could not be read: {}", e))])
+            .and_then(|contents| interpolate(&contents, format, dir, &|name| var(name).ok()));
+        let (fragment, mut file_errors) = match source {
+            Ok(source) => {
+                let (fragment, mut file_errors) = format.deserialize::<Fragment>(&source);
+                if let (Some(value), _) = format.deserialize::<Value>(&source) {
+                    file_errors.extend(validate::check_backend_values(&value));
+                }
+                format.locate(&mut file_errors, &source);
+                (fragment.map(|fragment| (fragment, source)), file_errors)
+            }
+            Err(file_errors) => (None, file_errors),
+        };
+        for error in file_errors.iter_mut() {
+            error.file = Some(file.clone());
+        }
+        errors.append(&mut file_errors);
+
+        if let Some((fragment, source)) = fragment {
+            let at = (file.as_path(), format, source.as_str(), main);
+            add(
+                "backends",
+                fragment.backends,
+                &mut config.backends,
+                at,
+                &mut origins,
+                &mut errors,
+            );
+            add(
+                "routes",
+                fragment.routes,
+                &mut config.routes,
+                at,
+                &mut origins,
+                &mut errors,
+            );
+            add(
+                "mappings",
+                fragment.mappings,
+                &mut config.mappings,
+                at,
+                &mut origins,
+                &mut errors,
+            );
+            origins.sources.insert(file.clone(), (format, source));
+        }
+        config.included.push(file);
+    }
+    (errors, origins)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::{
+            env::temp_dir,
+            fs::{create_dir_all, remove_dir_all, write},
+        },
+    };
+
+    /// Writes the files to a new directory and parses its `main.toml`.
+    fn parse(name: &str, files: &[(&str, &str)]) -> Result<Config, String> {
+        let dir = temp_dir().join(format!("loblaw-include-{}-{}", name, std::process::id()));
+        for (path, contents) in files.iter() {
+            let path = dir.join(path);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            write(path, contents).unwrap();
+        }
+        let config = Config::parse(&dir.join("main.toml"), Format::Toml)
+            .map_err(|e| e.to_string().replace(&format!("{}/", dir.display()), ""));
+        let _ = remove_dir_all(&dir);
+        config
+    }
+
+    #[test]
+    fn test_merges_in_order() {
+        let config = parse(
+            "merge",
+            &[
+                (
+                    "main.toml",
+                    "include = [\"conf.d/*.toml\", \"extra.toml\"]\n[backends.main]\nport = 1\n",
+                ),
+                ("conf.d/b.toml", "[backends.b]\nport = 3\n"),
+                (
+                    "conf.d/a.toml",
+                    "[backends.a]\nport = 2\n[routes.a]\npath = \"/a\"\n[mappings.a]\npath = \"/a\"\n",
+                ),
+                ("conf.d/notes.txt", "not toml"),
+                ("extra.toml", "[backends.extra]\nport = 4\n"),
+            ],
+        )
+        .unwrap();
+        let mut ports = config
+            .backends
+            .iter()
+            .map(|(name, backend)| (name.as_str(), backend.port))
+            .collect::<Vec<_>>();
+        ports.sort();
+        assert_eq!(ports, vec![("a", 2), ("b", 3), ("extra", 4), ("main", 1)]);
+        assert_eq!(config.routes["a"].path, "/a");
+        let included = config
+            .included
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(included, vec!["a.toml", "b.toml", "extra.toml"]);
+    }
+
+    #[test]
+    fn test_reports_duplicates_and_errors() {
+        let errors = parse(
+            "duplicates",
+            &[
+                (
+                    "main.toml",
+                    "include = [\"conf.d/*.toml\", \"missing.toml\"]\n[backends.a]\nport = 1\n",
+                ),
+                (
+                    "conf.d/1.toml",
+                    "[backends.b]\nport = 2\n[routes.r]\npath = \"/\"\n",
+                ),
+                (
+                    "conf.d/2.toml",
+                    "\n[backends.a]\nport = 3\n[backends.b]\nport = 4\n[routes.r]\npath = \"/r\"\n",
+                ),
+                (
+                    "conf.d/3.toml",
+                    "port = 80\n[backends.c]\nip = \"\"\nport = \"http\"\n",
+                ),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(
+            errors.lines().skip(1).collect::<Vec<_>>(),
+            vec![
+                "main.toml:1: include.1: 'missing.toml' does not exist",
+                "conf.d/2.toml:2: backends.a: is already defined in 'main.toml'",
+                "conf.d/2.toml:4: backends.b: is already defined in 'conf.d/1.toml'",
+                "conf.d/2.toml:6: routes.r: is already defined in 'conf.d/1.toml'",
+                "conf.d/3.toml:1: port: unknown key",
+                "conf.d/3.toml:3: backends.c.ip: is empty",
+                "conf.d/3.toml:4: backends.c.port: 'http' is not a valid port: invalid digit \
+                 found in string",
+            ]
+        );
+    }
+}
@@ -0,0 +1,377 @@
+use {
+    crate::{format::Format, validate::ConfigError},
+    std::{fs::read_to_string, path::Path},
+};
+
+/// Prefix of a string whose value is read from a file, e.g. `"@file:/run/secrets/token"`.
+const FILE_PREFIX: &str = "@file:";
+
+/// Expands `${VAR}` and `${VAR:-default}` anywhere in `source`, and replaces strings of the form
+/// `"@file:/path"` with the contents of the file. Values substituted into strings are quoted, so
+/// they may contain quotes, backslashes and newlines, while values substituted outside strings
+/// must not contain any character which could end the value, see `bare`. `$${` stands for a
+/// literal `${`, and comments are left as they are. Relative paths are resolved against `dir`.
+pub fn interpolate(
+    source: &str,
+    format: Format,
+    dir: &Path,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<String, Vec<ConfigError>> {
+    let quotes: &[&str] = match format {
+        Format::Toml => &["\"\"\"", "'''", "\"", "'"],
+        Format::Yaml => &["\"", "'"],
+        Format::Json => &["\""],
+    };
+    let mut interpolator = Interpolator {
+        format,
+        dir,
+        env,
+        line: 1,
+        errors: Vec::new(),
+    };
+    let mut out = String::with_capacity(source.len());
+    let mut rest = source;
+    while !rest.is_empty() {
+        // Quotes and hashes only start strings and comments where a value or a new token could,
+        // so that e.g. apostrophes in unquoted YAML strings are left alone.
+        let after = out.trim_end_matches([' ', '\t']).chars().last();
+        let string_start = after.is_none_or(|c| "\n=:-[{,?".contains(c));
+        let token_start = out.chars().last().is_none_or(char::is_whitespace);
+        let consumed = if rest.starts_with('#') && token_start && format != Format::Json {
+            let end = rest.find('\n').unwrap_or(rest.len());
+            out.push_str(&rest[..end]);
+            end
+        } else if let Some(quote) = quotes
+            .iter()
+            .find(|quote| string_start && rest.starts_with(**quote))
+        {
+            match string_len(rest, quote, format == Format::Yaml) {
+                Some(len) => {
+                    let content = &rest[quote.len()..len - quote.len()];
+                    out.push_str(&interpolator.string(content, quote));
+                    len
+                }
+                // Unterminated, which deserializing reports.
+                None => {
+                    out.push_str(rest);
+                    rest.len()
+                }
+            }
+        } else {
+            let end = rest
+                .char_indices()
+                .skip(1)
+                .find(|(_, c)| "#\"'".contains(*c))
+                .map_or(rest.len(), |(end, _)| end);
+            out.push_str(&interpolator.expand(&rest[..end], &bare));
+            end
+        };
+        interpolator.line += rest[..consumed].matches('\n').count();
+        rest = &rest[consumed..];
+    }
+    if interpolator.errors.is_empty() {
+        Ok(out)
+    } else {
+        Err(interpolator.errors)
+    }
+}
+
+/// The length of the string which `source` starts with, including its quotes. In YAML, a single
+/// quote is escaped by doubling it.
+fn string_len(source: &str, quote: &str, yaml: bool) -> Option<usize> {
+    let basic = quote.starts_with('"');
+    let mut chars = source.char_indices().skip(quote.len());
+    while let Some((i, c)) = chars.next() {
+        let escaped = if basic {
+            c == '\\'
+        } else {
+            yaml && source[i..].starts_with("''")
+        };
+        if escaped {
+            chars.next();
+        } else if source[i..].starts_with(quote) {
+            return Some(i + quote.len());
+        }
+    }
+    None
+}
+
+/// Escapes `value` for a double-quoted string, which TOML, YAML and JSON escape alike. Newlines
+/// are kept in multi-line strings.
+fn escape(value: &str, multiline: bool) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' if multiline => escaped.push('\n'),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `value` for substituting outside a string, or the first character which could end the value
+/// and change the structure of the config around it, such as a newline or a closing bracket.
+fn bare(value: &str) -> Result<String, char> {
+    match value
+        .chars()
+        .find(|c| c.is_control() || "[]{},#\"'".contains(*c))
+    {
+        Some(c) => Err(c),
+        None => Ok(value.to_string()),
+    }
+}
+
+struct Interpolator<'a> {
+    format: Format,
+    dir: &'a Path,
+    env: &'a dyn Fn(&str) -> Option<String>,
+    /// Line on which the text being expanded starts.
+    line: usize,
+    errors: Vec<ConfigError>,
+}
+
+impl Interpolator<'_> {
+    fn error(&mut self, line: usize, message: String) {
+        self.errors.push(ConfigError {
+            key: Vec::new(),
+            line: Some(line),
+            message,
+            file: None,
+        });
+    }
+
+    /// The string with the given content, with its references expanded. Literal strings which
+    /// need expanding become basic strings, since substituted values can't be escaped in them.
+    fn string(&mut self, content: &str, quote: &str) -> String {
+        let basic = quote.starts_with('"');
+        let multiline = quote.len() == 3;
+        if let (false, Some(path)) = (multiline, content.strip_prefix(FILE_PREFIX)) {
+            let path = self.expand(path, &|value| Ok(value.to_string()));
+            return format!("\"{}\"", escape(&self.file(&path), false));
+        }
+        if !content.contains("${") {
+            format!("{}{}{}", quote, content, quote)
+        } else if basic {
+            let content = self.expand(content, &|value| Ok(escape(value, multiline)));
+            format!("{}{}{}", quote, content, quote)
+        } else {
+            let quote = if multiline { "\"\"\"" } else { "\"" };
+            let content = match self.format {
+                Format::Yaml => content.replace("''", "'"),
+                _ => content.to_string(),
+            };
+            let content = self.expand(&content, &|value| Ok(value.to_string()));
+            format!("{}{}{}", quote, escape(&content, multiline), quote)
+        }
+    }
+
+    /// Expands the references in `text`, passing each value through `quote`, which may reject it
+    /// for the character it can't contain.
+    fn expand(&mut self, mut text: &str, quote: &dyn Fn(&str) -> Result<String, char>) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut line = self.line;
+        while let Some(start) = text.find('$') {
+            out.push_str(&text[..start]);
+            line += text[..start].matches('\n').count();
+            text = &text[start..];
+            if let Some(after) = text.strip_prefix("$${") {
+                out.push_str("${");
+                text = after;
+            } else if text.starts_with("${") {
+                let (value, len) = self.variable(text, line);
+                match quote(&value) {
+                    Ok(value) => out.push_str(&value),
+                    Err(c) => self.error(
+                        line,
+                        format!(
+                            "'{}' can only be used in a quoted string, since its value contains {:?}",
+                            &text[..len],
+                            c
+                        ),
+                    ),
+                }
+                text = &text[len..];
+            } else {
+                out.push('$');
+                text = &text[1..];
+            }
+        }
+        out.push_str(text);
+        out
+    }
+
+    /// The value of the reference which `text` starts with, and the reference's length.
+    fn variable(&mut self, text: &str, line: usize) -> (String, usize) {
+        let end = match text.find('}') {
+            Some(end) => end,
+            None => {
+                self.error(line, String::from("'${' is never closed"));
+                return (text.to_string(), text.len());
+            }
+        };
+        let reference = &text[2..end];
+        let (name, default) = match reference.find(":-") {
+            Some(i) => (&reference[..i], Some(&reference[i + 2..])),
+            None => (reference, None),
+        };
+        let valid = name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && name.chars().next().is_some_and(|c| !c.is_ascii_digit());
+        if !valid {
+            self.error(
+                line,
+                format!("'{}' is not a valid environment variable name", name),
+            );
+            return (String::new(), end + 1);
+        }
+        let value = match ((self.env)(name), default) {
+            (Some(value), Some(default)) if value.is_empty() => default.to_string(),
+            (Some(value), _) => value,
+            (None, Some(default)) => default.to_string(),
+            (None, None) => {
+                self.error(line, format!("environment variable '{}' is not set", name));
+                String::new()
+            }
+        };
+        (value, end + 1)
+    }
+
+    /// The contents of the file at `path`, without a trailing newline.
+    fn file(&mut self, path: &str) -> String {
+        match read_to_string(self.dir.join(path)) {
+            Ok(contents) => {
+                let contents = contents.strip_suffix('\n').unwrap_or(&contents);
+                contents.strip_suffix('\r').unwrap_or(contents).to_string()
+            }
+            Err(e) => {
+                let line = self.line;
+                self.error(line, format!("could not read '{}': {}", path, e));
+                String::new()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::{collections::HashMap, env::temp_dir, fs::write},
+    };
+
+    fn expand_as(source: &str, format: Format) -> Result<String, Vec<String>> {
+        let env = [
+            ("PORT", "8080"),
+            ("EMPTY", ""),
+            ("QUOTED", "a \"b\" \\c"),
+            ("LINES", "1\nb = 2"),
+            ("LIST", "1], b = [2"),
+        ]
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect::<HashMap<_, _>>();
+        interpolate(source, format, &temp_dir(), &|name| env.get(name).cloned()).map_err(|errors| {
+            errors
+                .iter()
+                .map(|error| format!("{:?}: {}", error.line, error))
+                .collect()
+        })
+    }
+
+    fn expand(source: &str) -> Result<String, Vec<String>> {
+        expand_as(source, Format::Toml)
+    }
+
+    #[test]
+    fn test_variables() {
+        assert_eq!(expand("port = ${PORT}").unwrap(), "port = 8080");
+        assert_eq!(
+            expand("a = \"${PORT:-1}/${MISSING:-x y}/${EMPTY:-z}\"").unwrap(),
+            "a = \"8080/x y/z\""
+        );
+        assert_eq!(expand("a = \"${QUOTED}\"").unwrap(), r#"a = "a \"b\" \\c""#);
+        assert_eq!(
+            expand("a = '${QUOTED} \\'").unwrap(),
+            r#"a = "a \"b\" \\c \\""#
+        );
+        assert_eq!(
+            expand("a = \"$${PORT} $5\" # ${MISSING}").unwrap(),
+            "a = \"${PORT} $5\" # ${MISSING}"
+        );
+    }
+
+    #[test]
+    fn test_yaml_and_json() {
+        assert_eq!(
+            expand_as(
+                "# it's ${MISSING}\nport: ${PORT}\npath: it's ${PORT}\na: 'it''s ${QUOTED}'\nb: a#${PORT}",
+                Format::Yaml
+            )
+            .unwrap(),
+            "# it's ${MISSING}\nport: 8080\npath: it's 8080\na: \"it's a \\\"b\\\" \\\\c\"\nb: a#8080"
+        );
+        assert_eq!(
+            expand_as("{\"a\": \"#${QUOTED}\", \"port\": ${PORT}}", Format::Json).unwrap(),
+            r##"{"a": "#a \"b\" \\c", "port": 8080}"##
+        );
+    }
+
+    #[test]
+    fn test_errors_name_the_variable() {
+        assert_eq!(
+            expand("a = 1\nb = \"${MISSING}\"\nc = ${1X}\nd = \"${PORT\"").unwrap_err(),
+            vec![
+                "Some(2): environment variable 'MISSING' is not set",
+                "Some(3): '1X' is not a valid environment variable name",
+                "Some(4): '${' is never closed",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bare_values_cannot_change_the_structure() {
+        assert_eq!(
+            expand("a = ${LINES}
+b = [${LIST}]
+c = \"${LINES}\"").unwrap_err(),
+            vec![
+                "Some(1): '${LINES}' can only be used in a quoted string, since its value contains '\\n'",
+                "Some(2): '${LIST}' can only be used in a quoted string, since its value contains ']'",
+            ]
+        );
+        assert_eq!(
+            expand_as("a: ${LIST}", Format::Yaml).unwrap_err(),
+            vec!["Some(1): '${LIST}' can only be used in a quoted string, since its value contains ']'"]
+        );
+        assert_eq!(expand("a = \"${LINES}\"").unwrap(), "a = \"1\\nb = 2\"");
+    }
+
+    #[test]
+    fn test_files() {
+        let name = format!("loblaw-secret-{}", std::process::id());
+        write(temp_dir().join(&name), "line \"1\"\nline 2\n").unwrap();
+        let source = format!(
+            "[backends.a]\nport = ${{PORT}}\npath = \"@file:{}\"\n",
+            name
+        );
+        let config = expand(&source).unwrap().parse::<toml::Value>().unwrap();
+        assert_eq!(config["backends"]["a"]["port"].as_integer(), Some(8080));
+        assert_eq!(
+            config["backends"]["a"]["path"].as_str(),
+            Some("line \"1\"\nline 2")
+        );
+        let _ = std::fs::remove_file(temp_dir().join(&name));
+
+        let errors = expand("a = \"@file:missing-secret\"").unwrap_err();
+        assert!(
+            errors[0].starts_with("Some(1): could not read 'missing-secret': "),
+            "{}",
+            errors[0]
+        );
+    }
+}
@@ -1,30 +1,63 @@
 #![feature(type_alias_impl_trait, async_closure, bool_to_option)]
 
+pub mod admin;
+pub mod agent;
+pub mod cli;
+pub mod command_check;
+pub mod concurrency;
 pub mod config;
+pub mod dns;
 pub mod dynamic;
 pub mod error;
+pub mod events;
+pub mod file_sd;
+pub mod format;
+pub mod grpc_check;
 pub mod health_check;
+pub mod include;
+pub mod interpolate;
+pub mod metrics;
+pub mod pool;
+pub mod ratelimit;
+pub mod registry;
 pub mod request;
+pub mod retry;
+pub mod route;
+pub mod schema;
+pub mod tcp_check;
 pub mod timed_future;
+pub mod validate;
 pub mod algorithm {
     pub mod algorithm;
     pub mod ip_hash;
+    pub mod least_connections;
     pub mod least_latency;
     pub mod random;
     pub mod round_robin;
     pub mod trie;
     pub mod url_hash;
+    pub mod weighted_round_robin;
 }
 
 use {
+    admin::AdminHandler,
     algorithm::algorithm::{Algorithm, Strategy},
+    cli::{Cli, Command},
     config::*,
+    dynamic::{expand, Reloader},
+    events::Events,
+    format::Format,
+    log::debug,
+    metrics::Metrics,
+    pool::Pool,
     request::*,
     std::{
+        collections::HashMap,
         net::SocketAddr,
-        str::FromStr,
+        path::Path,
         sync::{Arc, RwLock},
     },
+    structopt::StructOpt,
     tokio::try_join,
 };
 
@@ -43,43 +76,137 @@ pub fn with_write_lock<R, T>(data: Threadable<T>, f: impl FnOnce(&mut T) -> R) -
 async fn handle_requests(
     config: Threadable<Config>,
     strategy: Threadable<Strategy>,
+    pool: Arc<Pool>,
+    metrics: Arc<Metrics>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let (ip, port, persistence_type) = with_read_lock(config.clone(), |config| {
-        (
-            config.ip.clone(),
-            config.port.clone(),
-            config.persistence_type.clone(),
-        )
-    });
+    let config = with_read_lock(config, |config| config.clone());
 
-    match format!("{}:{}", ip, port).parse::<SocketAddr>() {
-        Ok(addr) => {
-            let handler = RequestHandler::new(addr, persistence_type, strategy);
-            handler.run().await
-        }
-        Err(e) => panic!("Invalid address due to '{}'.", e),
+    let handler = RequestHandler::new(config.addr(), &config, strategy, pool, metrics);
+    handler.run().await
+}
+
+async fn handle_admin(
+    config: Threadable<Config>,
+    metrics: Arc<Metrics>,
+    pool: Arc<Pool>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let admin = with_read_lock(config, |config| config.admin.clone());
+    let admin = match admin {
+        Some(admin) => admin,
+        None => return Ok(()),
+    };
+
+    AdminHandler::new(admin.addr(), metrics, pool).run().await
+}
+
+fn load(
+    path: &Path,
+    format: Format,
+    listen: Option<SocketAddr>,
+) -> Result<Config, Box<dyn std::error::Error>> {
+    let mut config = Config::parse(path, format)?;
+    if let Some(addr) = listen {
+        config.set_addr(addr);
     }
+    debug!("Loaded '{}': {:#?}", path.display(), config);
+    Ok(config)
 }
 
-fn init() -> Result<(Threadable<Config>, Threadable<Strategy>), Box<dyn std::error::Error>> {
-    let config = Config::parse()?;
-    let mut strategy = Strategy::from_str(config.strategy.as_str()).unwrap();
+/// The config to run from the `declared` one. Backends whose addresses are discovered have none
+/// yet.
+fn init(declared: &Config) -> (Threadable<Config>, Threadable<Strategy>) {
+    let config = expand(declared, &HashMap::new());
+    let mut strategy = config.strategy.clone();
     strategy.configure(&config);
-    Ok((
+    (
         Arc::new(RwLock::new(config)),
         Arc::new(RwLock::new(strategy)),
-    ))
+    )
 }
 
-#[actix_rt::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let (config, strategy) = init()?;
+async fn run(
+    path: &Path,
+    format: Format,
+    listen: Option<SocketAddr>,
+    watch: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let declared = load(path, format, listen)?;
+    let (config, strategy) = init(&declared);
+    let metrics = Arc::new(Metrics::default());
+    let (pool, webhook) = with_read_lock(config.clone(), |config| {
+        let (events, webhook) = Events::new(&config.events, metrics.clone());
+        let pool = Pool::new(config, Arc::new(events), metrics.clone());
+        (Arc::new(pool), webhook)
+    });
+    let reloader = Reloader::new(
+        path.to_path_buf(),
+        format,
+        listen,
+        declared,
+        config.clone(),
+        strategy.clone(),
+        pool.clone(),
+        metrics.clone(),
+    );
     if let Err(e) = try_join!(
-        handle_requests(config.clone(), strategy.clone()),
-        health_check::run(config.clone())
+        handle_requests(
+            config.clone(),
+            strategy.clone(),
+            pool.clone(),
+            metrics.clone()
+        ),
+        handle_admin(config.clone(), metrics.clone(), pool.clone()),
+        health_check::run(config.clone(), pool.clone()),
+        agent::run(config.clone(), pool.clone()),
+        events::run(webhook),
+        dns::run(&reloader, metrics.clone()),
+        file_sd::run(&reloader, metrics.clone()),
+        registry::run(&reloader, metrics.clone()),
+        reloader.run(watch)
     ) {
         panic!("Error running server: {}.", e);
     }
 
     Ok(())
 }
+
+/// Parses the config at `path` and prints the resolved settings.
+fn check(path: &Path, format: Format) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::parse(path, format)?;
+    config.print();
+    Ok(())
+}
+
+#[actix_rt::main]
+async fn main() {
+    let cli = Cli::from_args();
+    env_logger::Builder::new()
+        .filter_level(cli.log_level)
+        .init();
+
+    let result = match cli.command() {
+        Command::Run {
+            config,
+            format,
+            listen,
+            watch,
+        } => {
+            let format = format.unwrap_or_else(|| Format::of(&config));
+            run(&config, format, listen, watch).await
+        }
+        Command::Check { path, format } => {
+            check(&path, format.unwrap_or_else(|| Format::of(&path)))
+        }
+        Command::Schema => serde_json::to_string_pretty(&schema::schema())
+            .map(|schema| println!("{}", schema))
+            .map_err(Into::into),
+        Command::Version => {
+            println!("loblaw {}", env!("CARGO_PKG_VERSION"));
+            Ok(())
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
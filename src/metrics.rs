@@ -0,0 +1,129 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Write,
+    sync::{Mutex, RwLock},
+    time::Duration,
+};
+
+/// Number of latencies kept for computing percentiles.
+const LATENCY_WINDOW_SIZE: usize = 1024;
+
+/// Named counters describing what the load balancer has been doing.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    counters: RwLock<BTreeMap<String, u64>>,
+}
+
+impl Metrics {
+    pub fn incr(&self, name: &str) {
+        self.add(name, 1);
+    }
+
+    pub fn add(&self, name: &str, value: u64) {
+        let mut counters = self.counters.write().expect("Could not lock mutex.");
+        *counters.entry(name.to_string()).or_insert(0) += value;
+    }
+
+    /// Sets a metric which describes a current value rather than counting events.
+    pub fn set(&self, name: &str, value: u64) {
+        let mut counters = self.counters.write().expect("Could not lock mutex.");
+        counters.insert(name.to_string(), value);
+    }
+
+    pub fn get(&self, name: &str) -> u64 {
+        let counters = self.counters.read().expect("Could not lock mutex.");
+        counters.get(name).copied().unwrap_or(0)
+    }
+
+    /// Renders every counter on its own line as `<name> <value>`.
+    pub fn render(&self) -> String {
+        let counters = self.counters.read().expect("Could not lock mutex.");
+        counters
+            .iter()
+            .fold(String::new(), |mut out, (name, value)| {
+                let _ = writeln!(out, "{} {}", name, value);
+                out
+            })
+    }
+}
+
+/// The most recently observed latencies, used to estimate percentiles.
+#[derive(Debug, Default)]
+pub struct LatencyWindow {
+    samples: Mutex<VecDeque<Duration>>,
+}
+
+impl LatencyWindow {
+    pub fn record(&self, latency: Duration) {
+        let mut samples = self.samples.lock().expect("Could not lock mutex.");
+        if samples.len() == LATENCY_WINDOW_SIZE {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.lock().expect("Could not lock mutex.").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The latency below which `percentile` percent of the samples fall.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let mut samples = self
+            .samples
+            .lock()
+            .expect("Could not lock mutex.")
+            .iter()
+            .copied()
+            .collect::<Vec<_>>();
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort();
+        let rank = (percentile.clamp(0.0, 100.0) / 100.0 * (samples.len() - 1) as f64).round();
+        samples.get(rank as usize).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters() {
+        let metrics = Metrics::default();
+        metrics.incr("hedge.sent");
+        metrics.incr("hedge.sent");
+        metrics.add("retry.attempts", 3);
+
+        assert_eq!(metrics.get("hedge.sent"), 2);
+        assert_eq!(metrics.get("missing"), 0);
+        assert_eq!(metrics.render(), "hedge.sent 2\nretry.attempts 3\n");
+    }
+
+    #[test]
+    fn test_percentile() {
+        let window = LatencyWindow::default();
+        assert_eq!(window.percentile(50.0), None);
+
+        for ms in 1..=100 {
+            window.record(Duration::from_millis(ms));
+        }
+        assert_eq!(window.percentile(0.0), Some(Duration::from_millis(1)));
+        assert_eq!(window.percentile(50.0), Some(Duration::from_millis(51)));
+        assert_eq!(window.percentile(100.0), Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn test_window_is_bounded() {
+        let window = LatencyWindow::default();
+        for ms in 0..(LATENCY_WINDOW_SIZE as u64 + 10) {
+            window.record(Duration::from_millis(ms));
+        }
+        assert_eq!(window.len(), LATENCY_WINDOW_SIZE);
+        assert_eq!(window.percentile(0.0), Some(Duration::from_millis(10)));
+    }
+}
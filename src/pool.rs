@@ -0,0 +1,950 @@
+use {
+    crate::{
+        agent::AgentReport,
+        algorithm::algorithm::BackendLoad,
+        concurrency::{ConcurrencyLimiter, ConcurrencyPermit},
+        config::{BackendConfig, Config, HealthCheckConfig, PoolConfig, ServerStatus},
+        dynamic::ConfigDiff,
+        error::UpstreamError,
+        events::{Events, HealthEvent},
+        metrics::Metrics,
+    },
+    log::info,
+    serde_json::{json, Value},
+    std::{
+        collections::{HashMap, VecDeque},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex, RwLock,
+        },
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    },
+    tokio::{sync::broadcast, time::timeout},
+};
+
+/// The outcome of a single health check.
+#[derive(Debug, Clone)]
+pub struct Probe {
+    pub at: SystemTime,
+    pub healthy: bool,
+    pub latency: Duration,
+    /// What the probe observed, e.g. the error which made it fail.
+    pub detail: String,
+}
+
+impl Probe {
+    pub fn to_json(&self) -> Value {
+        let at = self.at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        json!({
+            "at_ms": at.as_millis() as u64,
+            "healthy": self.healthy,
+            "latency_ms": self.latency.as_millis() as u64,
+            "detail": self.detail,
+        })
+    }
+}
+
+/// What the pool knows about a backend while requests are being served.
+#[derive(Debug, Clone)]
+pub struct BackendState {
+    pub config: BackendConfig,
+    pub status: ServerStatus,
+    pub in_flight: usize,
+    /// When a `Throttled` backend may be sent requests again.
+    pub throttled_until: Option<Instant>,
+    /// The load last reported by the backend in its responses, between 0 and 1.
+    pub load: Option<f64>,
+    /// Percentage of its configured weight the backend's agent asked for.
+    pub agent_weight: u32,
+    /// `Draining` or `Maintenance` while the backend's agent asks for it, which the backend
+    /// returns to whenever it would otherwise be `Alive`, until the agent says it's up.
+    pub agent_status: Option<ServerStatus>,
+    /// Consecutive health checks which passed, or failed, depending on the latest outcome.
+    pub successes: usize,
+    pub failures: usize,
+    /// The most recent health checks, oldest first.
+    pub history: VecDeque<Probe>,
+    /// When the backend's health last changed, within the flap detection window.
+    pub changes: VecDeque<Instant>,
+    /// When a flapping backend is let back into rotation.
+    pub held_until: Option<Instant>,
+    /// Whether the backend is being drained, which keeps it `Draining` rather than `Alive` however
+    /// its health or agent change.
+    pub draining: bool,
+    /// When the backend joined the pool, telling it apart from one removed and added again.
+    pub since: Instant,
+}
+
+impl BackendState {
+    fn new(config: BackendConfig) -> Self {
+        Self {
+            config,
+            status: ServerStatus::Alive,
+            in_flight: 0,
+            throttled_until: None,
+            load: None,
+            agent_weight: 100,
+            agent_status: None,
+            successes: 0,
+            failures: 0,
+            history: VecDeque::new(),
+            changes: VecDeque::new(),
+            held_until: None,
+            draining: false,
+            since: Instant::now(),
+        }
+    }
+
+    /// Whether the latest health checks disagree with the backend's current health.
+    pub fn transitioning(&self) -> bool {
+        if self.status == ServerStatus::Dead {
+            self.successes > 0
+        } else {
+            self.failures > 0
+        }
+    }
+
+    /// Whether the backend is held out of rotation for flapping.
+    pub fn held(&self, now: Instant) -> bool {
+        self.held_until.is_some_and(|until| until > now)
+    }
+
+    /// Counts a health check result, marking the backend `Dead` after `unhealthy_threshold`
+    /// consecutive failures and `Alive` again after `healthy_threshold` consecutive successes.
+    /// Returns whether the backend's health changed.
+    fn record_health(&mut self, probe: Probe, config: &HealthCheckConfig, now: Instant) -> bool {
+        let healthy = probe.healthy;
+        self.history.push_back(probe);
+        while self.history.len() > config.history_size {
+            self.history.pop_front();
+        }
+        let changed = self.update_health(healthy, config);
+        if changed {
+            self.record_change(config, now);
+        }
+        changed
+    }
+
+    /// Holds the backend out of rotation if its health changed too often recently.
+    fn record_change(&mut self, config: &HealthCheckConfig, now: Instant) {
+        let flap = match config.flap {
+            Some(ref flap) => flap,
+            None => return,
+        };
+        let window = flap.window;
+        self.changes.push_back(now);
+        while self
+            .changes
+            .front()
+            .is_some_and(|change| now.saturating_duration_since(*change) > window)
+        {
+            self.changes.pop_front();
+        }
+        if self.changes.len() > flap.max_changes {
+            self.held_until = Some(now + flap.hold);
+        }
+    }
+
+    fn update_health(&mut self, healthy: bool, config: &HealthCheckConfig) -> bool {
+        if healthy {
+            self.successes += 1;
+            self.failures = 0;
+        } else {
+            self.failures += 1;
+            self.successes = 0;
+        }
+        match self.status {
+            ServerStatus::Dead if self.successes >= config.healthy_threshold => {
+                self.status = ServerStatus::Alive;
+                self.update_busy();
+                true
+            }
+            ServerStatus::Dead | ServerStatus::Maintenance => false,
+            _ if self.failures >= config.unhealthy_threshold => {
+                self.status = ServerStatus::Dead;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The configured weight, scaled by the agent's weight and the load the backend reports.
+    pub fn effective_weight(&self) -> f64 {
+        let load = self.load.unwrap_or(0.0);
+        self.config.weight as f64 * self.agent_weight as f64 / 100.0 * (1.0 - load)
+    }
+
+    /// Makes a `Throttled` backend available again once its `Retry-After` time has passed.
+    fn expire_throttle(&mut self, now: Instant) {
+        if self.throttled_until.is_some_and(|until| until <= now) {
+            self.throttled_until = None;
+            if self.status == ServerStatus::Throttled {
+                self.status = ServerStatus::Alive;
+                self.update_busy();
+            }
+        }
+    }
+
+    /// Whether the backend is at its connection limit.
+    fn full(&self) -> bool {
+        self.config
+            .max_connections
+            .is_some_and(|max| self.in_flight >= max)
+    }
+
+    /// Marks the backend `Busy` while it is at its connection limit, and `Alive` otherwise, unless
+    /// its agent put it in another state or it is being drained.
+    fn update_busy(&mut self) {
+        let full = self.full();
+        match (self.status, self.agent_status) {
+            (ServerStatus::Alive | ServerStatus::Busy, Some(status)) => self.status = status,
+            (ServerStatus::Alive | ServerStatus::Busy, None) if self.draining => {
+                self.status = ServerStatus::Draining
+            }
+            (ServerStatus::Alive, _) if full => self.status = ServerStatus::Busy,
+            (ServerStatus::Busy, _) if !full => self.status = ServerStatus::Alive,
+            _ => {}
+        }
+    }
+}
+
+/// State shared by every request to the pool of backends, which guards the backends as a whole.
+#[derive(Debug)]
+pub struct Pool {
+    config: PoolConfig,
+    limiter: Option<Arc<ConcurrencyLimiter>>,
+    backends: RwLock<HashMap<String, BackendState>>,
+    health_check: RwLock<HealthCheckConfig>,
+    panic: AtomicBool,
+    queue_depth: Mutex<usize>,
+    /// Tells every queued request that a backend may have become available.
+    freed: broadcast::Sender<()>,
+    events: Arc<Events>,
+    metrics: Arc<Metrics>,
+}
+
+impl Pool {
+    pub fn new(config: &Config, events: Arc<Events>, metrics: Arc<Metrics>) -> Self {
+        let backends = config
+            .backends
+            .values()
+            .map(|backend| (backend.authority(), BackendState::new(backend.clone())))
+            .collect();
+        metrics.set("pool.panic", 0);
+        Self {
+            config: config.pool.clone(),
+            limiter: config
+                .pool
+                .concurrency
+                .clone()
+                .map(|config| Arc::new(ConcurrencyLimiter::new(config))),
+            backends: RwLock::new(backends),
+            health_check: RwLock::new(config.health_check.clone()),
+            panic: AtomicBool::new(false),
+            queue_depth: Mutex::new(0),
+            freed: broadcast::channel(1).0,
+            events,
+            metrics,
+        }
+    }
+
+    /// Waits for the concurrency limit, if any, to admit a request.
+    pub async fn admit(&self) -> Result<Option<ConcurrencyPermit>, UpstreamError> {
+        match self.limiter {
+            Some(ref limiter) => match limiter.acquire().await {
+                Some(permit) => Ok(Some(permit)),
+                None => Err(UpstreamError::Overloaded),
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    pub fn config(&self) -> &PoolConfig {
+        &self.config
+    }
+
+    pub fn limiter(&self) -> Option<&Arc<ConcurrencyLimiter>> {
+        self.limiter.as_ref()
+    }
+
+    /// The health check settings in effect, which change when the config is reloaded.
+    pub fn health_check(&self) -> HealthCheckConfig {
+        let health_check = self.health_check.read().expect("Could not lock mutex.");
+        health_check.clone()
+    }
+
+    /// The current config of the backend at `server`'s address, if it is still in the pool.
+    pub fn current(&self, server: &BackendConfig) -> Option<BackendConfig> {
+        let backends = self.backends.read().expect("Could not lock mutex.");
+        backends
+            .get(&server.authority())
+            .map(|backend| backend.config.clone())
+    }
+
+    /// When the backend at `server`'s address joined the pool, if it is in it.
+    pub fn joined(&self, server: &BackendConfig) -> Option<Instant> {
+        let backends = self.backends.read().expect("Could not lock mutex.");
+        backends
+            .get(&server.authority())
+            .map(|backend| backend.since)
+    }
+
+    /// Adds, removes and updates backends as a reloaded config asks. Backends which stay keep
+    /// their health, load and requests in flight.
+    pub fn reload(&self, diff: &ConfigDiff, health_check: &HealthCheckConfig) {
+        *self.health_check.write().expect("Could not lock mutex.") = health_check.clone();
+        let mut backends = self.backends.write().expect("Could not lock mutex.");
+        for server in diff.removed.iter() {
+            backends.remove(&server.authority());
+        }
+        for server in diff.updated.iter() {
+            if let Some(backend) = backends.get_mut(&server.authority()) {
+                backend.config = server.clone();
+                backend.update_busy();
+            }
+        }
+        for server in diff.added.iter() {
+            backends
+                .entry(server.authority())
+                .or_insert_with(|| BackendState::new(server.clone()));
+        }
+        let healthy_percent = Self::healthy_percent(&backends);
+        drop(backends);
+        self.metrics
+            .set("pool.healthy_percent", healthy_percent as u64);
+        self.update_panic(healthy_percent);
+        self.wake_queue();
+    }
+
+    fn healthy_percent(backends: &HashMap<String, BackendState>) -> f64 {
+        let dead = backends
+            .values()
+            .filter(|backend| backend.status == ServerStatus::Dead)
+            .count();
+        100.0 * (backends.len() - dead) as f64 / backends.len().max(1) as f64
+    }
+
+    /// A snapshot of every backend's state.
+    pub fn backends(&self) -> Vec<BackendState> {
+        let backends = self.backends.read().expect("Could not lock mutex.");
+        backends.values().cloned().collect()
+    }
+
+    /// The load of every backend, keyed by authority, for strategies to weigh backends by.
+    pub fn loads(&self) -> HashMap<String, BackendLoad> {
+        let backends = self.backends.read().expect("Could not lock mutex.");
+        backends
+            .iter()
+            .map(|(authority, backend)| {
+                let load = BackendLoad {
+                    weight: backend.effective_weight(),
+                    in_flight: backend.in_flight,
+                };
+                (authority.clone(), load)
+            })
+            .collect()
+    }
+
+    /// Records the load `server` reported in a response.
+    pub fn report_load(&self, server: &BackendConfig, load: f64) {
+        let mut backends = self.backends.write().expect("Could not lock mutex.");
+        if let Some(backend) = backends.get_mut(&server.authority()) {
+            backend.load = Some(load.clamp(0.0, 1.0));
+        }
+    }
+
+    /// Applies what the agent of `server` answered.
+    pub fn report_agent(&self, server: &BackendConfig, report: &AgentReport) {
+        let mut backends = self.backends.write().expect("Could not lock mutex.");
+        let backend = match backends.get_mut(&server.authority()) {
+            Some(backend) => backend,
+            None => return,
+        };
+        if let Some(weight) = report.weight {
+            backend.agent_weight = weight;
+        }
+        if let Some(status) = report.status {
+            let previous = backend.status;
+            match status {
+                ServerStatus::Draining | ServerStatus::Maintenance => {
+                    backend.agent_status = Some(status);
+                    backend.status = status;
+                }
+                ServerStatus::Alive => {
+                    backend.agent_status = None;
+                    if let ServerStatus::Draining | ServerStatus::Maintenance = previous {
+                        backend.status = ServerStatus::Alive;
+                        backend.update_busy();
+                    }
+                }
+                _ => {}
+            }
+            if backend.status != previous {
+                info!(
+                    "Agent set backend {} from {:?} to {:?}.",
+                    server.authority(),
+                    previous,
+                    backend.status
+                );
+            }
+        }
+        drop(backends);
+        self.wake_queue();
+    }
+
+    /// Drains `server`, which keeps serving its sessions but takes no new ones, or makes it
+    /// available again.
+    pub fn set_draining(&self, server: &BackendConfig, draining: bool) {
+        let mut backends = self.backends.write().expect("Could not lock mutex.");
+        let backend = match backends.get_mut(&server.authority()) {
+            Some(backend) => backend,
+            None => return,
+        };
+        let previous = backend.status;
+        backend.draining = draining;
+        if !draining && previous == ServerStatus::Draining {
+            backend.status = ServerStatus::Alive;
+        }
+        backend.update_busy();
+        if backend.status == previous {
+            return;
+        }
+        info!(
+            "Set backend {} from {:?} to {:?}.",
+            server.authority(),
+            previous,
+            backend.status
+        );
+        drop(backends);
+        self.wake_queue();
+    }
+
+    /// Counts the outcome of a health check against `server`.
+    pub fn report_health(&self, server: &BackendConfig, probe: Probe) {
+        let (now, event_probe) = (Instant::now(), probe.clone());
+        let health_check = self.health_check();
+        let mut backends = self.backends.write().expect("Could not lock mutex.");
+        let backend = match backends.get_mut(&server.authority()) {
+            Some(backend) => backend,
+            None => return,
+        };
+        let (old, was_held) = (backend.status, backend.held(now));
+        let changed = backend.record_health(probe, &health_check, now);
+        let event = |new, reason| HealthEvent {
+            at: event_probe.at,
+            backend: server.authority(),
+            old,
+            new,
+            reason,
+            probe: Some(event_probe.clone()),
+        };
+        if !was_held && backend.held(now) {
+            let reason = format!(
+                "held out of rotation for {:?} after {} changes in health",
+                backend
+                    .held_until
+                    .map(|until| until - now)
+                    .unwrap_or_default(),
+                backend.changes.len()
+            );
+            self.events.emit(event(backend.status, reason));
+        }
+        if !changed {
+            return;
+        }
+        let reason = if backend.status == ServerStatus::Dead {
+            format!("{} consecutive failed checks", backend.failures)
+        } else {
+            format!("{} consecutive successful checks", backend.successes)
+        };
+        self.events.emit(event(backend.status, reason));
+        let healthy_percent = Self::healthy_percent(&backends);
+        drop(backends);
+        self.metrics
+            .set("pool.healthy_percent", healthy_percent as u64);
+        self.update_panic(healthy_percent);
+        self.wake_queue();
+    }
+
+    /// Enters panic mode while the percentage of healthy backends is below the panic threshold,
+    /// and leaves it once enough backends have recovered.
+    fn update_panic(&self, healthy_percent: f64) {
+        let panic = self
+            .config
+            .panic_threshold
+            .is_some_and(|threshold| healthy_percent < threshold);
+        if self.panic.swap(panic, Ordering::SeqCst) != panic {
+            if panic {
+                info!(
+                    "Only {:.0}% of backends are healthy; entering panic mode and routing to all backends.",
+                    healthy_percent
+                );
+            } else {
+                info!(
+                    "{:.0}% of backends are healthy; leaving panic mode.",
+                    healthy_percent
+                );
+            }
+            self.metrics.set("pool.panic", panic as u64);
+        }
+    }
+
+    /// Whether `server` is between healthy and unhealthy, and should be probed more often.
+    pub fn transitioning(&self, server: &BackendConfig) -> bool {
+        let backends = self.backends.read().expect("Could not lock mutex.");
+        backends
+            .get(&server.authority())
+            .is_some_and(BackendState::transitioning)
+    }
+
+    /// Whether health status is currently ignored because too few backends are healthy.
+    pub fn in_panic(&self) -> bool {
+        self.panic.load(Ordering::SeqCst)
+    }
+
+    /// Backends which shouldn't be sent new requests.
+    /// In panic mode, unhealthy backends are still considered available.
+    pub fn unavailable(&self) -> Vec<BackendState> {
+        let (now, panic) = (Instant::now(), self.in_panic());
+        let mut backends = self.backends.write().expect("Could not lock mutex.");
+        backends
+            .values_mut()
+            .for_each(|backend| backend.expire_throttle(now));
+        backends
+            .values()
+            .filter(|backend| match backend.status {
+                _ if backend.held(now) => !panic,
+                ServerStatus::Alive => false,
+                ServerStatus::Dead => !panic,
+                _ => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Stops sending requests to `server` for `retry_after`, capped at the configured maximum.
+    pub fn throttle(&self, server: &BackendConfig, retry_after: Duration) {
+        let retry_after = retry_after.min(self.config.max_throttle);
+        let mut backends = self.backends.write().expect("Could not lock mutex.");
+        if let Some(backend) = backends.get_mut(&server.authority()) {
+            match backend.status {
+                ServerStatus::Alive | ServerStatus::Busy | ServerStatus::Throttled => {}
+                _ => return,
+            }
+            backend.status = ServerStatus::Throttled;
+            backend.throttled_until = Some(Instant::now() + retry_after);
+            info!(
+                "Backend {} is throttled for {}ms.",
+                server.authority(),
+                retry_after.as_millis()
+            );
+        }
+        drop(backends);
+        self.metrics.incr("pool.throttled");
+    }
+
+    /// Counts a request in flight to `server` until the returned guard is dropped, unless the
+    /// backend is already at its connection limit. The limit is checked under the same lock the
+    /// request is counted in, so concurrent requests can't take the same last slot.
+    pub fn reserve(self: &Arc<Self>, server: &BackendConfig) -> Option<ConnectionGuard> {
+        let authority = server.authority();
+        let mut backends = self.backends.write().expect("Could not lock mutex.");
+        if let Some(backend) = backends.get_mut(&authority) {
+            if backend.full() {
+                return None;
+            }
+            backend.in_flight += 1;
+            backend.update_busy();
+        }
+        Some(ConnectionGuard {
+            pool: self.clone(),
+            authority,
+        })
+    }
+
+    fn release(&self, authority: &str) {
+        let mut backends = self.backends.write().expect("Could not lock mutex.");
+        if let Some(backend) = backends.get_mut(authority) {
+            backend.in_flight = backend.in_flight.saturating_sub(1);
+            backend.update_busy();
+        }
+        drop(backends);
+        self.wake_queue();
+    }
+
+    /// Wakes every queued request to try again, since events such as a backend recovering or a
+    /// reload adding backends may free many connections at once.
+    fn wake_queue(&self) {
+        // Fails only while nothing is queued.
+        let _ = self.freed.send(());
+    }
+
+    /// Joins the queue of requests waiting for a backend to become available. Requests should
+    /// look for a backend once more after joining, since they're only woken by what frees
+    /// connections later.
+    pub fn enqueue(self: &Arc<Self>) -> Result<QueueTicket, UpstreamError> {
+        let mut depth = self.queue_depth.lock().expect("Could not lock mutex.");
+        if *depth >= self.config.queue.max_size {
+            return Err(UpstreamError::QueueFull);
+        }
+        *depth += 1;
+        self.metrics.set("pool.queue_depth", *depth as u64);
+        self.metrics.incr("pool.queued");
+        let now = Instant::now();
+        Ok(QueueTicket {
+            pool: self.clone(),
+            freed: self.freed.subscribe(),
+            enqueued: now,
+            deadline: now + self.config.queue.max_wait,
+        })
+    }
+}
+
+/// A request in flight to a backend, released when dropped.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    pool: Arc<Pool>,
+    authority: String,
+}
+
+impl ConnectionGuard {
+    /// The address of the backend the request is in flight to.
+    pub fn authority(&self) -> &str {
+        &self.authority
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.pool.release(&self.authority);
+    }
+}
+
+/// A place in the pool's queue, given up when dropped.
+pub struct QueueTicket {
+    pool: Arc<Pool>,
+    freed: broadcast::Receiver<()>,
+    enqueued: Instant,
+    deadline: Instant,
+}
+
+impl QueueTicket {
+    /// Waits until a backend may have become available, or fails once the maximum wait is over.
+    pub async fn wait(&mut self) -> Result<(), UpstreamError> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return Err(UpstreamError::QueueTimeout);
+        }
+        // A ticket which fell behind missed wake-ups, which only means there's something to try.
+        let _ = timeout(self.deadline - now, self.freed.recv())
+            .await
+            .map_err(|_| UpstreamError::QueueTimeout)?;
+        Ok(())
+    }
+}
+
+impl Drop for QueueTicket {
+    fn drop(&mut self) {
+        let mut depth = self.pool.queue_depth.lock().expect("Could not lock mutex.");
+        *depth -= 1;
+        let metrics = &self.pool.metrics;
+        metrics.set("pool.queue_depth", *depth as u64);
+        metrics.add(
+            "pool.queue_wait_ms",
+            self.enqueued.elapsed().as_millis() as u64,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::config::FlapConfig};
+
+    fn probe(healthy: bool) -> Probe {
+        Probe {
+            at: SystemTime::now(),
+            healthy,
+            latency: Duration::from_millis(1),
+            detail: String::new(),
+        }
+    }
+
+    fn pool_of(config: &Config) -> Pool {
+        let metrics = Arc::new(Metrics::default());
+        let (events, _) = Events::new(&config.events, metrics.clone());
+        Pool::new(config, Arc::new(events), metrics)
+    }
+
+    fn pool() -> (Pool, BackendConfig) {
+        let backend = BackendConfig {
+            ip: "127.0.0.1".to_string(),
+            port: 8080,
+            ..BackendConfig::default()
+        };
+        let mut config = Config::default();
+        config
+            .backends
+            .insert("backend".to_string(), backend.clone());
+        (pool_of(&config), backend)
+    }
+
+    #[test]
+    fn test_throttled_backend_is_unavailable_until_expiry() {
+        let (pool, backend) = pool();
+        pool.throttle(&backend, Duration::from_secs(60));
+        assert_eq!(pool.unavailable().len(), 1);
+        assert_eq!(pool.backends()[0].status, ServerStatus::Throttled);
+
+        pool.throttle(&backend, Duration::from_secs(0));
+        assert!(pool.unavailable().is_empty());
+        assert_eq!(pool.backends()[0].status, ServerStatus::Alive);
+    }
+
+    #[test]
+    fn test_effective_weight() {
+        let (pool, backend) = pool();
+        pool.report_load(&backend, 0.75);
+        pool.report_agent(
+            &backend,
+            &AgentReport {
+                weight: Some(50),
+                status: None,
+            },
+        );
+        let load = pool.loads()[&backend.authority()];
+        assert!((load.weight - 0.125).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_agent_drain_and_up() {
+        let (pool, backend) = pool();
+        let report = |status| AgentReport {
+            weight: None,
+            status: Some(status),
+        };
+        pool.report_agent(&backend, &report(ServerStatus::Draining));
+        assert_eq!(pool.unavailable()[0].status, ServerStatus::Draining);
+        pool.report_agent(&backend, &report(ServerStatus::Alive));
+        assert!(pool.unavailable().is_empty());
+    }
+
+    #[test]
+    fn test_agent_drain_outlasts_recovery() {
+        let (pool, backend) = pool();
+        let report = |status| AgentReport {
+            weight: None,
+            status: Some(status),
+        };
+        pool.report_agent(&backend, &report(ServerStatus::Draining));
+        for _ in 0..pool.health_check().unhealthy_threshold {
+            pool.report_health(&backend, probe(false));
+        }
+        assert_eq!(pool.unavailable()[0].status, ServerStatus::Dead);
+        for _ in 0..pool.health_check().healthy_threshold {
+            pool.report_health(&backend, probe(true));
+        }
+        assert_eq!(pool.unavailable()[0].status, ServerStatus::Draining);
+
+        pool.set_draining(&backend, true);
+        pool.set_draining(&backend, false);
+        assert_eq!(pool.unavailable()[0].status, ServerStatus::Draining);
+
+        pool.report_agent(&backend, &report(ServerStatus::Alive));
+        assert!(pool.unavailable().is_empty());
+    }
+
+    #[test]
+    fn test_drain_outlasts_recovery() {
+        let (pool, backend) = pool();
+        pool.set_draining(&backend, true);
+        for _ in 0..pool.health_check().unhealthy_threshold {
+            pool.report_health(&backend, probe(false));
+        }
+        assert_eq!(pool.unavailable()[0].status, ServerStatus::Dead);
+        for _ in 0..pool.health_check().healthy_threshold {
+            pool.report_health(&backend, probe(true));
+        }
+        assert_eq!(pool.unavailable()[0].status, ServerStatus::Draining);
+
+        let up = AgentReport {
+            weight: None,
+            status: Some(ServerStatus::Alive),
+        };
+        pool.report_agent(&backend, &up);
+        assert_eq!(pool.unavailable()[0].status, ServerStatus::Draining);
+
+        pool.set_draining(&backend, false);
+        assert!(pool.unavailable().is_empty());
+    }
+
+    #[test]
+    fn test_health_thresholds() {
+        let (pool, backend) = pool();
+        for _ in 0..pool.health_check().unhealthy_threshold {
+            assert!(pool.unavailable().is_empty());
+            pool.report_health(&backend, probe(false));
+        }
+        assert_eq!(pool.unavailable()[0].status, ServerStatus::Dead);
+        for _ in 0..pool.health_check().healthy_threshold {
+            assert!(!pool.unavailable().is_empty());
+            pool.report_health(&backend, probe(true));
+        }
+        assert!(pool.unavailable().is_empty());
+    }
+
+    #[test]
+    fn test_panic_mode() {
+        let backends = (0..4)
+            .map(|port| BackendConfig {
+                port,
+                ..BackendConfig::default()
+            })
+            .collect::<Vec<_>>();
+        let mut config = Config::default();
+        config.pool.panic_threshold = Some(50.0);
+        config.health_check.unhealthy_threshold = 1;
+        for backend in backends.iter() {
+            config
+                .backends
+                .insert(backend.port.to_string(), backend.clone());
+        }
+        let pool = pool_of(&config);
+
+        pool.report_health(&backends[0], probe(false));
+        pool.report_health(&backends[1], probe(false));
+        assert!(!pool.in_panic());
+        assert_eq!(pool.unavailable().len(), 2);
+
+        pool.report_health(&backends[2], probe(false));
+        assert!(pool.in_panic());
+        assert!(pool.unavailable().is_empty());
+        assert_eq!(pool.metrics.get("pool.panic"), 1);
+    }
+
+    #[test]
+    fn test_history_and_transitions() {
+        let (pool, backend) = pool();
+        for _ in 0..pool.health_check().history_size + 5 {
+            pool.report_health(&backend, probe(true));
+        }
+        assert!(!pool.transitioning(&backend));
+        pool.report_health(&backend, probe(false));
+        assert!(pool.transitioning(&backend));
+
+        let state = &pool.backends()[0];
+        assert_eq!(state.history.len(), pool.health_check().history_size);
+        assert!(!state.history.back().unwrap().healthy);
+    }
+
+    #[test]
+    fn test_flapping_backend_is_held() {
+        let mut state = BackendState::new(BackendConfig::default());
+        let config = HealthCheckConfig {
+            healthy_threshold: 1,
+            unhealthy_threshold: 1,
+            flap: Some(FlapConfig {
+                max_changes: 2,
+                window: Duration::from_secs(1),
+                hold: Duration::from_secs(5),
+            }),
+            ..HealthCheckConfig::default()
+        };
+        let now = Instant::now();
+        assert!(state.record_health(probe(false), &config, now));
+        assert!(state.record_health(probe(true), &config, now));
+        assert!(!state.held(now));
+
+        let later = now + Duration::from_millis(2000);
+        assert!(state.record_health(probe(false), &config, later));
+        assert!(!state.held(later));
+        assert!(state.record_health(probe(true), &config, later));
+        assert!(state.record_health(probe(false), &config, later));
+        assert!(state.held(later));
+        assert!(!state.held(later + Duration::from_millis(5000)));
+    }
+
+    #[test]
+    fn test_throttle_is_capped() {
+        let (pool, backend) = pool();
+        pool.throttle(&backend, Duration::from_secs(u32::MAX as u64));
+        let until = pool.backends()[0].throttled_until.unwrap();
+        let max = pool.config.max_throttle;
+        assert!(until <= Instant::now() + max);
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let backend = BackendConfig {
+            max_connections: Some(1),
+            ..BackendConfig::default()
+        };
+        let mut config = Config::default();
+        config
+            .backends
+            .insert("backend".to_string(), backend.clone());
+        let pool = Arc::new(pool_of(&config));
+
+        let first = pool.reserve(&backend);
+        assert!(first.is_some());
+        assert!(pool.reserve(&backend).is_none());
+        assert_eq!(pool.unavailable()[0].status, ServerStatus::Busy);
+
+        drop(first);
+        assert!(pool.unavailable().is_empty());
+        assert!(pool.reserve(&backend).is_some());
+    }
+
+    #[test]
+    fn test_queue_overflow() {
+        let mut config = Config::default();
+        config.pool.queue.max_size = 1;
+        let pool = Arc::new(pool_of(&config));
+
+        let ticket = pool.enqueue().unwrap();
+        assert!(matches!(pool.enqueue(), Err(UpstreamError::QueueFull)));
+        drop(ticket);
+        assert!(pool.enqueue().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_every_queued_request_is_woken() {
+        let mut config = Config::default();
+        config.pool.queue.max_size = 3;
+        config
+            .backends
+            .insert(String::from("a"), BackendConfig::default());
+        let pool = Arc::new(pool_of(&config));
+        let mut tickets = (0..3).map(|_| pool.enqueue().unwrap()).collect::<Vec<_>>();
+        let up = AgentReport {
+            weight: None,
+            status: Some(ServerStatus::Alive),
+        };
+        pool.report_agent(&BackendConfig::default(), &up);
+        for ticket in tickets.iter_mut() {
+            assert!(timeout(Duration::from_millis(100), ticket.wait())
+                .await
+                .is_ok_and(|woken| woken.is_ok()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue_ticket_expires() {
+        let mut config = Config::default();
+        config.pool.queue.max_wait = Duration::from_millis(10);
+        let pool = Arc::new(pool_of(&config));
+
+        let mut ticket = pool.enqueue().unwrap();
+        assert!(matches!(
+            ticket.wait().await,
+            Err(UpstreamError::QueueTimeout)
+        ));
+        assert!(matches!(
+            ticket.wait().await,
+            Err(UpstreamError::QueueTimeout)
+        ));
+    }
+}
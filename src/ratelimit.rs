@@ -0,0 +1,319 @@
+use {
+    crate::{
+        config::{RateLimitConfig, RateLimitKey},
+        metrics::Metrics,
+        route::Routes,
+    },
+    actix_web::{dev::ServiceRequest, http::StatusCode, HttpMessage, HttpResponse},
+    std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    },
+};
+
+/// The outcome of counting a request against its client's bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    Allowed,
+    /// The request is over the limit. The client may try again after the given duration.
+    Limited {
+        retry_after: Duration,
+    },
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, rate: f64, burst: f64, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last_refill = now;
+    }
+}
+
+/// Token buckets for every client seen recently.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+    last_eviction: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+            last_eviction: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// Takes a token from the bucket of `key` if it has one left. Checking and taking happen under
+    /// one lock, so that concurrent requests can't share the last token.
+    pub fn take(&self, key: &str, now: Instant) -> Decision {
+        self.evict_idle(now);
+        let (rate, burst) = (self.config.rate, self.config.burst as f64);
+        let mut buckets = self.buckets.lock().expect("Could not lock mutex.");
+        let bucket = buckets.entry(key.to_string()).or_insert(TokenBucket {
+            tokens: burst,
+            last_refill: now,
+        });
+        bucket.refill(rate, burst, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Decision::Allowed
+        } else {
+            let wait = if rate > 0.0 {
+                (1.0 - bucket.tokens) / rate
+            } else {
+                f64::MAX
+            };
+            Decision::Limited {
+                retry_after: Duration::from_secs_f64(wait.min(u32::MAX as f64)),
+            }
+        }
+    }
+
+    /// Puts back a token taken for a request which another limit rejected.
+    pub fn give_back(&self, key: &str) {
+        let burst = self.config.burst as f64;
+        let mut buckets = self.buckets.lock().expect("Could not lock mutex.");
+        if let Some(bucket) = buckets.get_mut(key) {
+            bucket.tokens = (bucket.tokens + 1.0).min(burst);
+        }
+    }
+
+    /// How long the bucket of `key` takes to fill up again, in seconds.
+    pub fn reset(&self, key: &str) -> f64 {
+        let (rate, burst) = (self.config.rate, self.config.burst as f64);
+        let buckets = self.buckets.lock().expect("Could not lock mutex.");
+        let tokens = buckets.get(key).map_or(burst, |bucket| bucket.tokens);
+        (burst - tokens).max(0.0) / rate
+    }
+
+    /// Tokens left in the bucket of `key`.
+    pub fn remaining(&self, key: &str) -> u64 {
+        let buckets = self.buckets.lock().expect("Could not lock mutex.");
+        buckets
+            .get(key)
+            .map(|bucket| bucket.tokens as u64)
+            .unwrap_or(self.config.burst)
+    }
+
+    /// Number of clients currently tracked.
+    pub fn len(&self) -> usize {
+        self.buckets.lock().expect("Could not lock mutex.").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets the buckets of clients which haven't made a request for a while.
+    fn evict_idle(&self, now: Instant) {
+        let idle_timeout = self.config.idle_timeout;
+        let mut last_eviction = self.last_eviction.lock().expect("Could not lock mutex.");
+        if now.saturating_duration_since(*last_eviction) < idle_timeout {
+            return;
+        }
+        *last_eviction = now;
+        let mut buckets = self.buckets.lock().expect("Could not lock mutex.");
+        buckets
+            .retain(|_, bucket| now.saturating_duration_since(bucket.last_refill) < idle_timeout);
+    }
+}
+
+/// Applies the global rate limit and that of the matching route to incoming requests.
+pub struct RateLimits {
+    global: Option<RateLimiter>,
+    routes: Arc<Routes>,
+    metrics: Arc<Metrics>,
+}
+
+impl RateLimits {
+    pub fn new(
+        config: Option<RateLimitConfig>,
+        routes: Arc<Routes>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            global: config.map(RateLimiter::new),
+            routes,
+            metrics,
+        }
+    }
+
+    /// Returns the response to send instead of forwarding `req`, if it is over a limit.
+    pub fn check(&self, req: &ServiceRequest) -> Option<HttpResponse> {
+        let now = Instant::now();
+        let route = self.routes.find(req.path());
+        let limiters = self
+            .global
+            .iter()
+            .chain(route.iter().filter_map(|route| route.rate_limiter.as_ref()));
+
+        // A request rejected by one limit gives back the tokens the others took, so that e.g. a
+        // request rejected by the route doesn't use up the client's global allowance.
+        let mut taken: Vec<(&RateLimiter, String)> = Vec::new();
+        for limiter in limiters {
+            let key = Self::key(req, &limiter.config().key);
+            if let Decision::Limited { retry_after } = limiter.take(&key, now) {
+                for (limiter, key) in taken {
+                    limiter.give_back(&key);
+                }
+                self.metrics.incr("rate_limit.limited");
+                return Some(Self::limited_response(limiter, &key, retry_after));
+            }
+            taken.push((limiter, key));
+        }
+        None
+    }
+
+    fn key(req: &ServiceRequest, key: &RateLimitKey) -> String {
+        let value = match *key {
+            RateLimitKey::Ip => None,
+            RateLimitKey::Header(ref name) => req
+                .headers()
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .map(|value| format!("header:{}", value)),
+            RateLimitKey::Cookie(ref name) => req
+                .cookie(name)
+                .map(|cookie| format!("cookie:{}", cookie.value())),
+        };
+        value.unwrap_or_else(|| {
+            let ip = req
+                .peer_addr()
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_default();
+            format!("ip:{}", ip)
+        })
+    }
+
+    fn limited_response(limiter: &RateLimiter, key: &str, retry_after: Duration) -> HttpResponse {
+        let config = limiter.config();
+        let retry_after = retry_after.as_secs_f64().ceil() as u64;
+        let reset = if config.rate > 0.0 {
+            limiter.reset(key).ceil() as u64
+        } else {
+            retry_after
+        };
+        HttpResponse::build(StatusCode::TOO_MANY_REQUESTS)
+            .header("Retry-After", retry_after.to_string())
+            .header("RateLimit-Limit", config.burst.to_string())
+            .header("RateLimit-Remaining", limiter.remaining(key).to_string())
+            .header("RateLimit-Reset", reset.to_string())
+            .body("Too many requests.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(rate: f64, burst: u64) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            rate,
+            burst,
+            ..RateLimitConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_key_config() {
+        let config: RateLimitConfig = toml::from_str("key = { header = \"X-Api-Key\" }").unwrap();
+        assert_eq!(config.key, RateLimitKey::Header("X-Api-Key".to_string()));
+        let config: RateLimitConfig = toml::from_str("key = \"ip\"").unwrap();
+        assert_eq!(config.key, RateLimitKey::Ip);
+    }
+
+    #[test]
+    fn test_burst_then_limit() {
+        let (limiter, now) = (limiter(1.0, 2), Instant::now());
+        assert_eq!(limiter.take("a", now), Decision::Allowed);
+        assert_eq!(limiter.take("a", now), Decision::Allowed);
+        assert_eq!(
+            limiter.take("a", now),
+            Decision::Limited {
+                retry_after: Duration::from_secs(1)
+            }
+        );
+        assert_eq!(limiter.take("b", now), Decision::Allowed);
+    }
+
+    #[test]
+    fn test_refill() {
+        let (limiter, now) = (limiter(2.0, 1), Instant::now());
+        assert_eq!(limiter.take("a", now), Decision::Allowed);
+        assert_ne!(limiter.take("a", now), Decision::Allowed);
+        let later = now + Duration::from_millis(500);
+        assert_eq!(limiter.take("a", later), Decision::Allowed);
+    }
+
+    #[test]
+    fn test_idle_buckets_are_evicted() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            idle_timeout: Duration::from_secs(1),
+            ..RateLimitConfig::default()
+        });
+        let now = Instant::now();
+        limiter.take("a", now);
+        limiter.take("b", now + Duration::from_millis(900));
+        assert_eq!(limiter.len(), 2);
+
+        limiter.take("c", now + Duration::from_millis(1500));
+        assert_eq!(limiter.len(), 2);
+        assert_eq!(limiter.remaining("a"), limiter.config().burst);
+    }
+
+    #[test]
+    fn test_give_back() {
+        let (limiter, now) = (limiter(0.0, 1), Instant::now());
+        assert_eq!(limiter.take("a", now), Decision::Allowed);
+        assert_ne!(limiter.take("a", now), Decision::Allowed);
+        limiter.give_back("a");
+        limiter.give_back("a");
+        assert_eq!(limiter.remaining("a"), 1);
+        assert_eq!(limiter.take("a", now), Decision::Allowed);
+    }
+
+    #[test]
+    fn test_concurrent_requests_share_the_burst() {
+        let limiter = Arc::new(limiter(0.0, 3));
+        let now = Instant::now();
+        let threads = (0..8)
+            .map(|_| {
+                let limiter = limiter.clone();
+                std::thread::spawn(move || limiter.take("a", now) == Decision::Allowed)
+            })
+            .collect::<Vec<_>>();
+        let allowed = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .filter(|allowed| *allowed)
+            .count();
+        assert_eq!(allowed, 3);
+    }
+
+    #[test]
+    fn test_reset() {
+        let (limiter, now) = (limiter(2.0, 4), Instant::now());
+        assert!(limiter.reset("a").abs() < f64::EPSILON);
+        limiter.take("a", now);
+        limiter.take("a", now);
+        limiter.take("a", now);
+        assert!((limiter.reset("a") - 1.5).abs() < f64::EPSILON);
+    }
+}
@@ -0,0 +1,503 @@
+use {
+    crate::{
+        config::{BackendConfig, RegistryConfig},
+        dynamic::Reloader,
+        metrics::Metrics,
+    },
+    actix_web::client::Client,
+    futures::stream::{FuturesUnordered, StreamExt},
+    log::warn,
+    serde::Deserialize,
+    std::{
+        collections::{BTreeMap, HashSet},
+        sync::Arc,
+        time::{Duration, Instant},
+    },
+    tokio::{select, time::delay_for},
+};
+
+/// How often the config is checked for backends to start watching.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Least time between blocking queries, in case the registry answers them straight away.
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Largest answer that is read.
+const MAX_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
+
+/// An instance of a service and its node's checks, as the registry lists them.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct Entry {
+    node: Node,
+    service: Service,
+    #[serde(default)]
+    checks: Vec<Check>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct Node {
+    #[serde(default)]
+    address: String,
+    #[serde(default)]
+    meta: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct Service {
+    /// Defaults to the node's address when empty.
+    #[serde(default)]
+    address: String,
+    port: u16,
+    #[serde(default)]
+    tags: Option<Vec<String>>,
+    #[serde(default)]
+    meta: Option<BTreeMap<String, String>>,
+    #[serde(default)]
+    weights: Option<Weights>,
+}
+
+/// Weights of an instance whose checks pass, and of one with a check warning.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct Weights {
+    passing: u32,
+    warning: u32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct Check {
+    status: String,
+}
+
+/// The backends for the instances in `body`, each with the settings of `backend` but for its
+/// address, weight, zone, tags and metadata. Instances with a critical check, or a weight of 0,
+/// are left out.
+fn instances(
+    body: &[u8],
+    backend: &BackendConfig,
+    registry: &RegistryConfig,
+) -> Result<Vec<BackendConfig>, String> {
+    let entries = serde_json::from_slice::<Vec<Entry>>(body).map_err(|e| e.to_string())?;
+    let mut instances = Vec::new();
+    for entry in entries {
+        let status = |status: &str| entry.checks.iter().any(|check| check.status == status);
+        if status("critical") {
+            continue;
+        }
+        let weight = match entry.service.weights {
+            Some(ref weights) if status("warning") => weights.warning,
+            Some(ref weights) => weights.passing,
+            None => 1,
+        };
+        if weight == 0 {
+            continue;
+        }
+        let (node_meta, meta) = (
+            entry.node.meta.unwrap_or_default(),
+            entry.service.meta.unwrap_or_default(),
+        );
+        let zone = meta
+            .get(&registry.zone_key)
+            .or_else(|| node_meta.get(&registry.zone_key))
+            .cloned();
+        let ip = if entry.service.address.is_empty() {
+            entry.node.address
+        } else {
+            entry.service.address
+        };
+        instances.push(BackendConfig {
+            ip,
+            port: entry.service.port,
+            weight,
+            zone,
+            tags: entry.service.tags.unwrap_or_default(),
+            meta,
+            registry: None,
+            ..backend.clone()
+        });
+    }
+    instances.sort_by_key(BackendConfig::authority);
+    instances.dedup_by_key(|instance| instance.authority());
+    Ok(instances)
+}
+
+/// Asks the registry for the instances of the backend `name`'s service. With an `index` other
+/// than 0 and a `wait`, the registry holds the query until the service changes past the index.
+/// Returns the instances and the index of the answer.
+async fn query(
+    client: &Client,
+    name: &str,
+    backend: &BackendConfig,
+    index: u64,
+) -> Result<(Vec<BackendConfig>, u64), String> {
+    let registry = backend
+        .registry
+        .as_ref()
+        .ok_or("no registry is configured")?;
+    let service = registry.service.as_deref().unwrap_or(name);
+    let mut params = Vec::new();
+    if let Some(ref datacenter) = registry.datacenter {
+        params.push(("dc", datacenter.clone()));
+    }
+    if let Some(ref tag) = registry.tag {
+        params.push(("tag", tag.clone()));
+    }
+    let mut timeout = registry.timeout;
+    if let (Some(wait), true) = (registry.wait, index > 0) {
+        params.push(("index", index.to_string()));
+        params.push(("wait", format!("{}ms", wait.as_millis())));
+        timeout += wait;
+    }
+    let url = format!(
+        "{}/v1/health/service/{}",
+        registry.url.trim_end_matches('/'),
+        service
+    );
+    let mut request = client
+        .get(url)
+        .query(&params)
+        .map_err(|e| e.to_string())?
+        .timeout(timeout);
+    if let Some(ref token) = registry.token {
+        request = request.header("X-Consul-Token", token.as_str());
+    }
+    let mut res = request.send().await.map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("status {}", res.status()));
+    }
+    let index = res
+        .headers()
+        .get("X-Consul-Index")
+        .and_then(|index| index.to_str().ok()?.parse().ok())
+        .unwrap_or(0);
+    let body = res
+        .body()
+        .limit(MAX_RESPONSE_SIZE)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok((instances(&body, backend, registry)?, index))
+}
+
+/// Keeps the instances of a backend which is discovered through a registry up to date.
+#[derive(Debug)]
+pub struct Watch {
+    name: String,
+    /// The backend's config when it was last queried. Once it changes, the index is reset.
+    backend: Option<BackendConfig>,
+    /// Index of the last answer, which blocking queries wait to change.
+    index: u64,
+}
+
+impl Watch {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            backend: None,
+            index: 0,
+        }
+    }
+
+    /// Queries the registry and hands the instances to `reloader`. If the registry can't be
+    /// reached, or answers with an error, the instances found before are kept. Returns how long
+    /// to wait before the next query, or `None` once the backend is no longer discovered through
+    /// a registry.
+    pub async fn poll(
+        &mut self,
+        client: &Client,
+        reloader: &Reloader,
+        metrics: &Metrics,
+    ) -> Option<Duration> {
+        let name = self.name.clone();
+        let current = move |reloader: &Reloader| {
+            let declared = reloader.declared();
+            declared
+                .backends
+                .get(&name)
+                .filter(|backend| backend.registry.is_some())
+                .cloned()
+        };
+        let backend = current(reloader)?;
+        let registry = backend.registry.clone()?;
+        if self.backend.as_ref() != Some(&backend) {
+            self.backend = Some(backend.clone());
+            self.index = 0;
+        }
+
+        let started = Instant::now();
+        match query(client, &self.name, &backend, self.index).await {
+            // The config may have changed while a blocking query was held.
+            Ok(_) if current(reloader).as_ref() != Some(&backend) => Some(Duration::default()),
+            Ok((instances, index)) => {
+                metrics.incr("registry.polls");
+                reloader.discover(&self.name, instances);
+                // An index which went backwards means the registry's state was reset.
+                self.index = if index < self.index { 0 } else { index };
+                Some(match registry.wait {
+                    Some(_) => MIN_INTERVAL
+                        .checked_sub(started.elapsed())
+                        .unwrap_or_default(),
+                    None => registry.interval,
+                })
+            }
+            Err(e) => {
+                metrics.incr("registry.failures");
+                warn!(
+                    "Could not query the registry for backend '{}', keeping its instances: {}",
+                    self.name, e
+                );
+                self.index = 0;
+                Some(registry.interval)
+            }
+        }
+    }
+}
+
+/// Watches a backend until it's no longer discovered through a registry, returning its name.
+async fn watch(client: &Client, name: String, reloader: &Reloader, metrics: &Metrics) -> String {
+    let mut watch = Watch::new(&name);
+    while let Some(delay) = watch.poll(client, reloader, metrics).await {
+        delay_for(delay).await;
+    }
+    name
+}
+
+/// Watches every backend which is discovered through a registry for as long as the load balancer
+/// runs, each on its own so that a blocking query doesn't hold up the others.
+pub async fn run(
+    reloader: &Reloader,
+    metrics: Arc<Metrics>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = Client::default();
+    let mut watching = HashSet::new();
+    let mut watches = FuturesUnordered::new();
+    loop {
+        let declared = reloader.declared();
+        for (name, backend) in declared.backends.iter() {
+            if backend.registry.is_some() && watching.insert(name.clone()) {
+                watches.push(watch(&client, name.clone(), reloader, &metrics));
+            }
+        }
+        select! {
+            Some(name) = watches.next(), if !watches.is_empty() => {
+                watching.remove(&name);
+            }
+            _ = delay_for(WATCH_INTERVAL) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            algorithm::algorithm::Algorithm, config::Config, dynamic::expand, events::Events,
+            format::Format, pool::Pool,
+        },
+        std::{
+            collections::HashMap,
+            sync::{Mutex, RwLock},
+        },
+        tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpListener,
+        },
+    };
+
+    /// What the stand-in registry answers.
+    struct Catalog {
+        index: u64,
+        body: String,
+        fail: bool,
+        /// Paths and queries of the requests made.
+        requests: Vec<String>,
+    }
+
+    /// A stand-in registry, which holds blocking queries until the catalog's index changes or it
+    /// starts failing.
+    async fn registry(catalog: Arc<Mutex<Catalog>>) -> String {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        actix_rt::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let catalog = catalog.clone();
+                actix_rt::spawn(async move {
+                    let mut buf = [0; 4096];
+                    let len = stream.read(&mut buf).await.unwrap_or(0);
+                    let request = String::from_utf8_lossy(&buf[..len]);
+                    let target = request.split(' ').nth(1).unwrap_or_default().to_string();
+                    let param = |name: &str| {
+                        target
+                            .split(&['?', '&'][..])
+                            .find_map(|param| param.strip_prefix(&format!("{}=", name)))
+                            .map(str::to_string)
+                    };
+                    let index = param("index").and_then(|index| index.parse::<u64>().ok());
+                    catalog.lock().unwrap().requests.push(target.clone());
+                    let until = Instant::now() + Duration::from_secs(5);
+                    let held = || {
+                        let catalog = catalog.lock().unwrap();
+                        index == Some(catalog.index) && !catalog.fail
+                    };
+                    while held() && Instant::now() < until {
+                        delay_for(Duration::from_millis(10)).await;
+                    }
+                    let response = {
+                        let catalog = catalog.lock().unwrap();
+                        if catalog.fail {
+                            String::from("HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                        } else {
+                            format!(
+                                "HTTP/1.1 200 OK\r\nx-consul-index: {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                                catalog.index,
+                                catalog.body.len(),
+                                catalog.body
+                            )
+                        }
+                    };
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        url
+    }
+
+    const INSTANCES: &str = r#"[
+  {
+    "Node": { "Node": "n1", "Address": "10.0.0.1", "Meta": { "zone": "b" } },
+    "Service": {
+      "ID": "web-1", "Service": "web", "Address": "", "Port": 8080, "Tags": ["v1"],
+      "Meta": { "zone": "a", "version": "1.2" }, "Weights": { "Passing": 3, "Warning": 1 }
+    },
+    "Checks": [{ "Status": "passing" }]
+  },
+  {
+    "Node": { "Node": "n2", "Address": "10.0.1.1", "Meta": { "zone": "b" } },
+    "Service": {
+      "ID": "web-2", "Service": "web", "Address": "10.0.0.2", "Port": 8081, "Tags": null,
+      "Meta": null, "Weights": { "Passing": 3, "Warning": 1 }
+    },
+    "Checks": [{ "Status": "passing" }, { "Status": "warning" }]
+  },
+  {
+    "Node": { "Node": "n3", "Address": "10.0.0.3" },
+    "Service": { "ID": "web-3", "Service": "web", "Port": 8080 },
+    "Checks": [{ "Status": "critical" }]
+  }
+]"#;
+
+    #[actix_rt::test]
+    async fn test_instances_follow_the_registry() {
+        let catalog = Arc::new(Mutex::new(Catalog {
+            index: 5,
+            body: INSTANCES.to_string(),
+            fail: false,
+            requests: Vec::new(),
+        }));
+        let url = registry(catalog.clone()).await;
+
+        let declared = Config::from_toml(&format!(
+            "strategy = \"RoundRobin\"\n[backends.api]\npath = \"/api\"\nregistry = {{ url = \"{}\", service = \"web\", tag = \"v1\", wait = \"2s\" }}\n",
+            url
+        ))
+        .unwrap();
+        let config = expand(&declared, &HashMap::new());
+        let metrics = Arc::new(Metrics::default());
+        let (events, _) = Events::new(&config.events, metrics.clone());
+        let pool = Arc::new(Pool::new(&config, Arc::new(events), metrics.clone()));
+        let mut strategy = config.strategy.clone();
+        strategy.configure(&config);
+        let config = Arc::new(RwLock::new(config));
+        let reloader = Reloader::new(
+            std::env::temp_dir().join("loblaw-registry.toml"),
+            Format::Toml,
+            None,
+            declared,
+            config.clone(),
+            Arc::new(RwLock::new(strategy)),
+            pool.clone(),
+            metrics.clone(),
+        );
+        let instances = || {
+            let mut instances = config
+                .read()
+                .unwrap()
+                .backends
+                .iter()
+                .map(|(name, backend)| {
+                    let zone = backend.zone.clone().unwrap_or_default();
+                    (name.clone(), backend.weight, zone, backend.tags.len())
+                })
+                .collect::<Vec<_>>();
+            instances.sort();
+            instances
+        };
+        let instance = |name: &str, weight, zone: &str, tags| {
+            (name.to_string(), weight, zone.to_string(), tags)
+        };
+
+        let client = Client::default();
+        let mut watch = Watch::new("api");
+        assert!(watch.poll(&client, &reloader, &metrics).await.is_some());
+        assert_eq!(
+            instances(),
+            vec![
+                instance("api/10.0.0.1:8080", 3, "a", 1),
+                instance("api/10.0.0.2:8081", 1, "b", 0),
+            ]
+        );
+        let backends = config.read().unwrap().backends.clone();
+        assert_eq!(backends["api/10.0.0.1:8080"].meta["version"], "1.2");
+        assert_eq!(
+            backends["api/10.0.0.1:8080"].uri().unwrap(),
+            "http://10.0.0.1:8080/api"
+        );
+
+        let changed = catalog.clone();
+        actix_rt::spawn(async move {
+            delay_for(Duration::from_millis(100)).await;
+            let mut catalog = changed.lock().unwrap();
+            catalog.index = 6;
+            catalog.body = catalog.body.replace("\"warning\"", "\"critical\"");
+        });
+        assert!(watch.poll(&client, &reloader, &metrics).await.is_some());
+        assert_eq!(instances(), vec![instance("api/10.0.0.1:8080", 3, "a", 1)]);
+
+        catalog.lock().unwrap().fail = true;
+        let retry = watch.poll(&client, &reloader, &metrics).await;
+        assert_eq!(retry, Some(Duration::from_secs(10)));
+        assert_eq!(instances(), vec![instance("api/10.0.0.1:8080", 3, "a", 1)]);
+        assert_eq!(metrics.get("registry.polls"), 2);
+        assert_eq!(metrics.get("registry.failures"), 1);
+        assert_eq!(
+            catalog.lock().unwrap().requests,
+            vec![
+                "/v1/health/service/web?tag=v1",
+                "/v1/health/service/web?tag=v1&index=5&wait=2000ms",
+                "/v1/health/service/web?tag=v1&index=6&wait=2000ms",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validation() {
+        let errors = Config::from_toml(
+            "[backends.a]\nregistry = { url = \"127.0.0.1:8500\" }\nip = \"a.internal\"\n[backends.b]\nregistry = { url = \"http://consul\" }\nfile_sd = { path = \"b.json\" }\n",
+        )
+        .unwrap_err()
+        .iter()
+        .map(|error| format!("{:?}: {}", error.line, error))
+        .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                "Some(2): backends.a.registry.url: '127.0.0.1:8500' is not a valid address: it has no scheme",
+                "Some(5): backends.b.registry: can't be set along with 'file_sd'",
+            ]
+        );
+    }
+}
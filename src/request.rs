@@ -1,7 +1,15 @@
 use {
     crate::{
         algorithm::algorithm::RequestInfo,
-        config::PersistenceType,
+        concurrency::dropped,
+        config::{Config, PersistenceType, ServerStatus, TimeoutConfig},
+        error::UpstreamError,
+        metrics::Metrics,
+        pool::{ConnectionGuard, Pool, QueueTicket},
+        ratelimit::RateLimits,
+        retry::{is_idempotent, is_retryable_error, throttled_for, RetryPolicy},
+        route::{Route, Routes},
+        timed_future::TimedExt,
         with_read_lock, with_write_lock, Threadable,
         {
             algorithm::algorithm::{Algorithm, Strategy},
@@ -9,20 +17,37 @@ use {
         },
     },
     actix_web::{
-        client::{Client, ClientResponse},
+        client::{Client, ClientResponse, Connector, PayloadError, SendRequestError},
+        dev::{Decompress, Payload, Service},
         http::{header, Cookie},
         middleware, web, App, Error, HttpRequest, HttpResponse, HttpServer,
     },
+    futures::{
+        future::{ok, select, Either},
+        StreamExt,
+    },
+    log::{debug, info, warn},
     std::{
+        cell::RefCell,
         collections::hash_map::{DefaultHasher, HashMap},
         hash::{Hash, Hasher},
         net::SocketAddr,
         sync::{Arc, RwLock},
+        time::{Duration, Instant, SystemTime},
     },
+    tokio::time::{delay_for, timeout},
 };
 
+type UpstreamResponse = ClientResponse<Decompress<Payload>>;
+
+/// A response together with the connection it holds until its body has been read.
+type Attempt = (UpstreamResponse, ConnectionGuard);
+
 static COOKIE_SESSION_KEY: &'static str = "session";
 
+/// Largest response body read from a backend, the same limit awc puts on `ClientResponse::body`.
+const MAX_BODY_SIZE: usize = 262_144;
+
 #[derive(Hash)]
 pub struct User {
     client_host: String,
@@ -46,77 +71,187 @@ pub struct RequestHandler {
     strategy: Threadable<Strategy>,
     persistence_type: PersistenceType,
     persistence_mappings: Arc<RwLock<HashMap<String, BackendConfig>>>,
+    retry: RetryPolicy,
+    routes: Arc<Routes>,
+    timeouts: TimeoutConfig,
+    rate_limits: Arc<RateLimits>,
+    pool: Arc<Pool>,
+    metrics: Arc<Metrics>,
 }
 
 impl RequestHandler {
     pub fn new(
         addr: SocketAddr,
-        persistence_type: PersistenceType,
+        config: &Config,
         strategy: Threadable<Strategy>,
+        pool: Arc<Pool>,
+        metrics: Arc<Metrics>,
     ) -> Self {
+        let routes = Arc::new(Routes::new(&config.routes));
+        let rate_limits =
+            RateLimits::new(config.rate_limit.clone(), routes.clone(), metrics.clone());
         Self {
             addr,
-            persistence_type,
+            persistence_type: config.persistence_type,
             strategy,
             persistence_mappings: Arc::new(RwLock::new(HashMap::new())),
+            retry: RetryPolicy::new(config.retry.clone()),
+            routes,
+            timeouts: config.timeouts,
+            rate_limits: Arc::new(rate_limits),
+            pool,
+            metrics,
         }
     }
 
+    /// The server the session is stuck to, or else the one the strategy chooses. Sessions stuck
+    /// to a server which has since been removed from the pool choose a new one, while those stuck
+    /// to one of the `kept` servers, which are unavailable only for a while, stay with it.
     async fn get_server(
         strategy: Threadable<Strategy>,
         mappings: Threadable<HashMap<String, BackendConfig>>,
+        pool: &Pool,
         req_info: &RequestInfo,
         session_id: &String,
-    ) -> BackendConfig {
+        kept: &[String],
+    ) -> Option<BackendConfig> {
         let server = with_read_lock(mappings.clone(), |mappings| {
-            mappings.get(session_id).cloned()
+            mappings
+                .get(session_id)
+                .filter(|server| {
+                    !req_info.is_excluded(server) || kept.contains(&server.authority())
+                })
+                .and_then(|server| pool.current(server))
         });
         match server {
             Some(server) => {
-                println!("[Cached] Found server: {}.", server.ip());
-                server
+                debug!("[Cached] Found server: {}.", server.ip());
+                Some(server)
             },
             None => {
-                let mut strategy = strategy.write().expect("Couldn't acquire the lock.");
-                let server = strategy
-                    .server(req_info)
-                    .await
-                    .expect("Unable to retrieve server.");
+                let server = select_server(strategy, req_info).await?;
                 with_write_lock(mappings, |mappings| {
                     mappings.insert(session_id.clone(), server.clone())
                 });
-                println!("[No cache] Found server: {}.", server.ip());
-                server
+                debug!("[No cache] Found server: {}.", server.ip());
+                Some(server)
+            }
+        }
+    }
+
+    /// Choose a server among the available ones and reserve one of its connections, waiting in
+    /// the pool's queue while there are none.
+    async fn wait_for_server(
+        strategy: Threadable<Strategy>,
+        mappings: Threadable<HashMap<String, BackendConfig>>,
+        pool: &Arc<Pool>,
+        req_info: &mut RequestInfo,
+        session_id: &String,
+    ) -> Result<(BackendConfig, ConnectionGuard), UpstreamError> {
+        let mut queued: Option<QueueTicket> = None;
+        loop {
+            req_info.clear_exclusions();
+            req_info.set_loads(pool.loads());
+            // Sessions wait for a draining backend, or one at its connection limit, rather than
+            // moving to another.
+            let mut kept = Vec::new();
+            for backend in pool.unavailable() {
+                req_info.exclude(&backend.config);
+                if let ServerStatus::Draining | ServerStatus::Busy = backend.status {
+                    kept.push(backend.config.authority());
+                }
+            }
+            let server = Self::get_server(
+                strategy.clone(),
+                mappings.clone(),
+                pool,
+                req_info,
+                session_id,
+                &kept,
+            )
+            .await;
+            match server {
+                Some(server) => {
+                    // Another request may have taken the server's last connection since.
+                    if let Some(connection) = pool.reserve(&server) {
+                        return Ok((server, connection));
+                    }
+                }
+                None if !with_read_lock(strategy.clone(), |s| s.can_serve(req_info)) => {
+                    return Err(UpstreamError::Unroutable);
+                }
+                None => {}
+            }
+            match queued {
+                Some(ref mut ticket) => ticket.wait().await?,
+                // Whatever was freed before joining the queue wakes no one, so look once more.
+                None => queued = Some(pool.enqueue()?),
             }
         }
     }
 
     /// Forward `req` to a given server based on a previously chosen strategy.
+    #[allow(clippy::too_many_arguments)]
     async fn forward(
         req: HttpRequest,
         body: web::Bytes,
-        client: web::Data<Client>,
+        clients: web::Data<Clients>,
         strategy: web::Data<Threadable<Strategy>>,
         mappings: web::Data<Threadable<HashMap<String, BackendConfig>>>,
+        retry: web::Data<RetryPolicy>,
+        routes: web::Data<Arc<Routes>>,
+        timeouts: web::Data<TimeoutConfig>,
+        pool: web::Data<Arc<Pool>>,
+        metrics: web::Data<Arc<Metrics>>,
     ) -> Result<HttpResponse, Error> {
+        let permit = pool
+            .admit()
+            .await
+            .inspect_err(|e| metrics.incr(e.metric()))?;
         let strategy = strategy.get_ref().clone();
         let mappings = mappings.get_ref().clone();
-        let client_uri = if let Some(addr) = req.peer_addr() {
-            addr.to_string()
-        } else {
-            String::from("")
-        };
+        let client_uri = req.get_client_host();
         let session_id = req.get_session_id(&client_uri, &req.get_server_host());
-        let req_info = RequestInfo::new(req.uri().clone(), req.connection_info().clone());
-        let server = Self::get_server(strategy, mappings, &req_info, &session_id).await;
-        let uri = server.uri()?;
-        let mut forwarded_response = client
-            .request_from(uri, req.head())
-            .no_decompress()
-            .header(header::FORWARDED, client_uri)
-            .send_body(body)
-            .await
-            .map_err(Error::from)?;
+        let mut req_info = RequestInfo::new(req.uri().clone(), req.connection_info().clone());
+        let (server, connection) = Self::wait_for_server(
+            strategy.clone(),
+            mappings.clone(),
+            &pool,
+            &mut req_info,
+            &session_id,
+        )
+        .await
+        .inspect_err(|e| metrics.incr(e.metric()))?;
+        let upstream = Upstream {
+            clients: &clients,
+            req: &req,
+            body,
+            strategy,
+            route: routes.find(req.path()),
+            timeouts: *timeouts.get_ref(),
+            pool: &pool,
+            reserved: RefCell::new(Some(connection)),
+            metrics: &metrics,
+        };
+        let deadline = upstream.timeouts_for(&server).total;
+        let exchange = upstream.exchange(&mut req_info, server.clone(), &retry);
+        let exchange = match deadline {
+            Some(deadline) => timeout(deadline, exchange)
+                .await
+                .unwrap_or(Err(UpstreamError::DeadlineExceeded)),
+            None => exchange.await,
+        };
+        drop(permit);
+        if let Some(limiter) = pool.limiter() {
+            metrics.set("pool.concurrency_limit", limiter.limit() as u64);
+        }
+        let (served_by, mut forwarded_response, body) =
+            exchange.inspect_err(|e| metrics.incr(e.metric()))?;
+        if served_by.authority() != server.authority() {
+            with_write_lock(mappings, |mappings| {
+                mappings.insert(session_id.clone(), served_by.clone())
+            });
+        }
         let mut res = HttpResponse::build(forwarded_response.status());
 
         if !forwarded_response.has_cookie(COOKIE_SESSION_KEY) {
@@ -127,18 +262,37 @@ impl RequestHandler {
             res.cookie(cookie);
         }
 
-        Ok(res.body(forwarded_response.body().await?))
+        Ok(res.body(body))
     }
 
     pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
         let strat = self.strategy.clone();
         let mappings = self.persistence_mappings.clone();
-        println!("Waiting for packets on '{}'.", &self.addr);
+        let (retry, routes, timeouts, metrics) = (
+            self.retry.clone(),
+            self.routes.clone(),
+            self.timeouts,
+            self.metrics.clone(),
+        );
+        let (rate_limits, pool) = (self.rate_limits.clone(), self.pool.clone());
+        info!("Waiting for packets on '{}'.", &self.addr);
         HttpServer::new(move || {
             App::new()
-                .data(Client::new())
+                .data(Clients::default())
                 .data(strat.clone())
                 .data(mappings.clone())
+                .data(retry.clone())
+                .data(routes.clone())
+                .data(timeouts)
+                .data(pool.clone())
+                .data(metrics.clone())
+                .wrap_fn({
+                    let rate_limits = rate_limits.clone();
+                    move |req, srv| match rate_limits.check(&req) {
+                        Some(res) => Either::Left(ok(req.into_response(res))),
+                        None => Either::Right(srv.call(req)),
+                    }
+                })
                 .wrap(middleware::Logger::default())
                 .default_service(web::route().to(Self::forward))
         })
@@ -150,6 +304,284 @@ impl RequestHandler {
     }
 }
 
+/// HTTP clients keyed by their connect timeout, since it can only be set per client.
+#[derive(Default)]
+struct Clients {
+    clients: RefCell<HashMap<Option<Duration>, Client>>,
+}
+
+impl Clients {
+    fn get(&self, connect: Option<Duration>) -> Client {
+        self.clients
+            .borrow_mut()
+            .entry(connect)
+            .or_insert_with(|| {
+                let mut connector = Connector::new();
+                if let Some(connect) = connect {
+                    connector = connector.timeout(connect);
+                }
+                Client::build().connector(connector.finish()).finish()
+            })
+            .clone()
+    }
+}
+
+/// A client request on its way to the backends, which may be sent more than once.
+struct Upstream<'a> {
+    clients: &'a Clients,
+    req: &'a HttpRequest,
+    body: web::Bytes,
+    strategy: Threadable<Strategy>,
+    route: Option<Arc<Route>>,
+    timeouts: TimeoutConfig,
+    pool: &'a Arc<Pool>,
+    /// The connection reserved for the first attempt while choosing its server.
+    reserved: RefCell<Option<ConnectionGuard>>,
+    metrics: &'a Metrics,
+}
+
+impl<'a> Upstream<'a> {
+    /// The timeouts which apply when sending the request to `server`.
+    fn timeouts_for(&self, server: &BackendConfig) -> TimeoutConfig {
+        let timeouts = server.timeouts.or(&self.timeouts);
+        match self.route {
+            Some(ref route) => route.config.timeouts.or(&timeouts),
+            None => timeouts,
+        }
+    }
+
+    /// Send a single attempt of the request to `server`.
+    async fn send(
+        &self,
+        server: BackendConfig,
+        per_try_timeout: Option<Duration>,
+    ) -> Result<Attempt, UpstreamError> {
+        let reserved = self.reserved.borrow_mut().take();
+        let connection = reserved
+            .filter(|connection| connection.authority() == server.authority())
+            .or_else(|| self.pool.reserve(&server))
+            .ok_or(UpstreamError::Busy)?;
+        let timeouts = self.timeouts_for(&server);
+        let header_timeout = timeouts.response_header;
+        let uri = server.uri().map_err(SendRequestError::Http)?;
+        let mut request = self
+            .clients
+            .get(timeouts.connect)
+            .request_from(uri, self.req.head())
+            .no_decompress()
+            .header(header::FORWARDED, self.req.get_client_host());
+        if let Some(header_timeout) = header_timeout {
+            request = request.timeout(header_timeout);
+        }
+        let (route, start) = (self.route.clone(), Instant::now());
+        let attempt = request
+            .send_body(self.body.clone())
+            .timed(|result, elapsed| {
+                if let (Some(route), Ok(_)) = (&route, result) {
+                    route.latencies.record(elapsed);
+                }
+            });
+        // The header timeout is left to awc so that whichever of the two is shorter is reported.
+        let per_try_timeout = match (per_try_timeout, header_timeout) {
+            (Some(per_try), Some(header)) if per_try >= header => None,
+            (per_try, _) => per_try,
+        };
+        let res = match per_try_timeout {
+            Some(per_try) => match timeout(per_try, attempt).await {
+                Ok(res) => res.map_err(UpstreamError::from),
+                Err(_) => Err(UpstreamError::PerTryTimeout),
+            },
+            None => attempt.await.map_err(UpstreamError::from),
+        };
+        if let Some(limiter) = self.pool.limiter() {
+            let outcome = res.as_ref().map(|res| res.status());
+            limiter.record(start.elapsed(), dropped(outcome));
+        }
+        Ok((res?, connection))
+    }
+
+    /// Send an attempt to `server`, and if the route allows hedging and it hasn't answered in
+    /// time, send a second attempt to another server. Whichever answers first successfully wins
+    /// and the other attempt is cancelled.
+    async fn send_hedged(
+        &self,
+        req_info: &RequestInfo,
+        server: BackendConfig,
+        timeout: Option<Duration>,
+    ) -> (BackendConfig, Result<Attempt, UpstreamError>) {
+        let hedge = self.route.as_ref().and_then(|route| {
+            let budget = route.hedge_budget.clone()?;
+            Some((route.hedge_delay()?, budget))
+        });
+        let (delay, budget) = match hedge {
+            Some(hedge) if is_idempotent(self.req.method()) => hedge,
+            _ => {
+                let result = self.send(server.clone(), timeout).await;
+                return (server, result);
+            }
+        };
+
+        let _active = budget.start_request();
+        let primary = Box::pin(self.send(server.clone(), timeout));
+        let primary = match select(primary, delay_for(delay)).await {
+            Either::Left((result, _)) => return (server, result),
+            Either::Right((_, primary)) => primary,
+        };
+        let _hedge = match budget.try_retry() {
+            Some(guard) => guard,
+            None => {
+                self.metrics.incr("hedge.budget_exhausted");
+                return (server, primary.await);
+            }
+        };
+        let mut hedge_info = req_info.clone();
+        hedge_info.exclude(&server);
+        let hedge_server = match select_server(self.strategy.clone(), &hedge_info).await {
+            Some(hedge_server) => hedge_server,
+            None => return (server, primary.await),
+        };
+
+        self.metrics.incr("hedge.sent");
+        let hedged = Box::pin(self.send(hedge_server.clone(), timeout));
+        match select(primary, hedged).await {
+            Either::Left((Ok(res), _)) => (server, Ok(res)),
+            Either::Left((Err(_), hedged)) => {
+                self.metrics.incr("hedge.won");
+                (hedge_server, hedged.await)
+            }
+            Either::Right((Ok(res), _)) => {
+                self.metrics.incr("hedge.won");
+                (hedge_server, Ok(res))
+            }
+            Either::Right((Err(_), primary)) => (server, primary.await),
+        }
+    }
+
+    /// Records the load `server` reported in its response, if it did.
+    fn report_load(&self, server: &BackendConfig, res: &UpstreamResponse) {
+        let load = res
+            .headers()
+            .get(self.pool.config().load_header.as_str())
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<f64>().ok());
+        if let Some(load) = load.filter(|load| load.is_finite()) {
+            self.pool.report_load(server, load);
+        }
+    }
+
+    /// Marks `server` as throttled if it answered with a `Retry-After` header.
+    fn throttle(&self, server: &BackendConfig, res: &UpstreamResponse) -> bool {
+        let retry_after = res
+            .headers()
+            .get(header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok());
+        match throttled_for(res.status(), retry_after, SystemTime::now()) {
+            Some(duration) => {
+                self.pool.throttle(server, duration);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Send the request upstream, retrying on a different server when the attempt fails and the
+    /// retry policy and budget allow it.
+    async fn send_with_retries(
+        &self,
+        req_info: &mut RequestInfo,
+        mut server: BackendConfig,
+        policy: &RetryPolicy,
+    ) -> (BackendConfig, Result<Attempt, UpstreamError>) {
+        let (retry, budget) = (&policy.config, &policy.budget);
+        let _active = budget.start_request();
+        let per_try_timeout = retry.per_try_timeout;
+        let can_retry = retry.allow_non_idempotent || is_idempotent(self.req.method());
+        let mut attempt = 0;
+        let mut _retry = None;
+
+        loop {
+            let (served_by, result) = self.send_hedged(req_info, server, per_try_timeout).await;
+            server = served_by;
+            let failed = match result {
+                Ok((ref res, _)) => {
+                    self.report_load(&server, res);
+                    let throttled = self.throttle(&server, res);
+                    retry.on_status.contains(&res.status().as_u16())
+                        || (throttled && retry.on_throttled)
+                }
+                Err(ref e) => is_retryable_error(e),
+            };
+            if !failed || !can_retry || attempt >= retry.attempts {
+                return (server, result);
+            }
+
+            _retry = match budget.try_retry() {
+                Some(guard) => Some(guard),
+                None => {
+                    self.metrics.incr("retry.budget_exhausted");
+                    warn!(
+                        "Retry budget exhausted; not retrying request to {}.",
+                        server.authority()
+                    );
+                    return (server, result);
+                }
+            };
+            req_info.exclude(&server);
+            server = match select_server(self.strategy.clone(), req_info).await {
+                Some(next) => next,
+                None => return (server, result),
+            };
+            attempt += 1;
+            self.metrics.incr("retry.attempts");
+            info!(
+                "Retrying request on {} (attempt {}).",
+                server.authority(),
+                attempt + 1
+            );
+        }
+    }
+
+    /// Send the request upstream and read the whole response body.
+    async fn exchange(
+        &self,
+        req_info: &mut RequestInfo,
+        server: BackendConfig,
+        policy: &RetryPolicy,
+    ) -> Result<(BackendConfig, UpstreamResponse, web::Bytes), UpstreamError> {
+        let (served_by, result) = self.send_with_retries(req_info, server, policy).await;
+        let (mut res, _connection) = result?;
+        let idle = self.timeouts_for(&served_by).idle_body;
+        let mut body = web::BytesMut::new();
+        loop {
+            let chunk = match idle {
+                Some(idle) => timeout(idle, res.next())
+                    .await
+                    .map_err(|_| UpstreamError::IdleBodyTimeout)?,
+                None => res.next().await,
+            };
+            match chunk {
+                Some(chunk) => {
+                    let chunk = chunk?;
+                    if body.len() + chunk.len() > MAX_BODY_SIZE {
+                        return Err(PayloadError::Overflow.into());
+                    }
+                    body.extend_from_slice(&chunk);
+                }
+                None => return Ok((served_by, res, body.freeze())),
+            }
+        }
+    }
+}
+
+/// Ask the strategy for a server, ignoring any session that may already exist.
+async fn select_server(
+    strategy: Threadable<Strategy>,
+    req_info: &RequestInfo,
+) -> Option<BackendConfig> {
+    let mut strategy = strategy.write().expect("Couldn't acquire the lock.");
+    strategy.server(req_info).await
+}
+
 trait HasCookie {
     fn has_cookie(&mut self, cookie_key: &str) -> bool;
 }
@@ -173,6 +605,7 @@ impl<T> HasCookie for ClientResponse<T> {
 trait RequestProxy {
     fn get_session_id(&self, client_host: &String, server_host: &String) -> String;
     fn get_server_host(&self) -> String;
+    fn get_client_host(&self) -> String;
 }
 
 impl RequestProxy for HttpRequest {
@@ -215,4 +648,124 @@ impl RequestProxy for HttpRequest {
             .unwrap()
             .to_string()
     }
+
+    fn get_client_host(&self) -> String {
+        self.peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{config::ConcurrencyConfig, events::Events},
+        actix_web::test::TestRequest,
+        tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpListener,
+        },
+    };
+
+    /// A backend which sends its response headers at once and its body after `body_delay`.
+    async fn slow_body(body_delay: Duration) -> u16 {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        actix_rt::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n")
+                    .await;
+                delay_for(body_delay).await;
+                let _ = stream.write_all(b"ok").await;
+            }
+        });
+        port
+    }
+
+    #[actix_rt::test]
+    async fn test_sessions_wait_for_their_busy_backend() {
+        let busy = BackendConfig {
+            port: 1,
+            max_connections: Some(1),
+            ..BackendConfig::default()
+        };
+        let mut config = Config::default();
+        config.backends.insert(String::from("a"), busy.clone());
+        config.backends.insert(
+            String::from("b"),
+            BackendConfig {
+                port: 2,
+                ..BackendConfig::default()
+            },
+        );
+        let metrics = Arc::new(Metrics::default());
+        let (events, _) = Events::new(&config.events, metrics.clone());
+        let pool = Arc::new(Pool::new(&config, Arc::new(events), metrics));
+        let mut strategy = config.strategy.clone();
+        strategy.configure(&config);
+        let session = String::from("session");
+        let mut mappings = HashMap::new();
+        mappings.insert(session.clone(), busy.clone());
+        let mappings = Arc::new(RwLock::new(mappings));
+        let req = TestRequest::default().to_http_request();
+        let mut req_info = RequestInfo::new(req.uri().clone(), req.connection_info().clone());
+
+        let held = pool.reserve(&busy).unwrap();
+        let release = async {
+            delay_for(Duration::from_millis(20)).await;
+            drop(held);
+        };
+        let (result, _) = futures::join!(
+            RequestHandler::wait_for_server(
+                Arc::new(RwLock::new(strategy)),
+                mappings.clone(),
+                &pool,
+                &mut req_info,
+                &session,
+            ),
+            release
+        );
+        let (server, _connection) = result.unwrap();
+        assert_eq!(server.authority(), busy.authority());
+        assert_eq!(
+            mappings.read().unwrap()[&session].authority(),
+            busy.authority()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_latency_is_sampled_per_attempt() {
+        let port = slow_body(Duration::from_millis(300)).await;
+        let server = BackendConfig {
+            port,
+            ..BackendConfig::default()
+        };
+        let mut config = Config::default();
+        config.backends.insert(String::from("a"), server.clone());
+        config.pool.concurrency = Some(ConcurrencyConfig::default());
+        let metrics = Arc::new(Metrics::default());
+        let (events, _) = Events::new(&config.events, metrics.clone());
+        let pool = Arc::new(Pool::new(&config, Arc::new(events), metrics.clone()));
+        let (clients, req) = (Clients::default(), TestRequest::default().to_http_request());
+        let upstream = Upstream {
+            clients: &clients,
+            req: &req,
+            body: web::Bytes::new(),
+            strategy: Arc::new(RwLock::new(config.strategy.clone())),
+            route: None,
+            timeouts: TimeoutConfig::default(),
+            pool: &pool,
+            reserved: RefCell::new(None),
+            metrics: &metrics,
+        };
+
+        let (mut res, _connection) = upstream.send(server, None).await.unwrap();
+        assert_eq!(res.body().await.unwrap(), "ok");
+        let latency = pool.limiter().unwrap().min_latency().unwrap();
+        assert!(latency < Duration::from_millis(200), "{:?}", latency);
+    }
 }
@@ -0,0 +1,190 @@
+use {
+    crate::{config::RetryConfig, error::UpstreamError},
+    actix_web::http::{header::HttpDate, Method, StatusCode},
+    std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, SystemTime},
+    },
+};
+
+/// Methods which can safely be sent more than once without changing the outcome.
+pub fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+/// Whether a failed send is worth retrying on another backend.
+/// Connection failures, timed out attempts and backends at their connection limit are retried;
+/// anything else points at the request itself.
+pub fn is_retryable_error(e: &UpstreamError) -> bool {
+    matches!(
+        *e,
+        UpstreamError::Connect(_)
+            | UpstreamError::ConnectTimeout
+            | UpstreamError::ResponseHeaderTimeout
+            | UpstreamError::PerTryTimeout
+            | UpstreamError::Busy
+    )
+}
+
+/// How long a backend that answered with `status` asked to be left alone, if it did.
+/// `Retry-After` may either be a number of seconds or an HTTP date.
+pub fn throttled_for(
+    status: StatusCode,
+    retry_after: Option<&str>,
+    now: SystemTime,
+) -> Option<Duration> {
+    if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE {
+        return None;
+    }
+    let retry_after = retry_after?.trim();
+    match retry_after.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            let date: SystemTime = retry_after.parse::<HttpDate>().ok()?.into();
+            Some(date.duration_since(now).unwrap_or_default())
+        }
+    }
+}
+
+/// The configured retry behaviour together with the budget shared by all requests.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub config: RetryConfig,
+    pub budget: Arc<RetryBudget>,
+}
+
+impl RetryPolicy {
+    pub fn new(config: RetryConfig) -> Self {
+        let budget = RetryBudget::new(config.budget_percent, config.min_concurrent_retries);
+        Self {
+            config,
+            budget: Arc::new(budget),
+        }
+    }
+}
+
+/// Limits the number of retries in flight to a percentage of the active requests.
+/// ****************************************************************************
+/// Without a budget, every request to a failing backend is multiplied by the number of attempts,
+/// which turns a partial outage into a retry storm against the remaining backends.
+/// ****************************************************************************
+#[derive(Debug)]
+pub struct RetryBudget {
+    percent: f64,
+    min_concurrent: usize,
+    active: AtomicUsize,
+    retries: AtomicUsize,
+}
+
+impl RetryBudget {
+    pub fn new(percent: f64, min_concurrent: usize) -> Self {
+        Self {
+            percent,
+            min_concurrent,
+            active: AtomicUsize::new(0),
+            retries: AtomicUsize::new(0),
+        }
+    }
+
+    /// Marks a request as active until the returned guard is dropped.
+    pub fn start_request(self: &Arc<Self>) -> ActiveRequest {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        ActiveRequest(self.clone())
+    }
+
+    /// Reserves a retry if the budget allows it. The retry is released when the guard is dropped.
+    pub fn try_retry(self: &Arc<Self>) -> Option<ActiveRetry> {
+        let retries = self.retries.fetch_add(1, Ordering::SeqCst) + 1;
+        if retries <= self.limit() {
+            Some(ActiveRetry(self.clone()))
+        } else {
+            self.retries.fetch_sub(1, Ordering::SeqCst);
+            None
+        }
+    }
+
+    fn limit(&self) -> usize {
+        let active = self.active.load(Ordering::SeqCst) as f64;
+        let allowed = (active * self.percent / 100.0) as usize;
+        allowed.max(self.min_concurrent)
+    }
+}
+
+pub struct ActiveRequest(Arc<RetryBudget>);
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub struct ActiveRetry(Arc<RetryBudget>);
+
+impl Drop for ActiveRetry {
+    fn drop(&mut self) {
+        self.0.retries.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idempotent_methods() {
+        assert!(is_idempotent(&Method::GET));
+        assert!(is_idempotent(&Method::PUT));
+        assert!(!is_idempotent(&Method::POST));
+        assert!(!is_idempotent(&Method::PATCH));
+    }
+
+    #[test]
+    fn test_throttled_for() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777);
+        let throttled = |status, value| throttled_for(status, value, now);
+        assert_eq!(
+            throttled(StatusCode::SERVICE_UNAVAILABLE, Some("120")),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            throttled(
+                StatusCode::TOO_MANY_REQUESTS,
+                Some("Sun, 06 Nov 1994 08:49:47 GMT")
+            ),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(throttled(StatusCode::TOO_MANY_REQUESTS, Some("soon")), None);
+        assert_eq!(throttled(StatusCode::TOO_MANY_REQUESTS, None), None);
+        assert_eq!(throttled(StatusCode::BAD_GATEWAY, Some("120")), None);
+    }
+
+    #[test]
+    fn test_budget_respects_minimum() {
+        let budget = Arc::new(RetryBudget::new(0.0, 1));
+        let first = budget.try_retry();
+        assert!(first.is_some());
+        assert!(budget.try_retry().is_none());
+
+        drop(first);
+        assert!(budget.try_retry().is_some());
+    }
+
+    #[test]
+    fn test_budget_scales_with_active_requests() {
+        let budget = Arc::new(RetryBudget::new(50.0, 0));
+        let requests = (0..4).map(|_| budget.start_request()).collect::<Vec<_>>();
+        let retries = (0..3)
+            .filter_map(|_| budget.try_retry())
+            .collect::<Vec<_>>();
+        assert_eq!(retries.len(), 2);
+
+        drop(requests);
+        assert!(budget.try_retry().is_none());
+    }
+}
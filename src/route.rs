@@ -0,0 +1,133 @@
+use {
+    crate::{
+        algorithm::trie::Trie,
+        config::{HedgeConfig, RouteConfig},
+        metrics::LatencyWindow,
+        ratelimit::RateLimiter,
+        retry::RetryBudget,
+    },
+    std::{collections::HashMap, sync::Arc, time::Duration},
+};
+
+/// Minimum number of observed latencies before a percentile based hedge delay is trusted.
+const MIN_LATENCY_SAMPLES: usize = 20;
+
+/// A configured route together with the state it accumulates while serving requests.
+#[derive(Debug)]
+pub struct Route {
+    pub name: String,
+    pub config: RouteConfig,
+    pub hedge_budget: Option<Arc<RetryBudget>>,
+    pub rate_limiter: Option<RateLimiter>,
+    pub latencies: LatencyWindow,
+}
+
+impl Route {
+    pub fn new(name: String, config: RouteConfig) -> Self {
+        let hedge_budget = config.hedge.as_ref().map(|hedge| {
+            Arc::new(RetryBudget::new(
+                hedge.budget_percent,
+                hedge.min_concurrent_hedges,
+            ))
+        });
+        let rate_limiter = config.rate_limit.clone().map(RateLimiter::new);
+        Self {
+            name,
+            config,
+            hedge_budget,
+            rate_limiter,
+            latencies: LatencyWindow::default(),
+        }
+    }
+
+    pub fn hedge(&self) -> Option<&HedgeConfig> {
+        self.config.hedge.as_ref()
+    }
+
+    /// How long to wait for the first attempt before sending a hedged one.
+    pub fn hedge_delay(&self) -> Option<Duration> {
+        let hedge = self.hedge()?;
+        let observed = hedge.percentile.and_then(|percentile| {
+            if self.latencies.len() >= MIN_LATENCY_SAMPLES {
+                self.latencies.percentile(percentile)
+            } else {
+                None
+            }
+        });
+        Some(observed.unwrap_or(hedge.delay))
+    }
+}
+
+/// Matches request paths to the route with the longest matching path prefix.
+#[derive(Debug, Default)]
+pub struct Routes {
+    paths: Trie,
+    routes: HashMap<String, Arc<Route>>,
+}
+
+impl Routes {
+    pub fn new(configs: &HashMap<String, RouteConfig>) -> Self {
+        let mut routes = Self::default();
+        let mut configs = configs.iter().collect::<Vec<_>>();
+        configs.sort_by_key(|(name, _)| name.as_str());
+        for (name, config) in configs {
+            // Validation rejects routes with the same path, but keep the first regardless.
+            if routes.routes.contains_key(&config.path) {
+                continue;
+            }
+            routes.paths.insert(config.path.as_str());
+            routes.routes.insert(
+                config.path.clone(),
+                Arc::new(Route::new(name.clone(), config.clone())),
+            );
+        }
+        routes
+    }
+
+    pub fn find(&self, path: &str) -> Option<Arc<Route>> {
+        self.paths
+            .longest_match(path)
+            .and_then(|prefix| self.routes.get(&prefix).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(path: &str, hedge: Option<HedgeConfig>) -> RouteConfig {
+        RouteConfig {
+            path: path.to_string(),
+            hedge,
+            ..RouteConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_find_longest_route() {
+        let mut configs = HashMap::new();
+        configs.insert("api".to_string(), route("/api", None));
+        configs.insert("users".to_string(), route("/api/users", None));
+        let routes = Routes::new(&configs);
+
+        assert_eq!(routes.find("/api/users/1").unwrap().name, "users");
+        assert_eq!(routes.find("/api/orders").unwrap().name, "api");
+        assert!(routes.find("/static").is_none());
+    }
+
+    #[test]
+    fn test_hedge_delay_uses_percentile_once_warm() {
+        let hedge = HedgeConfig {
+            delay: Duration::from_millis(100),
+            percentile: Some(90.0),
+            ..HedgeConfig::default()
+        };
+        let route = Route::new("reads".to_string(), route("/", Some(hedge)));
+        assert_eq!(route.hedge_delay(), Some(Duration::from_millis(100)));
+
+        for _ in 0..MIN_LATENCY_SAMPLES {
+            route.latencies.record(Duration::from_millis(10));
+        }
+        assert_eq!(route.hedge_delay(), Some(Duration::from_millis(10)));
+    }
+}
@@ -0,0 +1,201 @@
+use {
+    crate::config::Config,
+    schemars::{
+        gen::{SchemaGenerator, SchemaSettings},
+        schema::{
+            InstanceType, Metadata, NumberValidation, RootSchema, Schema, SchemaObject,
+            StringValidation, SubschemaValidation,
+        },
+        visit::{self, Visitor},
+        JsonSchema,
+    },
+    std::time,
+};
+
+/// A port, given as a number or as a string of digits.
+pub struct Port;
+
+impl JsonSchema for Port {
+    fn schema_name() -> String {
+        String::from("Port")
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        let number = SchemaObject {
+            instance_type: Some(InstanceType::Integer.into()),
+            number: Some(Box::new(NumberValidation {
+                minimum: Some(0.0),
+                maximum: Some(f64::from(u16::MAX)),
+                ..NumberValidation::default()
+            })),
+            ..SchemaObject::default()
+        };
+        let string = SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            string: Some(Box::new(StringValidation {
+                pattern: Some(String::from("^[0-9]+$")),
+                ..StringValidation::default()
+            })),
+            ..SchemaObject::default()
+        };
+        any_of("A port number.", vec![number.into(), string.into()])
+    }
+}
+
+/// A duration such as `"250ms"` or `"5s"`, or a whole number of seconds.
+pub struct Duration;
+
+impl JsonSchema for Duration {
+    fn schema_name() -> String {
+        String::from("Duration")
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        let seconds = SchemaObject {
+            instance_type: Some(InstanceType::Integer.into()),
+            number: Some(Box::new(NumberValidation {
+                minimum: Some(0.0),
+                ..NumberValidation::default()
+            })),
+            ..SchemaObject::default()
+        };
+        let string = SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            ..SchemaObject::default()
+        };
+        any_of(
+            "A duration such as \"250ms\" or \"5s\", or a whole number of seconds.",
+            vec![seconds.into(), string.into()],
+        )
+    }
+}
+
+fn any_of(description: &str, schemas: Vec<Schema>) -> Schema {
+    SchemaObject {
+        metadata: Some(Box::new(Metadata {
+            description: Some(description.to_string()),
+            ..Metadata::default()
+        })),
+        subschemas: Some(Box::new(SubschemaValidation {
+            any_of: Some(schemas),
+            ..SubschemaValidation::default()
+        })),
+        ..SchemaObject::default()
+    }
+    .into()
+}
+
+/// Disallows keys which aren't known, as validating a config does.
+#[derive(Debug, Clone)]
+struct DenyUnknownKeys;
+
+impl Visitor for DenyUnknownKeys {
+    fn visit_schema_object(&mut self, schema: &mut SchemaObject) {
+        if let Some(ref mut object) = schema.object {
+            if !object.properties.is_empty() && object.additional_properties.is_none() {
+                object.additional_properties = Some(Box::new(Schema::Bool(false)));
+            }
+        }
+        visit::visit_schema_object(self, schema);
+    }
+}
+
+/// Shows the defaults of durations as they're written in configs, e.g. `"5s"`, rather than as
+/// `std::time::Duration` serializes them.
+#[derive(Debug, Clone)]
+struct DurationDefaults;
+
+impl Visitor for DurationDefaults {
+    fn visit_schema_object(&mut self, schema: &mut SchemaObject) {
+        let reference = Some("#/definitions/Duration");
+        let is_duration = schema
+            .subschemas
+            .as_ref()
+            .and_then(|subschemas| subschemas.all_of.as_ref())
+            .is_some_and(|all_of| {
+                all_of.iter().any(|subschema| {
+                    matches!(subschema, Schema::Object(object) if object.reference.as_deref() == reference)
+                })
+            });
+        if let (true, Some(metadata)) = (is_duration, schema.metadata.as_mut()) {
+            if let Some(ref mut default) = metadata.default {
+                if let Ok(duration) = serde_json::from_value::<time::Duration>(default.clone()) {
+                    *default = humantime::format_duration(duration).to_string().into();
+                }
+            }
+        }
+        visit::visit_schema_object(self, schema);
+    }
+}
+
+/// A JSON Schema of configs, whichever format they're written in.
+pub fn schema() -> RootSchema {
+    let mut schema = SchemaSettings::draft07()
+        .with_visitor(DenyUnknownKeys)
+        .with_visitor(DurationDefaults)
+        .into_generator()
+        .into_root_schema_for::<Config>();
+    schema.schema.metadata().title = Some(String::from("loblaw config"));
+    schema
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, serde_json::Value};
+
+    #[test]
+    fn test_schema() {
+        let schema = serde_json::to_value(schema()).unwrap();
+        let definitions = &schema["definitions"];
+        assert_eq!(schema["title"], "loblaw config");
+        assert_eq!(schema["additionalProperties"], false);
+        assert_eq!(
+            schema["properties"]["port"]["allOf"][0]["$ref"],
+            "#/definitions/Port"
+        );
+        assert_eq!(
+            schema["properties"]["backends"]["additionalProperties"]["$ref"],
+            "#/definitions/BackendConfig"
+        );
+        assert_eq!(
+            definitions["BackendConfig"]["properties"]["ip"]["description"],
+            "Host name or IP address of the backend."
+        );
+        let interval = &definitions["HealthCheckConfig"]["properties"]["interval"];
+        assert_eq!(interval["allOf"][0]["$ref"], "#/definitions/Duration");
+        assert_eq!(interval["default"], "5s");
+
+        let persistence = definitions["PersistenceType"]["oneOf"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|variant| {
+                (
+                    variant["enum"][0].as_str().unwrap(),
+                    variant["description"].as_str().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            persistence[0],
+            (
+                "Cookie",
+                "Requests with the same cookie will be routed to the same server."
+            )
+        );
+
+        let strategy = &definitions["Strategy"]["oneOf"];
+        assert!(strategy[0]["enum"]
+            .as_array()
+            .unwrap()
+            .contains(&Value::from("LeastLatency")));
+        assert_eq!(
+            strategy[1]["properties"]["LeastLatency"]["$ref"],
+            "#/definitions/LeastLatency"
+        );
+        assert_eq!(
+            strategy[1]["properties"].as_object().unwrap().len(),
+            strategy[0]["enum"].as_array().unwrap().len()
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use {
+    crate::config::{TcpCheckConfig, TcpCheckPattern, TcpCheckPayload, TcpCheckStep},
+    regex::bytes::Regex,
+    std::time::Duration,
+    tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        net::TcpStream,
+        time::timeout,
+    },
+};
+
+/// Largest amount of data an `expect` step reads while looking for its pattern.
+const MAX_EXPECT_SIZE: usize = 64 * 1024;
+
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// Decodes hex encoded bytes, ignoring whitespace.
+pub fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+    let digits = hex
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<Vec<_>>();
+    if digits.len() % 2 != 0 {
+        return Err(format!("'{}' has an odd number of hex digits", hex));
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair = pair.iter().collect::<String>();
+            u8::from_str_radix(&pair, 16).map_err(|_| format!("'{}' is not valid hex", hex))
+        })
+        .collect()
+}
+
+enum Pattern {
+    Bytes(Vec<u8>),
+    Regex(Regex),
+}
+
+impl Pattern {
+    /// The end of the first match in `data`, if any.
+    fn find(&self, data: &[u8]) -> Option<usize> {
+        match *self {
+            Pattern::Bytes(ref bytes) if bytes.is_empty() => Some(0),
+            Pattern::Bytes(ref bytes) => data
+                .windows(bytes.len())
+                .position(|window| window == bytes.as_slice())
+                .map(|start| start + bytes.len()),
+            Pattern::Regex(ref regex) => regex.find(data).map(|found| found.end()),
+        }
+    }
+}
+
+enum Step {
+    Connect {
+        tls: bool,
+        insecure: bool,
+        server_name: Option<String>,
+    },
+    Send(Vec<u8>),
+    Expect(Pattern, String),
+}
+
+/// A compiled `tcp-check` sequence, ready to be run against a backend.
+pub struct Script {
+    steps: Vec<Step>,
+    expect_timeout: Duration,
+}
+
+impl Script {
+    pub fn new(config: &TcpCheckConfig) -> Result<Self, String> {
+        let steps = config
+            .steps
+            .iter()
+            .map(|step| {
+                Ok(match *step {
+                    TcpCheckStep::Connect {
+                        tls,
+                        insecure,
+                        ref server_name,
+                    } => Step::Connect {
+                        tls,
+                        insecure,
+                        server_name: server_name.clone(),
+                    },
+                    TcpCheckStep::Send(TcpCheckPayload::Text(ref text)) => {
+                        Step::Send(text.as_bytes().to_vec())
+                    }
+                    TcpCheckStep::Send(TcpCheckPayload::Hex(ref hex)) => {
+                        Step::Send(decode_hex(hex)?)
+                    }
+                    TcpCheckStep::Expect(ref pattern) => {
+                        let compiled = match *pattern {
+                            TcpCheckPattern::String(ref string) => {
+                                Pattern::Bytes(string.as_bytes().to_vec())
+                            }
+                            TcpCheckPattern::Binary(ref hex) => Pattern::Bytes(decode_hex(hex)?),
+                            TcpCheckPattern::Regex(ref regex) => Pattern::Regex(
+                                Regex::new(regex).map_err(|e| format!("invalid regex: {}", e))?,
+                            ),
+                        };
+                        Step::Expect(compiled, format!("{:?}", pattern))
+                    }
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Self {
+            steps,
+            expect_timeout: config.expect_timeout,
+        })
+    }
+
+    /// Runs every step against `ip:port`, describing the last step on success or the failing one
+    /// otherwise.
+    pub async fn run(&self, ip: &str, port: u16) -> Result<String, String> {
+        let addr = format!("{}:{}", ip, port);
+        let mut connection: Option<Box<dyn Connection>> = None;
+        let mut received = Vec::new();
+        let mut detail = String::from("no steps");
+
+        for step in self.steps.iter() {
+            if let Step::Connect {
+                tls,
+                insecure,
+                ref server_name,
+            } = *step
+            {
+                let name = server_name.as_deref().unwrap_or(ip);
+                connection = Some(Self::connect(&addr, tls, insecure, name).await?);
+                received.clear();
+                detail = format!("connected to {}", addr);
+                continue;
+            }
+            let stream = match connection {
+                Some(ref mut stream) => stream,
+                None => connection.get_or_insert(Self::connect(&addr, false, false, ip).await?),
+            };
+            match *step {
+                Step::Send(ref bytes) => {
+                    stream
+                        .write_all(bytes)
+                        .await
+                        .map_err(|e| format!("send failed: {}", e))?;
+                    detail = format!("sent {} bytes", bytes.len());
+                }
+                Step::Expect(ref pattern, ref description) => {
+                    self.expect(stream, &mut received, pattern, description)
+                        .await?;
+                    detail = format!("matched {}", description);
+                }
+                Step::Connect { .. } => {}
+            }
+        }
+        Ok(detail)
+    }
+
+    async fn connect(
+        addr: &str,
+        tls: bool,
+        insecure: bool,
+        server_name: &str,
+    ) -> Result<Box<dyn Connection>, String> {
+        let stream = TcpStream::connect(addr)
+            .await
+            .map_err(|e| format!("connect to {} failed: {}", addr, e))?;
+        if !tls {
+            return Ok(Box::new(stream));
+        }
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(insecure)
+            .danger_accept_invalid_hostnames(insecure)
+            .build()
+            .map_err(|e| format!("TLS setup failed: {}", e))?;
+        let stream = tokio_tls::TlsConnector::from(connector)
+            .connect(server_name, stream)
+            .await
+            .map_err(|e| format!("TLS handshake with {} failed: {}", addr, e))?;
+        Ok(Box::new(stream))
+    }
+
+    /// Reads until `pattern` appears, consuming the data up to the end of the match.
+    async fn expect(
+        &self,
+        stream: &mut Box<dyn Connection>,
+        received: &mut Vec<u8>,
+        pattern: &Pattern,
+        description: &str,
+    ) -> Result<(), String> {
+        let mut buf = [0; 4096];
+        let read = async {
+            loop {
+                if let Some(end) = pattern.find(received) {
+                    received.drain(..end);
+                    return Ok(());
+                }
+                if received.len() >= MAX_EXPECT_SIZE {
+                    return Err(format!(
+                        "{} not found in {} bytes",
+                        description,
+                        received.len()
+                    ));
+                }
+                match stream.read(&mut buf).await {
+                    Ok(0) => {
+                        return Err(format!(
+                            "connection closed before {} was received",
+                            description
+                        ))
+                    }
+                    Ok(n) => received.extend_from_slice(&buf[..n]),
+                    Err(e) => return Err(format!("read failed: {}", e)),
+                }
+            }
+        };
+        timeout(self.expect_timeout, read).await.map_err(|_| {
+            format!(
+                "timed out after {}ms waiting for {}",
+                self.expect_timeout.as_millis(),
+                description
+            )
+        })?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, tokio::net::TcpListener};
+
+    fn script(toml: &str) -> Script {
+        let config: TcpCheckConfig = toml::from_str(toml).unwrap();
+        Script::new(&config).unwrap()
+    }
+
+    /// A stand-in for Redis, answering `PING` with `+PONG` unless it is hung.
+    async fn redis(hung: bool) -> u16 {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 64];
+            let n = stream.read(&mut buf).await.unwrap();
+            if !hung && &buf[..n] == b"PING\r\n" {
+                stream.write_all(b"+PONG\r\n").await.unwrap();
+            }
+            tokio::time::delay_for(Duration::from_secs(1)).await;
+        });
+        port
+    }
+
+    const PING: &str = r#"
+        expect_timeout = "200ms"
+        steps = [
+            { action = "connect" },
+            { action = "send", text = "PING\r\n" },
+            { action = "expect", regex = "^\\+PONG" },
+        ]
+    "#;
+
+    #[test]
+    fn test_decode_hex() {
+        assert_eq!(decode_hex("2a31 0d0a"), Ok(vec![0x2a, 0x31, 0x0d, 0x0a]));
+        assert!(decode_hex("2a3").is_err());
+        assert!(decode_hex("zz").is_err());
+    }
+
+    #[test]
+    fn test_pattern() {
+        let bytes = Pattern::Bytes(b"PONG".to_vec());
+        assert_eq!(bytes.find(b"+PONG\r\n"), Some(5));
+        assert_eq!(bytes.find(b"+PON"), None);
+        let regex = Pattern::Regex(Regex::new("^220 .*\r\n").unwrap());
+        assert_eq!(regex.find(b"220 mail ready\r\n"), Some(16));
+    }
+
+    #[test]
+    fn test_invalid_script() {
+        let config: TcpCheckConfig =
+            toml::from_str(r#"steps = [{ action = "send", hex = "abc" }]"#).unwrap();
+        assert!(Script::new(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_healthy_redis() {
+        let port = redis(false).await;
+        let result = script(PING).run("127.0.0.1", port).await;
+        assert!(result.unwrap().starts_with("matched"));
+    }
+
+    #[tokio::test]
+    async fn test_hung_redis() {
+        let port = redis(true).await;
+        let result = script(PING).run("127.0.0.1", port).await;
+        assert!(result.unwrap_err().starts_with("timed out"));
+    }
+}
@@ -0,0 +1,525 @@
+use {
+    crate::{
+        algorithm::algorithm::Strategy,
+        config::{Config, ProbeConfig},
+        tcp_check::Script,
+    },
+    actix_web::http::Uri,
+    serde_json::Value,
+    std::{collections::HashMap, error::Error, fmt, net::IpAddr, path::PathBuf, str::FromStr},
+    strum::VariantNames,
+};
+
+/// A problem with a config, located by its TOML key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub key: Vec<String>,
+    /// 1-based line on which the key is set, if it could be found.
+    pub line: Option<usize>,
+    pub message: String,
+    /// The included file the problem is in, or `None` for the main config.
+    pub file: Option<PathBuf>,
+}
+
+impl ConfigError {
+    pub fn new(key: &[&str], message: impl Into<String>) -> Self {
+        Self {
+            key: key.iter().map(|segment| segment.to_string()).collect(),
+            line: None,
+            message: message.into(),
+            file: None,
+        }
+    }
+
+    /// An unknown key, as reported by `serde_ignored`.
+    pub fn unknown(path: &serde_ignored::Path) -> Self {
+        let mut key = Vec::new();
+        segments(path, &mut key);
+        Self {
+            key,
+            line: None,
+            message: String::from("unknown key"),
+            file: None,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.key.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.key.join("."), self.message)
+        }
+    }
+}
+
+/// Every problem found in a config file.
+#[derive(Debug)]
+pub struct ConfigErrors {
+    pub path: PathBuf,
+    pub errors: Vec<ConfigError>,
+}
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let count = self.errors.len();
+        write!(
+            f,
+            "'{}' has {} error{}:",
+            self.path.display(),
+            count,
+            if count == 1 { "" } else { "s" }
+        )?;
+        for error in self.errors.iter() {
+            let path = error.file.as_ref().unwrap_or(&self.path).display();
+            match error.line {
+                Some(line) => write!(f, "\n{}:{}: {}", path, line, error)?,
+                None => write!(f, "\n{}: {}", path, error)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConfigErrors {}
+
+/// Collects the keys of `path`, leaving out the steps through options and newtypes.
+fn segments(path: &serde_ignored::Path, out: &mut Vec<String>) {
+    match *path {
+        serde_ignored::Path::Root => {}
+        serde_ignored::Path::Seq { parent, index } => {
+            segments(parent, out);
+            out.push(index.to_string());
+        }
+        serde_ignored::Path::Map { parent, ref key } => {
+            segments(parent, out);
+            out.push(key.clone());
+        }
+        serde_ignored::Path::Some { parent }
+        | serde_ignored::Path::NewtypeStruct { parent }
+        | serde_ignored::Path::NewtypeVariant { parent } => segments(parent, out),
+    }
+}
+
+/// Orders errors by file, the main config first, and then by line, with those whose line
+/// couldn't be found last.
+pub fn sort(errors: &mut [ConfigError]) {
+    errors.sort_by(|a, b| {
+        let key = |error: &ConfigError| {
+            (
+                error.file.clone(),
+                error.line.is_none(),
+                error.line,
+                error.key.clone(),
+            )
+        };
+        key(a).cmp(&key(b))
+    });
+}
+
+/// The text of a string or number, as it was written.
+fn text(value: &Value) -> String {
+    match *value {
+        Value::String(ref s) => s.clone(),
+        ref value => value.to_string(),
+    }
+}
+
+fn check_ip(key: &[&str], ip: &Value, errors: &mut Vec<ConfigError>) {
+    let ip = text(ip);
+    if let Err(e) = IpAddr::from_str(&ip) {
+        errors.push(ConfigError::new(
+            key,
+            format!("'{}' is not a valid IP address: {}", ip, e),
+        ));
+    }
+}
+
+fn check_port(key: &[&str], port: &Value, errors: &mut Vec<ConfigError>) {
+    let port = text(port);
+    if let Err(e) = port.parse::<u16>() {
+        errors.push(ConfigError::new(
+            key,
+            format!("'{}' is not a valid port: {}", port, e),
+        ));
+    }
+}
+
+fn check_strategy(strategy: &Value, errors: &mut Vec<ConfigError>) {
+    let name = match *strategy {
+        Value::String(ref name) => Some(name),
+        Value::Object(ref table) => table.keys().next(),
+        _ => None,
+    };
+    if let Some(name) = name.filter(|name| Strategy::from_str(name).is_err()) {
+        errors.push(ConfigError::new(
+            &["strategy"],
+            format!(
+                "unknown strategy '{}', expected one of {}",
+                name,
+                Strategy::VARIANTS.join(", ")
+            ),
+        ));
+        return;
+    }
+    let mut unknown = Vec::new();
+    let parsed: Result<Strategy, _> = serde_ignored::deserialize(strategy, |path| {
+        let mut error = ConfigError::unknown(&path);
+        error.key.insert(0, String::from("strategy"));
+        unknown.push(error);
+    });
+    match parsed {
+        Ok(_) => errors.append(&mut unknown),
+        Err(e) => errors.push(ConfigError::new(&["strategy"], e.to_string())),
+    }
+}
+
+/// Checks the ports of the backends of a config, or of a file it includes, before it is
+/// deserialized.
+pub fn check_backend_values(value: &Value) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+    let backends = value.get("backends").and_then(Value::as_object);
+    for (name, backend) in backends.into_iter().flatten() {
+        if let Some(port) = backend.get("port") {
+            check_port(&["backends", name, "port"], port, &mut errors);
+        }
+        if let Some(port) = backend.get("agent").and_then(|agent| agent.get("port")) {
+            check_port(&["backends", name, "agent", "port"], port, &mut errors);
+        }
+    }
+    errors
+}
+
+/// Checks the addresses, ports and strategy of a config before it is deserialized. Invalid
+/// values are deserialized as their defaults instead of failing, so that they're reported along
+/// with every other problem rather than on their own.
+pub fn check_values(value: &Value) -> Vec<ConfigError> {
+    let mut errors = check_backend_values(value);
+    if let Some(strategy) = value.get("strategy") {
+        check_strategy(strategy, &mut errors);
+    }
+    if let Some(ip) = value.get("ip") {
+        check_ip(&["ip"], ip, &mut errors);
+    }
+    if let Some(port) = value.get("port") {
+        check_port(&["port"], port, &mut errors);
+    }
+    if let Some(admin) = value.get("admin") {
+        if let Some(ip) = admin.get("ip") {
+            check_ip(&["admin", "ip"], ip, &mut errors);
+        }
+        if let Some(port) = admin.get("port") {
+            check_port(&["admin", "port"], port, &mut errors);
+        }
+    }
+    errors
+}
+
+/// Checks what deserializing can't, such as whether names refer to something that exists.
+pub fn validate(config: &Config) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+
+    if config.backends.is_empty() {
+        errors.push(ConfigError::new(
+            &["backends"],
+            "no backends are configured",
+        ));
+    }
+    for (name, backend) in config.backends.iter() {
+        if backend.ip.is_empty() {
+            errors.push(ConfigError::new(&["backends", name, "ip"], "is empty"));
+        }
+        // A discovered backend only names where its addresses come from.
+        if let (false, Err(e)) = (backend.is_discovered(), backend.uri()) {
+            errors.push(ConfigError::new(
+                &["backends", name],
+                format!(
+                    "'{}://{}{}' is not a valid address: {}",
+                    backend.scheme,
+                    backend.authority(),
+                    backend.path,
+                    e
+                ),
+            ));
+        }
+        if let Some(ref file_sd) = backend.file_sd {
+            if file_sd.path.is_empty() {
+                errors.push(ConfigError::new(
+                    &["backends", name, "file_sd", "path"],
+                    "is empty",
+                ));
+            }
+        }
+        if let Some(ref registry) = backend.registry {
+            let invalid = match registry.url.parse::<Uri>() {
+                Ok(uri) if uri.scheme().is_some() => None,
+                Ok(_) => Some(String::from("it has no scheme")),
+                Err(e) => Some(e.to_string()),
+            };
+            if let Some(e) = invalid {
+                errors.push(ConfigError::new(
+                    &["backends", name, "registry", "url"],
+                    format!("'{}' is not a valid address: {}", registry.url, e),
+                ));
+            }
+        }
+        let sources = [
+            ("file_sd", backend.file_sd.is_some()),
+            ("registry", backend.registry.is_some()),
+            ("dns", backend.dns.is_some()),
+        ];
+        let mut sources = sources.iter().filter(|(_, set)| *set).map(|(key, _)| *key);
+        if let Some(first) = sources.next() {
+            for source in sources {
+                errors.push(ConfigError::new(
+                    &["backends", name, source],
+                    format!("can't be set along with '{}'", first),
+                ));
+            }
+        }
+    }
+
+    let mut routes = config.routes.iter().collect::<Vec<_>>();
+    routes.sort_by_key(|(name, _)| name.as_str());
+    let mut paths = HashMap::new();
+    for (name, route) in routes {
+        if let Some(first) = paths.insert(route.path.as_str(), name) {
+            paths.insert(route.path.as_str(), first);
+            errors.push(ConfigError::new(
+                &["routes", name, "path"],
+                format!("'{}' is already the path of route '{}'", route.path, first),
+            ));
+        }
+    }
+
+    if let ProbeConfig::Tcp(ref check) = config.health_check.probe {
+        if let Err(e) = Script::new(check) {
+            errors.push(ConfigError::new(&["health_check", "probe", "tcp"], e));
+        }
+    }
+
+    for name in config.mappings.keys() {
+        let message = match config.backends.get(name) {
+            None => format!("refers to backend '{}', which isn't configured", name),
+            // Its addresses become backends of their own, which the mapping wouldn't apply to.
+            Some(backend) if backend.is_discovered() => {
+                format!(
+                    "refers to backend '{}', whose addresses are discovered",
+                    name
+                )
+            }
+            Some(_) => continue,
+        };
+        errors.push(ConfigError::new(&["mappings", name], message));
+    }
+    errors
+}
+
+/// Splits a TOML key such as `backends."10.0.0.1".port` into its segments.
+fn split_key(key: &str) -> Vec<String> {
+    let mut segments = Vec::new();
+    let mut segment = String::new();
+    let mut quote = None;
+    for c in key.chars() {
+        match (c, quote) {
+            ('"', None) | ('\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            ('.', None) => segments.push(std::mem::take(&mut segment).trim().to_string()),
+            (c, _) => segment.push(c),
+        }
+    }
+    segments.push(segment.trim().to_string());
+    segments
+}
+
+/// The 1-based line of `source` on which `key` is set, or else on which its closest enclosing
+/// table is. Array indices are ignored, so keys in arrays of tables resolve to the first one.
+pub fn line_of(source: &str, key: &[String]) -> Option<usize> {
+    let key = key
+        .iter()
+        .filter(|segment| segment.parse::<usize>().is_err())
+        .collect::<Vec<_>>();
+    let mut table = Vec::new();
+    let mut best: Option<(usize, usize)> = None;
+    for (i, line) in source.lines().enumerate() {
+        let line = line.trim();
+        let found = if let Some(header) = line.strip_prefix('[') {
+            table = split_key(header.trim_start_matches('[').split(']').next()?);
+            table.clone()
+        } else if !line.starts_with('#') && line.contains('=') {
+            let mut found = table.clone();
+            found.extend(split_key(line.split('=').next()?));
+            found
+        } else {
+            continue;
+        };
+        let matches =
+            found.len() <= key.len() && found.iter().zip(key.iter()).all(|(a, b)| a == *b);
+        if matches && best.is_none_or(|(len, _)| found.len() > len) {
+            best = Some((found.len(), i + 1));
+        }
+    }
+    best.map(|(_, line)| line)
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::time::Duration};
+
+    const SOURCE: &str = r#"
+strategy = "Fastest"
+port = "80a"
+
+[backends.a]
+ip = "10.0.0.1"
+port = "80"
+
+[backends."10.0.0.2"]
+ip = "10.0.0.2"
+port = "99999"
+
+[strategies]
+[strategies.a]
+path = "/a"
+
+[mappings.c]
+path = "/c"
+"#;
+
+    fn errors(source: &str) -> Vec<String> {
+        Config::from_toml(source)
+            .unwrap_err()
+            .iter()
+            .map(|error| format!("{:?}: {}", error.line, error))
+            .collect()
+    }
+
+    #[test]
+    fn test_split_key() {
+        assert_eq!(split_key("a"), vec!["a"]);
+        assert_eq!(split_key(" a . \"b.c\" "), vec!["a", "b.c"]);
+    }
+
+    #[test]
+    fn test_line_of() {
+        let key = |key: &[&str]| key.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(line_of(SOURCE, &key(&["port"])), Some(3));
+        assert_eq!(
+            line_of(SOURCE, &key(&["backends", "10.0.0.2", "port"])),
+            Some(11)
+        );
+        assert_eq!(
+            line_of(SOURCE, &key(&["backends", "10.0.0.2", "weight"])),
+            Some(9)
+        );
+        assert_eq!(line_of(SOURCE, &key(&["strategies"])), Some(13));
+        assert_eq!(line_of(SOURCE, &key(&["missing"])), None);
+    }
+
+    #[test]
+    fn test_reports_every_error() {
+        assert_eq!(
+            errors(SOURCE),
+            vec![
+                "Some(2): strategy: unknown strategy 'Fastest', expected one of RoundRobin, \
+                 WeightedRoundRobin, Random, LeastConnections, WeightedLeastConnections, \
+                 UriPathHash, SourceIPHash, LeastTraffic, LeastLatency",
+                "Some(3): port: '80a' is not a valid port: invalid digit found in string",
+                "Some(11): backends.10.0.0.2.port: '99999' is not a valid port: number too large \
+                 to fit in target type",
+                "Some(13): strategies: unknown key",
+                "Some(17): mappings.c: refers to backend 'c', which isn't configured",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_route_paths() {
+        assert_eq!(
+            errors("[backends.a]\n[routes.a]\npath = \"/a\"\n[routes.b]\npath = \"/a\"\n[routes.c]\npath = \"/c\"\n"),
+            vec!["Some(5): routes.b.path: '/a' is already the path of route 'a'"]
+        );
+    }
+
+    #[test]
+    fn test_typed_values() {
+        let config = Config::from_toml(
+            r#"
+port = "8081"
+[strategy.LeastLatency]
+[health_check]
+timeout = 2
+interval = "250ms"
+[backends.a]
+port = 443
+scheme = "https"
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.addr(), "127.0.0.1:8081".parse().unwrap());
+        assert_eq!(config.strategy.to_string(), "LeastLatency");
+        assert_eq!(config.health_check.timeout, Duration::from_secs(2));
+        assert_eq!(config.health_check.interval, Duration::from_millis(250));
+        assert_eq!(
+            config.backends["a"].uri().unwrap(),
+            "https://127.0.0.1:443/backend"
+        );
+    }
+
+    #[test]
+    fn test_invalid_backend_address() {
+        assert_eq!(
+            errors("[backends.a]\nip = \"bad host\"\n[backends.b]\nip = \"bad host\"\ndns = {}\n"),
+            vec!["Some(1): backends.a: 'http://bad host:8080/backend' is not a valid address: invalid uri character"]
+        );
+    }
+
+    #[test]
+    fn test_empty_backends_and_bad_values() {
+        assert_eq!(
+            errors("ip = \"localhost\""),
+            vec![
+                "Some(1): ip: 'localhost' is not a valid IP address: invalid IP address syntax",
+                "None: backends: no backends are configured",
+            ]
+        );
+        let errors = errors("persistence_type = \"Session\"\n[health_check]\nport = 1");
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("unknown variant `Session`"));
+    }
+
+    #[test]
+    fn test_typed_values_are_reported_together() {
+        assert_eq!(
+            errors(
+                "port = 99999\n[strategy.LeastLatency]\nfastest = true\n[backends.a]\nip = \"\"\n\
+                 agent = { port = -1 }\n[admin]\nip = \"::g\"\nport = 9090"
+            ),
+            vec![
+                "Some(1): port: '99999' is not a valid port: number too large to fit in target type",
+                "Some(3): strategy.LeastLatency.fastest: unknown key",
+                "Some(5): backends.a.ip: is empty",
+                "Some(6): backends.a.agent.port: '-1' is not a valid port: invalid digit found in \
+                 string",
+                "Some(8): admin.ip: '::g' is not a valid IP address: invalid IP address syntax",
+            ]
+        );
+        let script = errors(
+            "[backends.a]\n[health_check]\nprobe = { tcp = { steps = [{ action = \"expect\", regex = \"(\" }] } }",
+        );
+        assert_eq!(script.len(), 1);
+        assert!(
+            script[0].starts_with("Some(3): health_check.probe.tcp: invalid regex"),
+            "{}",
+            script[0]
+        );
+        let duration = errors("[backends.a]\n[health_check]\ninterval = \"5 parsecs\"");
+        assert_eq!(duration.len(), 1);
+        assert!(
+            duration[0].contains("invalid duration '5 parsecs'"),
+            "{}",
+            duration[0]
+        );
+    }
+}